@@ -50,6 +50,12 @@ class BiblioApp {
         // Track which library each view was rendered for
         this.gridRenderedForLibrary = null;
         this.tableRenderedForLibrary = null;
+
+        // Live library updates, see watchLibraryChanges()
+        this.eventSource = null;
+        this.pendingLibraryEvents = [];
+        this.pendingLibraryResync = false;
+        this.libraryEventsTimer = null;
     }
 
     // Cookie Management Methods
@@ -162,18 +168,20 @@ class BiblioApp {
         // Load view preferences
         this.loadViewPreferences();
 
-        // Check if user is authenticated
-        const authState = this.loadAuthState();
+        // Check if user is authenticated and the server session is still valid
+        const authState = await this.verifySession();
         if (authState && authState.isAuthenticated) {
             this.isAuthenticated = true;
             this.currentUsername = authState.username;
             this.showMainApp();
             this.checkAdminStatus();
+            await this.loadServerPreferences();
 
             // Load saved app state from cookies
             const savedState = this.loadAppState();
 
             await this.loadLibraries();
+            this.watchLibraryChanges();
             this.setupEventListeners();
 
             // Restore filter section collapsed/expanded states
@@ -237,6 +245,33 @@ class BiblioApp {
         localStorage.removeItem('biblio_auth');
     }
 
+    async verifySession() {
+        // The session itself lives in an HttpOnly cookie; ask the server whether it is still valid
+        const authState = this.loadAuthState();
+        if (!authState || !authState.isAuthenticated) {
+            return null;
+        }
+
+        try {
+            const response = await fetch('/api/auth/current-user');
+            if (!response.ok) {
+                this.clearAuthState();
+                return null;
+            }
+
+            const data = await response.json();
+            if (data.success && data.data) {
+                this.saveAuthState(data.data.username, data.data.role);
+                return this.loadAuthState();
+            }
+        } catch (error) {
+            console.error('Error verifying session:', error);
+        }
+
+        this.clearAuthState();
+        return null;
+    }
+
     showLoginPage() {
         try {
             const contentArea = document.querySelector('.content-area');
@@ -438,10 +473,12 @@ class BiblioApp {
 
                 // Check admin status AFTER auth state is saved
                 this.checkAdminStatus();
+                await this.loadServerPreferences();
 
                 // Initialize app
                 const savedState = this.loadAppState();
                 await this.loadLibraries();
+                this.watchLibraryChanges();
                 this.setupEventListeners();
 
                 if (savedState && savedState.currentLibraryId && this.libraries.some(lib => lib.id === savedState.currentLibraryId)) {
@@ -516,6 +553,7 @@ class BiblioApp {
             console.error('Logout error:', error);
         }
 
+        this.stopWatchingLibraryChanges();
         this.isAuthenticated = false;
         this.currentUsername = null;
         this.clearAuthState();
@@ -651,16 +689,36 @@ class BiblioApp {
 
     async loadBooks() {
         try {
-            const response = await fetch(`/api/libraries/${this.currentLibraryId}/books`);
-            const data = await response.json();
+            // The books endpoint is paginated; fetch every page (most recent first)
+            const libraryId = this.currentLibraryId;
+            const pageSize = 1000;
+            let books = [];
+            let total = 0;
+
+            do {
+                const response = await fetch(`/api/libraries/${libraryId}/books?sort=timestamp&offset=${books.length}&limit=${pageSize}`);
+                const data = await response.json();
+
+                if (!data.success) {
+                    console.error('Failed to load books:', data.error);
+                    return;
+                }
 
-            if (data.success) {
-                this.allBooks = data.data || [];
-                document.getElementById('statusBooks').textContent = this.allBooks.length;
-                this.applyFilters();
-            } else {
-                console.error('Failed to load books:', data.error);
-            }
+                // Stop if the user switched library while pages were loading
+                if (libraryId !== this.currentLibraryId) {
+                    return;
+                }
+
+                books = books.concat(data.data.books || []);
+                total = data.data.total;
+                if (!data.data.books || data.data.books.length === 0) {
+                    break;
+                }
+            } while (books.length < total);
+
+            this.allBooks = books;
+            document.getElementById('statusBooks').textContent = this.allBooks.length;
+            this.applyFilters();
         } catch (error) {
             console.error('Error loading books:', error);
         }
@@ -1411,7 +1469,8 @@ class BiblioApp {
 
             if (book.has_cover) {
                 const img = document.createElement('img');
-                img.src = `/api/libraries/${this.currentLibraryId}/books/${book.id}/cover`;
+                const thumbWidth = Math.ceil(this.coverSize * (window.devicePixelRatio || 1));
+                img.src = `/api/libraries/${this.currentLibraryId}/books/${book.id}/cover?w=${thumbWidth}`;
                 img.onerror = () => {
                     img.parentElement.innerHTML = '<div class="no-image">No Cover</div>';
                 };
@@ -1619,7 +1678,7 @@ class BiblioApp {
         // Update cover image
         const coverImage = document.getElementById('coverImage');
         if (book.has_cover) {
-            coverImage.src = `/api/libraries/${this.currentLibraryId}/books/${book.id}/cover`;
+            coverImage.src = `/api/libraries/${this.currentLibraryId}/books/${book.id}/cover?w=400`;
             coverImage.style.display = 'block';
         } else {
             // Generate a temporary cover with title and author
@@ -2136,6 +2195,35 @@ class BiblioApp {
             columnVisibility: this.columnVisibility
         };
         localStorage.setItem('biblioViewPreferences', JSON.stringify(preferences));
+
+        // Keep a copy on the server so the view follows the user to other browsers
+        if (this.isAuthenticated) {
+            clearTimeout(this.preferencesSaveTimer);
+            this.preferencesSaveTimer = setTimeout(() => {
+                fetch('/api/auth/preferences', {
+                    method: 'PUT',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify({ view: preferences })
+                }).catch(error => console.error('Error saving preferences:', error));
+            }, 1000);
+        }
+    }
+
+    async loadServerPreferences() {
+        // The server keeps preferences with the SQLite user store only; otherwise the local copy is used
+        try {
+            const response = await fetch('/api/auth/preferences');
+            if (!response.ok) {
+                return;
+            }
+            const data = await response.json();
+            if (data.success && data.data && data.data.view) {
+                localStorage.setItem('biblioViewPreferences', JSON.stringify(data.data.view));
+                this.loadViewPreferences();
+            }
+        } catch (error) {
+            console.error('Error loading saved preferences:', error);
+        }
     }
 
     loadViewPreferences() {
@@ -2638,6 +2726,79 @@ class BiblioApp {
         }
     }
 
+    watchLibraryChanges() {
+        if (this.eventSource || typeof EventSource === 'undefined') {
+            return;
+        }
+
+        // The browser reconnects by itself and the server replays what was missed
+        this.eventSource = new EventSource('/api/events');
+        ['library_added', 'library_removed', 'books_changed'].forEach(type => {
+            this.eventSource.addEventListener(type, e => this.queueLibraryEvent(JSON.parse(e.data)));
+        });
+        this.eventSource.addEventListener('resync', () => {
+            this.pendingLibraryResync = true;
+            this.queueLibraryEvent(null);
+        });
+        this.eventSource.addEventListener('session_expired', () => {
+            this.stopWatchingLibraryChanges();
+            this.handleLogout();
+        });
+    }
+
+    stopWatchingLibraryChanges() {
+        if (this.eventSource) {
+            this.eventSource.close();
+            this.eventSource = null;
+        }
+        clearTimeout(this.libraryEventsTimer);
+        this.pendingLibraryEvents = [];
+        this.pendingLibraryResync = false;
+    }
+
+    // Calibre often changes several books in a row; apply them together
+    queueLibraryEvent(event) {
+        if (event) {
+            this.pendingLibraryEvents.push(event);
+        }
+        clearTimeout(this.libraryEventsTimer);
+        this.libraryEventsTimer = setTimeout(() => {
+            const events = this.pendingLibraryEvents;
+            const complete = !this.pendingLibraryResync;
+            this.pendingLibraryEvents = [];
+            this.pendingLibraryResync = false;
+            this.applyLibraryChanges(events, complete);
+        }, 500);
+    }
+
+    async applyLibraryChanges(events, complete) {
+        // Without the full list of changes, assume the current library changed too
+        const currentChanged = !complete || events.some(e => e.library_id === this.currentLibraryId);
+
+        // Reload the list for added and removed libraries and new book counts
+        await this.loadLibraries();
+
+        if (!this.libraries.some(lib => lib.id === this.currentLibraryId)) {
+            if (this.libraries.length > 0) {
+                await this.selectLibrary(this.libraries[0].id);
+            }
+            this.updateStatus('The selected library was removed');
+            return;
+        }
+
+        document.querySelectorAll('input[name="library"]').forEach(radio => {
+            radio.checked = radio.value === this.currentLibraryId;
+        });
+        this.currentLibrary = this.libraries.find(lib => lib.id === this.currentLibraryId);
+
+        if (currentChanged) {
+            await this.loadBooks();
+            await this.loadFilters();
+            this.restoreFilterUI();
+            this.updateStatus('Library updated');
+        }
+    }
+
     showAbout() {
         alert('Biblio - E-book Library Browser\n\nVersion 1.0.0\n\nA web-based application for browsing Calibre e-book libraries.\n\nBuilt with Rust (Actix-web) and modern web technologies');
     }
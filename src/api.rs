@@ -1,16 +1,27 @@
-use actix_web::{web, HttpResponse, Result};
+use actix_files::NamedFile;
+use actix_web::http::header::{
+    self, Charset, ContentDisposition, DispositionParam, DispositionType, EntityTag, ExtendedValue, IfNoneMatch,
+};
+use actix_web::{middleware, web, HttpMessage, HttpRequest, HttpResponse, Responder, Result};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 use crate::library::{LibraryCache, LibraryMetadata};
-use crate::db::Book;
+use crate::db::{Book, BookPage, BookQuery, BookSort, CalibreDb, ContentRestrictions, CustomColumn, CustomColumnValue};
 use crate::config;
 use crate::auth;
-#[allow(unused_imports)]
 use crate::session;
-#[allow(unused_imports)]
 use crate::audit;
-#[allow(unused_imports)]
-use crate::rbac;
+use crate::rbac::{self, LibraryAccess};
+use crate::throttle::{LockoutKey, LoginThrottle};
+use crate::content::{ContentIndexJobs, ContentIndexProgress};
+use crate::search::{self, ContentMatch, SearchIndex};
+use crate::thumbnail::{self, ThumbnailFormat, ThumbnailRequest};
+use crate::events::{self, LibraryEvent, LibraryEvents};
+use crate::users::{self, NewApiToken, StoreError};
+use crate::watcher;
+use tracing::{error, warn};
 
 #[derive(Debug, Serialize)]
 pub struct ApiResponse<T> {
@@ -20,11 +31,117 @@ pub struct ApiResponse<T> {
 }
 
 #[derive(Debug, Deserialize)]
-pub struct FilterQuery {
-    pub formats: Option<Vec<String>>,
+pub struct BookListQuery {
     pub search: Option<String>,
+    pub formats: Option<String>,    // comma-separated, e.g. "EPUB,PDF"
+    pub languages: Option<String>,  // comma-separated, e.g. "eng,fra"
+    pub author_ids: Option<String>, // comma-separated ids
+    pub tag_ids: Option<String>,    // comma-separated ids
+    pub series_ids: Option<String>, // comma-separated ids
+    pub sort: Option<BookSort>,
+    pub order: Option<String>,      // "asc" or "desc"
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+impl BookListQuery {
+    /// Convert the query string into a database query, validating list parameters.
+    ///
+    /// Custom columns are filtered with `custom.<label>=<value>` keys taken from the raw
+    /// query string; repeating a key accepts any of its values.
+    pub fn to_book_query(&self, query_string: &str) -> std::result::Result<BookQuery, String> {
+        fn ids(value: &Option<String>, name: &str) -> std::result::Result<Vec<i32>, String> {
+            value
+                .as_deref()
+                .unwrap_or("")
+                .split(',')
+                .map(str::trim)
+                .filter(|id| !id.is_empty())
+                .map(|id| id.parse::<i32>().map_err(|_| format!("Invalid id '{}' in {}", id, name)))
+                .collect()
+        }
+
+        let searching = self.search.as_deref().is_some_and(|s| !s.trim().is_empty());
+        let sort = self.sort.unwrap_or(if searching { BookSort::Relevance } else { BookSort::default() });
+        let descending = match self.order.as_deref().map(str::to_lowercase).as_deref() {
+            None => sort.default_descending(),
+            Some("asc") => false,
+            Some("desc") => true,
+            Some(other) => return Err(format!("Invalid order '{}': expected 'asc' or 'desc'", other)),
+        };
+
+        Ok(BookQuery {
+            search: self.search.clone(),
+            book_ids: None,
+            formats: self
+                .formats
+                .as_deref()
+                .unwrap_or("")
+                .split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .map(str::to_string)
+                .collect(),
+            languages: self
+                .languages
+                .as_deref()
+                .unwrap_or("")
+                .split(',')
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(str::to_string)
+                .collect(),
+            author_ids: ids(&self.author_ids, "author_ids")?,
+            tag_ids: ids(&self.tag_ids, "tag_ids")?,
+            series_ids: ids(&self.series_ids, "series_ids")?,
+            custom: custom_filters(query_string)?,
+            sort,
+            descending,
+            offset: self.offset.unwrap_or(0),
+            limit: self.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE),
+        })
+    }
+}
+
+/// Group `custom.<label>=<value>` query parameters by column label
+fn custom_filters(query_string: &str) -> std::result::Result<Vec<(String, Vec<String>)>, String> {
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(query_string)
+        .map_err(|e| format!("Invalid query string: {}", e))?
+        .into_inner();
+
+    let mut filters: Vec<(String, Vec<String>)> = Vec::new();
+    for (key, value) in pairs {
+        let Some(label) = key.strip_prefix("custom.") else { continue };
+        let label = label.trim_start_matches('#').to_lowercase();
+        match filters.iter_mut().find(|(l, _)| *l == label) {
+            Some((_, values)) => values.push(value),
+            None => filters.push((label, vec![value])),
+        }
+    }
+    Ok(filters)
+}
+
+/// Take a library's database handle out of the cache, so that queries run
+/// without holding the cache lock
+fn library_db(cache: &RwLock<LibraryCache>, library_id: &str) -> Option<CalibreDb> {
+    cache.read().unwrap().get_database(library_id).cloned()
+}
+
+/// Like `library_db`, limited to the books the user may see
+fn visible_library_db(cache: &RwLock<LibraryCache>, library_id: &str, user: &session::AuthenticatedUser) -> Option<CalibreDb> {
+    library_db(cache, library_id).map(|db| db.restricted(user.account.restrictions.clone()))
+}
+
+fn library_search_index(cache: &RwLock<LibraryCache>, library_id: &str) -> Option<Arc<SearchIndex>> {
+    cache.read().unwrap().get_search_index(library_id)
 }
 
+/// Page size used when a list request does not specify `limit`
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Largest page size a list request may ask for
+const MAX_PAGE_SIZE: usize = 1000;
+
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
@@ -33,7 +150,6 @@ pub struct LoginRequest {
 
 #[derive(Debug, Deserialize)]
 pub struct ChangePasswordRequest {
-    pub username: String,
     pub current_password: String,
     pub new_password: String,
 }
@@ -41,6 +157,11 @@ pub struct ChangePasswordRequest {
 #[derive(Debug, Deserialize)]
 pub struct AuditLogQuery {
     pub username: Option<String>,
+    pub event_type: Option<String>,
+    pub success: Option<bool>,
+    pub since: Option<String>, // RFC 3339 timestamp
+    pub until: Option<String>, // RFC 3339 timestamp
+    pub offset: Option<usize>,
     pub limit: Option<usize>,
 }
 
@@ -92,26 +213,39 @@ pub struct CreateUserRequest {
     pub password: String,
     pub role: Option<String>, // admin, librarian, user, reader (default: reader)
     pub email: Option<String>,
-    pub admin_username: Option<String>, // Username of the admin making this request
+    pub restrictions: Option<ContentRestrictions>,
+    pub libraries: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct UpdateUserRequest {
     pub role: Option<String>,
     pub email: Option<String>,
-    pub admin_username: Option<String>, // Username of the admin making this request
+    /// Replaces the user's restrictions; an empty object lifts them
+    pub restrictions: Option<ContentRestrictions>,
+    /// Replaces the libraries the user may see; `null` falls back to `library_access` in config.yaml
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub libraries: Option<Option<Vec<String>>>,
+}
+
+/// Tell a field set to `null` (`Some(None)`) apart from a missing one (`None`)
+fn deserialize_some<'de, T, D>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Deserialize)]
-pub struct AdminChangePasswordRequest {
-    pub username: String,
-    pub new_password: String,
-    pub admin_username: Option<String>, // Username of the admin making this request
+pub struct CreateApiTokenRequest {
+    pub name: String,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct DeleteUserRequest {
-    pub admin_username: Option<String>, // Username of the admin making this request
+pub struct AdminChangePasswordRequest {
+    pub username: String,
+    pub new_password: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -120,13 +254,28 @@ pub struct UserResponse {
     pub role: String,
     pub email: Option<String>,
     pub created_at: Option<String>,
+    pub restrictions: ContentRestrictions,
+    pub libraries: Option<Vec<String>>,
+}
+
+impl From<auth::User> for UserResponse {
+    fn from(user: auth::User) -> Self {
+        UserResponse {
+            username: user.username,
+            role: user.role,
+            email: user.email,
+            created_at: user.created_at,
+            restrictions: user.restrictions,
+            libraries: user.libraries,
+        }
+    }
 }
 
 pub async fn get_libraries(
-    cache: web::Data<Mutex<LibraryCache>>,
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
 ) -> Result<HttpResponse> {
-    let cache = cache.lock().unwrap();
-    let libraries = cache.get_libraries();
+    let libraries = web::block(move || visible_libraries(&cache, &user)).await?;
     
     Ok(HttpResponse::Ok().json(ApiResponse {
         success: true,
@@ -136,16 +285,19 @@ pub async fn get_libraries(
 }
 
 pub async fn get_library(
-    cache: web::Data<Mutex<LibraryCache>>,
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
     path: web::Path<String>,
 ) -> Result<HttpResponse> {
     let library_id = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(lib) = cache.get_library(&library_id) {
+    let library = web::block(move || {
+        visible_libraries(&cache, &user).into_iter().find(|library| library.id == library_id)
+    }).await?;
+
+    if let Some(lib) = library {
         Ok(HttpResponse::Ok().json(ApiResponse {
             success: true,
-            data: Some(lib.clone()),
+            data: Some(lib),
             error: None,
         }))
     } else {
@@ -158,50 +310,58 @@ pub async fn get_library(
 }
 
 pub async fn get_books(
-    cache: web::Data<Mutex<LibraryCache>>,
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
     path: web::Path<String>,
-    query: web::Query<FilterQuery>,
+    query: web::Query<BookListQuery>,
+    req: HttpRequest,
 ) -> Result<HttpResponse> {
     let library_id = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(db) = cache.get_database(&library_id) {
-        match db.get_all_books() {
-            Ok(mut books) => {
-                // Apply search filter if provided
-                if let Some(search_term) = &query.search {
-                    let search_lower = search_term.to_lowercase();
-                    books.retain(|book| {
-                        book.title.to_lowercase().contains(&search_lower)
-                            || book.authors.iter().any(|a| a.to_lowercase().contains(&search_lower))
-                    });
-                }
 
-                // Apply format filter if provided
-                if let Some(requested_formats) = &query.formats {
-                    let requested_formats_upper: Vec<String> = 
-                        requested_formats.iter().map(|f| f.to_uppercase()).collect();
-                    
-                    books.retain(|book| {
-                        match db.get_book_formats(book.id) {
-                            Ok(available_formats) => {
-                                available_formats.iter().any(|fmt| {
-                                    requested_formats_upper.contains(&fmt.to_uppercase())
-                                })
-                            }
-                            Err(_) => false,
-                        }
-                    });
-                }
+    let mut book_query = match query.to_book_query(req.query_string()) {
+        Ok(book_query) => book_query,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<BookPage> {
+                success: false,
+                data: None,
+                error: Some(e),
+            }));
+        }
+    };
+
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        if let Some((label, _)) = book_query.custom.iter().find(|(label, _)| db.custom_column(label).is_none()) {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<BookPage> {
+                success: false,
+                data: None,
+                error: Some(format!("Unknown custom column '{}'", label)),
+            }));
+        }
+
+        let index = library_search_index(&cache, &library_id);
+        let expression = match search::prepare_query(index.as_deref(), &mut book_query) {
+            Ok(expression) => expression,
+            Err(e) => {
+                return Ok(HttpResponse::BadRequest().json(ApiResponse::<BookPage> {
+                    success: false,
+                    data: None,
+                    error: Some(e),
+                }));
+            }
+        };
 
+        let page = web::block(move || search::books_page(&db, index.as_deref(), book_query, expression)).await?;
+
+        match page {
+            Ok(page) => {
                 Ok(HttpResponse::Ok().json(ApiResponse {
                     success: true,
-                    data: Some(books),
+                    data: Some(page),
                     error: None,
                 }))
             }
             Err(e) => {
-                Ok(HttpResponse::InternalServerError().json(ApiResponse::<Vec<Book>> {
+                Ok(HttpResponse::InternalServerError().json(ApiResponse::<BookPage> {
                     success: false,
                     data: None,
                     error: Some(format!("Database error: {}", e)),
@@ -209,7 +369,7 @@ pub async fn get_books(
             }
         }
     } else {
-        Ok(HttpResponse::NotFound().json(ApiResponse::<Vec<Book>> {
+        Ok(HttpResponse::NotFound().json(ApiResponse::<BookPage> {
             success: false,
             data: None,
             error: Some("Library not found".to_string()),
@@ -218,14 +378,13 @@ pub async fn get_books(
 }
 
 pub async fn get_book(
-    cache: web::Data<Mutex<LibraryCache>>,
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
     path: web::Path<(String, i32)>,
 ) -> Result<HttpResponse> {
     let (library_id, book_id) = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(db) = cache.get_database(&library_id) {
-        match db.get_book(book_id) {
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        match web::block(move || db.get_book(book_id)).await? {
             Ok(Some(book)) => {
                 Ok(HttpResponse::Ok().json(ApiResponse {
                     success: true,
@@ -257,352 +416,987 @@ pub async fn get_book(
     }
 }
 
-pub async fn get_authors(
-    cache: web::Data<Mutex<LibraryCache>>,
-    path: web::Path<String>,
+pub async fn get_book_identifiers(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<(String, i32)>,
 ) -> Result<HttpResponse> {
-    let library_id = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(db) = cache.get_database(&library_id) {
-        match db.get_all_authors() {
-            Ok(authors) => {
+    let (library_id, book_id) = path.into_inner();
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        match web::block(move || db.get_book(book_id)).await? {
+            Ok(Some(book)) => {
                 Ok(HttpResponse::Ok().json(ApiResponse {
                     success: true,
-                    data: Some(authors),
+                    data: Some(book.identifiers),
                     error: None,
                 }))
             }
+            Ok(None) => {
+                Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
+                    success: false,
+                    data: None,
+                    error: Some("Book not found".to_string()),
+                }))
+            }
             Err(e) => {
-                Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                Ok(HttpResponse::InternalServerError().json(ApiResponse::<()> {
                     success: false,
-                    data: None as Option<Vec<String>>,
+                    data: None,
                     error: Some(format!("Database error: {}", e)),
                 }))
             }
         }
     } else {
-        Ok(HttpResponse::NotFound().json(ApiResponse {
+        Ok(HttpResponse::NotFound().json(ApiResponse::<()> {
             success: false,
-            data: None as Option<Vec<String>>,
+            data: None,
             error: Some("Library not found".to_string()),
         }))
     }
 }
 
-pub async fn get_tags(
-    cache: web::Data<Mutex<LibraryCache>>,
-    path: web::Path<String>,
+/// Look books up by a Calibre identifier; more than one match means duplicates
+pub async fn find_books_by_identifier(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<(String, String, String)>,
 ) -> Result<HttpResponse> {
-    let library_id = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(db) = cache.get_database(&library_id) {
-        match db.get_all_tags() {
-            Ok(tags) => {
+    let (library_id, id_type, value) = path.into_inner();
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        match web::block(move || db.find_books_by_identifier(&id_type, &value)).await? {
+            Ok(books) => {
                 Ok(HttpResponse::Ok().json(ApiResponse {
                     success: true,
-                    data: Some(tags),
+                    data: Some(books),
                     error: None,
                 }))
             }
             Err(e) => {
-                Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                Ok(HttpResponse::InternalServerError().json(ApiResponse::<Vec<Book>> {
                     success: false,
-                    data: None as Option<Vec<String>>,
+                    data: None,
                     error: Some(format!("Database error: {}", e)),
                 }))
             }
         }
     } else {
-        Ok(HttpResponse::NotFound().json(ApiResponse {
+        Ok(HttpResponse::NotFound().json(ApiResponse::<Vec<Book>> {
             success: false,
-            data: None as Option<Vec<String>>,
+            data: None,
             error: Some("Library not found".to_string()),
         }))
     }
 }
 
-pub async fn get_series(
-    cache: web::Data<Mutex<LibraryCache>>,
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+/// A book whose contents match a search, with its best matching chapters
+#[derive(Debug, Serialize)]
+pub struct ContentSearchResult {
+    pub book_id: i32,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub matches: Vec<ContentMatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContentSearchPage {
+    pub results: Vec<ContentSearchResult>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Matching chapters looked at per content search; books are ranked by their best chapter
+const MAX_CONTENT_MATCHES: usize = 2000;
+
+/// Chapters listed per book in content search results
+const MATCHES_PER_BOOK: usize = 3;
+
+pub async fn search_book_contents(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
     path: web::Path<String>,
+    query: web::Query<SearchQuery>,
 ) -> Result<HttpResponse> {
     let library_id = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(db) = cache.get_database(&library_id) {
-        match db.get_all_series() {
-            Ok(series) => {
-                Ok(HttpResponse::Ok().json(ApiResponse {
-                    success: true,
-                    data: Some(series),
-                    error: None,
-                }))
+    let (Some(db), Some(index)) = (visible_library_db(&cache, &library_id, &user), library_search_index(&cache, &library_id)) else {
+        return Ok(HttpResponse::NotFound().json(ApiResponse::<ContentSearchPage> {
+            success: false,
+            data: None,
+            error: Some("Library not found".to_string()),
+        }));
+    };
+
+    let expression = match search::parse_content_query(query.q.as_deref().unwrap_or("")) {
+        Ok(Some(expression)) => expression,
+        result => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<ContentSearchPage> {
+                success: false,
+                data: None,
+                error: Some(result.err().unwrap_or_else(|| "Missing search query 'q'".to_string())),
+            }));
+        }
+    };
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+    let page = web::block(move || {
+        // Group chapters by book, keeping the ranking of each book's best chapter
+        let hidden = db.hidden_book_ids()?;
+        let mut books: Vec<(i32, Vec<ContentMatch>)> = Vec::new();
+        for hit in index.search_content(&expression, MAX_CONTENT_MATCHES)? {
+            if hidden.contains(&hit.book_id) {
+                continue;
             }
-            Err(e) => {
-                Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                    success: false,
-                    data: None as Option<Vec<String>>,
-                    error: Some(format!("Database error: {}", e)),
-                }))
+            match books.iter_mut().find(|(book_id, _)| *book_id == hit.book_id) {
+                Some((_, matches)) if matches.len() < MATCHES_PER_BOOK => matches.push(hit),
+                Some(_) => {}
+                None => books.push((hit.book_id, vec![hit])),
             }
         }
-    } else {
-        Ok(HttpResponse::NotFound().json(ApiResponse {
+        let total = books.len();
+        let books: Vec<_> = books.into_iter().skip(offset).take(limit).collect();
+
+        let ids: Vec<i32> = books.iter().map(|(book_id, _)| *book_id).collect();
+        let mut details: HashMap<i32, Book> = db.load_books(&ids)?.into_iter().map(|b| (b.id, b)).collect();
+        let results = books
+            .into_iter()
+            .filter_map(|(book_id, matches)| {
+                let book = details.remove(&book_id)?;
+                Some(ContentSearchResult { book_id, title: book.title, authors: book.authors, matches })
+            })
+            .collect();
+        Ok::<_, rusqlite::Error>(ContentSearchPage { results, total, offset, limit })
+    }).await?;
+
+    match page {
+        Ok(page) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(page),
+            error: None,
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<ContentSearchPage> {
             success: false,
-            data: None as Option<Vec<String>>,
-            error: Some("Library not found".to_string()),
-        }))
+            data: None,
+            error: Some(format!("Database error: {}", e)),
+        })),
     }
 }
 
-pub async fn get_book_cover(
-    cache: web::Data<Mutex<LibraryCache>>,
-    path: web::Path<(String, i32)>,
-) -> Result<HttpResponse> {
-    let (library_id, book_id) = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(lib) = cache.get_library(&library_id) {
-        // Find book directory by searching for pattern "*({book_id})"
-        // Calibre stores books as: {library}/{author}/{book_title ({book_id})}/cover.jpg
-        let pattern = format!("({})", book_id);
-        
-        if let Ok(entries) = std::fs::read_dir(&lib.path) {
-            for author_dir in entries.flatten() {
-                let author_path = author_dir.path();
-                if author_path.is_dir() {
-                    if let Ok(book_entries) = std::fs::read_dir(&author_path) {
-                        for book_dir in book_entries.flatten() {
-                            let book_path = book_dir.path();
-                            if book_path.is_dir() {
-                                let dir_name = book_path.file_name()
-                                    .and_then(|n| n.to_str())
-                                    .unwrap_or("");
-                                
-                                if dir_name.ends_with(&pattern) {
-                                    let cover_path = book_path.join("cover.jpg");
-                                    if cover_path.exists() {
-                                        if let Ok(data) = std::fs::read(&cover_path) {
-                                            return Ok(HttpResponse::Ok()
-                                                .content_type("image/jpeg")
-                                                .body(data));
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+/// A book found by a cross-library search, with the library holding it
+#[derive(Debug, Serialize)]
+pub struct LibrarySearchHit {
+    pub library_id: String,
+    pub library_name: String,
+    pub book: Book,
+}
+
+/// Number of books a cross-library search matched in one library
+#[derive(Debug, Serialize)]
+pub struct LibraryMatchCount {
+    pub library_id: String,
+    pub library_name: String,
+    pub total: usize,
+    /// Set when this library could not be searched; the other libraries are still returned
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CrossLibrarySearchPage {
+    pub results: Vec<LibrarySearchHit>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub libraries: Vec<LibraryMatchCount>,
+}
+
+/// Loaded libraries the user may see. For users with content restrictions,
+/// `book_count` only counts the books they may see, which takes a query per library.
+pub(crate) fn visible_libraries(cache: &RwLock<LibraryCache>, user: &session::AuthenticatedUser) -> Vec<LibraryMetadata> {
+    let access = LibraryAccess::for_account(&user.account);
+    let restrictions = &user.account.restrictions;
+    let libraries: Vec<_> = {
+        let cache = cache.read().unwrap();
+        cache
+            .get_libraries()
+            .into_iter()
+            .filter(|library| access.allows(&library.id, &library.name))
+            .map(|library| {
+                let db = cache.get_database(&library.id).cloned();
+                (library, db)
+            })
+            .collect()
+    };
+
+    libraries
+        .into_iter()
+        .map(|(mut library, db)| {
+            if let Some(db) = db.filter(|_| !restrictions.is_empty()) {
+                match db.restricted(restrictions.clone()).count_books() {
+                    Ok(count) => library.book_count = count,
+                    Err(e) => error!("Failed to count the visible books of library '{}': {}", library.name, e),
                 }
             }
+            library
+        })
+        .collect()
+}
+
+/// Loaded libraries the user may see, with their database limited to the books
+/// the user may see and their search index, in name order
+fn searchable_libraries(
+    cache: &RwLock<LibraryCache>,
+    user: &session::AuthenticatedUser,
+) -> Vec<(LibraryMetadata, CalibreDb, Option<Arc<SearchIndex>>)> {
+    let access = LibraryAccess::for_account(&user.account);
+    let restrictions = &user.account.restrictions;
+    let cache = cache.read().unwrap();
+    cache
+        .get_libraries()
+        .into_iter()
+        .filter(|library| access.allows(&library.id, &library.name))
+        .filter_map(|library| {
+            let db = cache.get_database(&library.id)?.restricted(restrictions.clone());
+            let index = cache.get_search_index(&library.id);
+            Some((library, db, index))
+        })
+        .collect()
+}
+
+/// Search one library, returning its number of matches and the best `wanted` of them
+/// with their bm25 score. Libraries without an index fall back to the simple title and
+/// author search, whose matches rank after any scored match.
+fn search_library(
+    db: &CalibreDb,
+    index: Option<&SearchIndex>,
+    search: &str,
+    expression: &str,
+    wanted: usize,
+) -> rusqlite::Result<(usize, Vec<(i32, f64)>)> {
+    match index {
+        Some(index) => {
+            index.ensure_fresh(db)?;
+            let mut hits = index.search_ranked(expression)?;
+            let hidden = db.hidden_book_ids()?;
+            hits.retain(|(book_id, _)| !hidden.contains(book_id));
+            let total = hits.len();
+            hits.truncate(wanted);
+            Ok((total, hits))
+        }
+        None => {
+            let page = db.get_books_page(&BookQuery {
+                search: Some(search.to_string()),
+                limit: wanted,
+                ..Default::default()
+            })?;
+            Ok((page.total, page.books.iter().map(|book| (book.id, 0.0)).collect()))
+        }
+    }
+}
+
+/// Search every library at once and merge the results by relevance
+pub async fn search_all_libraries(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse> {
+    let search = query.q.clone().unwrap_or_default();
+    let expression = match search::parse_query(&search) {
+        Ok(Some(expression)) => expression,
+        result => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<CrossLibrarySearchPage> {
+                success: false,
+                data: None,
+                error: Some(result.err().unwrap_or_else(|| "Missing search query 'q'".to_string())),
+            }));
+        }
+    };
+    let offset = query.offset.unwrap_or(0);
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let libraries = searchable_libraries(&cache, &user);
+
+    // Each library contributes at most the hits up to the end of the page; `offset` comes
+    // straight from the query string, so the sum must not overflow
+    let wanted = offset.saturating_add(limit);
+
+    // web::block starts each search right away, so the libraries are searched in parallel
+    let searches: Vec<_> = libraries
+        .iter()
+        .map(|(_, db, index)| {
+            let (db, index) = (db.clone(), index.clone());
+            let (search, expression) = (search.clone(), expression.clone());
+            web::block(move || search_library(&db, index.as_deref(), &search, &expression, wanted))
+        })
+        .collect();
+
+    let mut counts = Vec::with_capacity(libraries.len());
+    let mut hits: Vec<(usize, i32, f64)> = Vec::new();
+    for (position, ((library, _, _), search)) in libraries.iter().zip(searches).enumerate() {
+        let (total, error) = match search.await? {
+            Ok((total, library_hits)) => {
+                hits.extend(library_hits.into_iter().map(|(book_id, score)| (position, book_id, score)));
+                (total, None)
+            }
+            Err(e) => {
+                error!("Search failed in library '{}': {}", library.name, e);
+                (0, Some(format!("Database error: {}", e)))
+            }
+        };
+        counts.push(LibraryMatchCount {
+            library_id: library.id.clone(),
+            library_name: library.name.clone(),
+            total,
+            error,
+        });
+    }
+
+    // Stable sort: equal scores keep library order, then each library's own ranking
+    hits.sort_by(|a, b| a.2.total_cmp(&b.2));
+    let page: Vec<(usize, i32)> = hits.into_iter().skip(offset).take(limit).map(|(lib, id, _)| (lib, id)).collect();
+
+    let loads: Vec<_> = libraries
+        .iter()
+        .enumerate()
+        .map(|(position, (_, db, _))| {
+            let db = db.clone();
+            let ids: Vec<i32> = page.iter().filter(|(lib, _)| *lib == position).map(|(_, id)| *id).collect();
+            web::block(move || if ids.is_empty() { Ok(Vec::new()) } else { db.load_books(&ids) })
+        })
+        .collect();
+    let mut books: HashMap<(usize, i32), Book> = HashMap::new();
+    for (position, load) in loads.into_iter().enumerate() {
+        match load.await? {
+            Ok(loaded) => books.extend(loaded.into_iter().map(|book| ((position, book.id), book))),
+            Err(e) => error!("Failed to load search results from library '{}': {}", libraries[position].0.name, e),
         }
-        // If not found, return 404
-        Ok(HttpResponse::NotFound().finish())
-    } else {
-        Ok(HttpResponse::NotFound().finish())
     }
+
+    let results = page
+        .into_iter()
+        .filter_map(|key| {
+            let book = books.remove(&key)?;
+            let library = &libraries[key.0].0;
+            Some(LibrarySearchHit {
+                library_id: library.id.clone(),
+                library_name: library.name.clone(),
+                book,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(CrossLibrarySearchPage {
+            results,
+            total: counts.iter().map(|c| c.total).sum(),
+            offset,
+            limit,
+            libraries: counts,
+        }),
+        error: None,
+    }))
 }
 
-pub async fn get_book_formats(
-    cache: web::Data<Mutex<LibraryCache>>,
-    path: web::Path<(String, i32)>,
+pub async fn get_authors(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<String>,
 ) -> Result<HttpResponse> {
-    let (library_id, book_id) = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(db) = cache.get_database(&library_id) {
-        match db.get_book_formats(book_id) {
-            Ok(formats) => {
+    let library_id = path.into_inner();
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        match web::block(move || db.get_all_authors()).await? {
+            Ok(authors) => {
                 Ok(HttpResponse::Ok().json(ApiResponse {
                     success: true,
-                    data: Some(formats),
+                    data: Some(authors),
                     error: None,
                 }))
             }
             Err(e) => {
-                Ok(HttpResponse::InternalServerError().json(ApiResponse::<Vec<String>> {
+                Ok(HttpResponse::InternalServerError().json(ApiResponse {
                     success: false,
-                    data: None,
+                    data: None as Option<Vec<String>>,
                     error: Some(format!("Database error: {}", e)),
                 }))
             }
         }
     } else {
-        Ok(HttpResponse::NotFound().json(ApiResponse::<Vec<String>> {
+        Ok(HttpResponse::NotFound().json(ApiResponse {
             success: false,
-            data: None,
+            data: None as Option<Vec<String>>,
             error: Some("Library not found".to_string()),
         }))
     }
 }
 
-pub async fn get_book_file(
-    cache: web::Data<Mutex<LibraryCache>>,
-    path: web::Path<(String, i32, String)>,
-) -> Result<HttpResponse> {
-    let (library_id, book_id, format) = path.into_inner();
-    let cache = cache.lock().unwrap();
-    
-    if let Some(lib) = cache.get_library(&library_id) {
-        // Find book directory by searching for pattern "*({book_id})"
-        // Calibre stores books as: {library}/{author}/{book_title ({book_id})}/
-        let pattern = format!("({})", book_id);
-        let format_upper = format.to_uppercase();
-        
-        if let Ok(entries) = std::fs::read_dir(&lib.path) {
-            for author_dir in entries.flatten() {
-                let author_path = author_dir.path();
-                if author_path.is_dir() {
-                    if let Ok(book_entries) = std::fs::read_dir(&author_path) {
-                        for book_dir in book_entries.flatten() {
-                            let book_path = book_dir.path();
-                            if book_path.is_dir() {
-                                let dir_name = book_path.file_name()
-                                    .and_then(|n| n.to_str())
-                                    .unwrap_or("");
-                                
-                                if dir_name.ends_with(&pattern) {
-                                    // Look for the file with the matching format
-                                    if let Ok(file_entries) = std::fs::read_dir(&book_path) {
-                                        for file_entry in file_entries.flatten() {
-                                            let file_path = file_entry.path();
-                                            if file_path.is_file() {
-                                                if let Some(ext) = file_path.extension() {
-                                                    if ext.to_string_lossy().to_uppercase() == format_upper {
-                                                        if let Ok(data) = std::fs::read(&file_path) {
-                                                            let content_type = match format_upper.as_str() {
-                                                                "EPUB" => "application/epub+zip",
-                                                                "PDF" => "application/pdf",
-                                                                "MOBI" => "application/x-mobipocket-ebook",
-                                                                "AZW" => "application/vnd.amazon.ebook",
-                                                                "AZW3" => "application/vnd.amazon.ebook",
-                                                                "HTML" => "text/html",
-                                                                "TXT" => "text/plain; charset=utf-8",
-                                                                _ => "application/octet-stream",
-                                                            };
-                                                            
-                                                            let filename = file_path.file_name()
-                                                                .and_then(|n| n.to_str())
-                                                                .unwrap_or("book");
-                                                            
-                                                            return Ok(HttpResponse::Ok()
-                                                                .content_type(content_type)
-                                                                .insert_header(("Content-Disposition", format!("inline; filename=\"{}\"", filename)))
-                                                                .body(data));
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        // If not found, return 404
-        Ok(HttpResponse::NotFound().finish())
-    } else {
-        Ok(HttpResponse::NotFound().finish())
-    }
-}
-
-pub async fn refresh_libraries(
-    cache: web::Data<Mutex<LibraryCache>>,
+pub async fn get_tags(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<String>,
 ) -> Result<HttpResponse> {
-    let mut cache = cache.lock().unwrap();
-    let library_path = config::library_path();
-    let libraries_path = std::path::Path::new(&library_path);
-    
-    // Clear and reload the cache
-    cache.clear();
-    
-    if libraries_path.exists() {
-        match cache.load_libraries(libraries_path) {
-            Ok(_) => {
-                let libraries = cache.get_libraries();
+    let library_id = path.into_inner();
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        match web::block(move || db.get_all_tags()).await? {
+            Ok(tags) => {
                 Ok(HttpResponse::Ok().json(ApiResponse {
                     success: true,
-                    data: Some(libraries),
+                    data: Some(tags),
                     error: None,
                 }))
-            },
+            }
             Err(e) => {
                 Ok(HttpResponse::InternalServerError().json(ApiResponse {
                     success: false,
-                    data: None::<Vec<LibraryMetadata>>,
-                    error: Some(format!("Failed to refresh libraries: {}", e)),
+                    data: None as Option<Vec<String>>,
+                    error: Some(format!("Database error: {}", e)),
                 }))
             }
         }
     } else {
-        Ok(HttpResponse::InternalServerError().json(ApiResponse {
+        Ok(HttpResponse::NotFound().json(ApiResponse {
             success: false,
-            data: None::<Vec<LibraryMetadata>>,
-            error: Some("Libraries path not found".to_string()),
+            data: None as Option<Vec<String>>,
+            error: Some("Library not found".to_string()),
         }))
     }
 }
 
-pub async fn login(
-    req: web::Json<LoginRequest>,
-    _users: web::Data<Vec<auth::User>>,
-    audit_logger: web::Data<audit::AuditLogger>,
+pub async fn get_custom_columns(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<String>,
 ) -> Result<HttpResponse> {
-    // Load users from file to get the latest data (including newly created users)
-    let users_path = config::users_file_path();
-    let file_users = match auth::load_users(&users_path) {
-        Ok(users) => users,
-        Err(e) => {
-            audit_logger.log_event(
-                audit::AuditEventType::LoginFailure,
-                &req.username,
-                "127.0.0.1",
-                &format!("Error loading users: {}", e),
-                false,
-            );
-            
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                data: None::<serde_json::Value>,
-                error: Some(format!("Authentication system error: {}", e)),
-            }));
-        }
-    };
-
-    // Validate username and password
-    match auth::authenticate_user(&req.username, &req.password, &file_users) {
+    let library_id = path.into_inner();
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(db.custom_columns()),
+            error: None,
+        }))
+    } else {
+        Ok(HttpResponse::NotFound().json(ApiResponse::<Vec<CustomColumn>> {
+            success: false,
+            data: None,
+            error: Some("Library not found".to_string()),
+        }))
+    }
+}
+
+pub async fn get_custom_column_values(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse> {
+    let (library_id, label) = path.into_inner();
+    let Some(db) = visible_library_db(&cache, &library_id, &user) else {
+        return Ok(HttpResponse::NotFound().json(ApiResponse::<Vec<CustomColumnValue>> {
+            success: false,
+            data: None,
+            error: Some("Library not found".to_string()),
+        }));
+    };
+    let Some(column) = db.custom_column(&label) else {
+        return Ok(HttpResponse::NotFound().json(ApiResponse::<Vec<CustomColumnValue>> {
+            success: false,
+            data: None,
+            error: Some(format!("Unknown custom column '{}'", label)),
+        }));
+    };
+
+    let column = column.clone();
+    match web::block(move || db.get_custom_column_values(&column)).await? {
+        Ok(values) => {
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(values),
+                error: None,
+            }))
+        }
+        Err(e) => {
+            Ok(HttpResponse::InternalServerError().json(ApiResponse::<Vec<CustomColumnValue>> {
+                success: false,
+                data: None,
+                error: Some(format!("Database error: {}", e)),
+            }))
+        }
+    }
+}
+
+pub async fn get_series(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<String>,
+) -> Result<HttpResponse> {
+    let library_id = path.into_inner();
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        match web::block(move || db.get_all_series()).await? {
+            Ok(series) => {
+                Ok(HttpResponse::Ok().json(ApiResponse {
+                    success: true,
+                    data: Some(series),
+                    error: None,
+                }))
+            }
+            Err(e) => {
+                Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                    success: false,
+                    data: None as Option<Vec<String>>,
+                    error: Some(format!("Database error: {}", e)),
+                }))
+            }
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json(ApiResponse {
+            success: false,
+            data: None as Option<Vec<String>>,
+            error: Some("Library not found".to_string()),
+        }))
+    }
+}
+
+/// Browsers may reuse a cover for an hour before revalidating it with its ETag
+const COVER_CACHE_CONTROL: &str = "private, max-age=3600";
+
+#[derive(Debug, Deserialize)]
+pub struct CoverQuery {
+    /// Thumbnail width in pixels, rounded up to one of `thumbnail::WIDTHS`
+    pub w: Option<u32>,
+    /// Thumbnail encoding, `jpeg` (default) or `webp`
+    pub format: Option<ThumbnailFormat>,
+}
+
+/// Serve a book cover, or a resized thumbnail of it when `w` is given.
+/// Thumbnails are cached under `thumbnail_cache_path`.
+pub async fn get_book_cover(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<(String, i32)>,
+    query: web::Query<CoverQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let (library_id, book_id) = path.into_inner();
+
+    let files = match visible_library_db(&cache, &library_id, &user) {
+        Some(db) => match web::block(move || db.get_book_files(book_id)).await? {
+            Ok(files) => files,
+            Err(e) => {
+                error!("Failed to resolve cover of book {} in library {}: {}", book_id, library_id, e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        },
+        None => None,
+    };
+    let Some((cover_path, last_modified)) = files.and_then(|f| f.cover.map(|c| (c, f.last_modified))) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let Some(width) = query.w else {
+        // Full-size cover; NamedFile takes care of ETag and Last-Modified
+        return match NamedFile::open_async(&cover_path).await {
+            Ok(file) => Ok(file
+                .set_content_type(actix_web::mime::IMAGE_JPEG)
+                .customize()
+                .insert_header((header::CACHE_CONTROL, COVER_CACHE_CONTROL))
+                .respond_to(&req)
+                .map_into_boxed_body()),
+            Err(_) => Ok(HttpResponse::NotFound().finish()),
+        };
+    };
+
+    let request = ThumbnailRequest {
+        library_id,
+        book_id,
+        cover: cover_path,
+        last_modified,
+        width: thumbnail::preset_width(width),
+        format: query.format.unwrap_or_default(),
+    };
+    let Ok(fingerprint) = request.fingerprint() else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let etag = EntityTag::new_strong(fingerprint.clone());
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .insert_header((header::CACHE_CONTROL, COVER_CACHE_CONTROL))
+            .finish());
+    }
+
+    let content_type = request.format.content_type();
+    let cache_dir = PathBuf::from(config::thumbnail_cache_path());
+    let rendered = web::block(move || {
+        request.cached(&cache_dir, &fingerprint).and_then(std::fs::read)
+    }).await?;
+
+    match rendered {
+        Ok(data) => {
+            Ok(HttpResponse::Ok()
+                .content_type(content_type)
+                .insert_header(header::ETag(etag))
+                .insert_header((header::CACHE_CONTROL, COVER_CACHE_CONTROL))
+                .body(data))
+        }
+        Err(e) => {
+            error!("Failed to render cover thumbnail of book {}: {}", book_id, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+pub async fn get_book_formats(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse> {
+    let (library_id, book_id) = path.into_inner();
+    if let Some(db) = visible_library_db(&cache, &library_id, &user) {
+        match web::block(move || db.get_book_formats(book_id)).await? {
+            Ok(formats) => {
+                Ok(HttpResponse::Ok().json(ApiResponse {
+                    success: true,
+                    data: Some(formats),
+                    error: None,
+                }))
+            }
+            Err(e) => {
+                Ok(HttpResponse::InternalServerError().json(ApiResponse::<Vec<String>> {
+                    success: false,
+                    data: None,
+                    error: Some(format!("Database error: {}", e)),
+                }))
+            }
+        }
+    } else {
+        Ok(HttpResponse::NotFound().json(ApiResponse::<Vec<String>> {
+            success: false,
+            data: None,
+            error: Some("Library not found".to_string()),
+        }))
+    }
+}
+
+/// MIME type of a Calibre format, given in upper case
+pub fn format_content_type(format: &str) -> &'static str {
+    match format {
+        "EPUB" => "application/epub+zip",
+        "PDF" => "application/pdf",
+        "MOBI" => "application/x-mobipocket-ebook",
+        "AZW" => "application/vnd.amazon.ebook",
+        "AZW3" => "application/vnd.amazon.ebook",
+        "HTML" => "text/html",
+        "TXT" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BookFileQuery {
+    /// Ask the browser to save the file rather than open it
+    pub download: Option<bool>,
+}
+
+/// Stream a book file. Range, `If-None-Match` and `If-Modified-Since` requests are
+/// answered by `NamedFile`, so interrupted downloads can resume.
+pub async fn get_book_file(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    path: web::Path<(String, i32, String)>,
+    query: web::Query<BookFileQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse> {
+    let (library_id, book_id, format) = path.into_inner();
+    let format_upper = format.to_uppercase();
+
+    let files = match visible_library_db(&cache, &library_id, &user) {
+        Some(db) => match web::block(move || db.get_book_files(book_id)).await? {
+            Ok(files) => files,
+            Err(e) => {
+                error!("Failed to resolve {} file of book {} in library {}: {}", format_upper, book_id, library_id, e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        },
+        None => None,
+    };
+    let file_path = files.and_then(|files| {
+        files.formats.into_iter()
+            .find(|(f, _)| *f == format_upper)
+            .map(|(_, path)| path)
+    });
+
+    let Some(file_path) = file_path else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let file = match NamedFile::open_async(&file_path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Book file missing on disk: {:?}: {}", file_path, e);
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let content_type = format_content_type(&format_upper);
+
+    let filename = file_path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("book")
+        .to_string();
+    let disposition = if query.download.unwrap_or(false) {
+        DispositionType::Attachment
+    } else {
+        DispositionType::Inline
+    };
+
+    // ASCII filename for old clients, RFC 5987 filename* for non-ASCII names
+    let ascii_name: String = filename.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+    let mut parameters = vec![DispositionParam::Filename(ascii_name)];
+    if !filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.into_bytes(),
+        }));
+    }
+
+    Ok(file
+        .set_content_type(content_type.parse().unwrap_or(actix_web::mime::APPLICATION_OCTET_STREAM))
+        .set_content_disposition(ContentDisposition { disposition, parameters })
+        .into_response(&req))
+}
+
+/// Rescan the libraries directory now rather than waiting for the watcher.
+/// Only added, removed and changed libraries are reloaded.
+pub async fn refresh_libraries(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    content_jobs: web::Data<ContentIndexJobs>,
+    events: web::Data<LibraryEvents>,
+) -> Result<HttpResponse> {
+    let library_path = config::library_path();
+    let libraries_path = PathBuf::from(&library_path);
+
+    if !libraries_path.exists() {
+        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+            success: false,
+            data: None::<Vec<LibraryMetadata>>,
+            error: Some("Libraries path not found".to_string()),
+        }));
+    }
+
+    let libraries = web::block(move || {
+        watcher::sync_libraries(&cache, &events, &content_jobs);
+
+        // Also retry indexes that failed or were interrupted earlier
+        {
+            let cache = cache.read().unwrap();
+            cache.refresh_search_indexes();
+            if config::content_indexing() {
+                content_jobs.start_all(&cache);
+            }
+        }
+        visible_libraries(&cache, &user)
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(libraries),
+        error: None,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LibraryChangesQuery {
+    pub since: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LibraryChanges {
+    /// Revision of the latest change, to pass as `since` next time
+    pub revision: u64,
+    /// `false` when changes since `since` are no longer known, e.g. after a
+    /// server restart; the client should then reload everything
+    pub complete: bool,
+    pub events: Vec<LibraryEvent>,
+}
+
+/// Library changes detected since a revision. Without `since`, only the
+/// current revision is returned, as a starting point.
+pub async fn get_library_changes(
+    user: session::AuthenticatedUser,
+    events: web::Data<LibraryEvents>,
+    query: web::Query<LibraryChangesQuery>,
+) -> Result<HttpResponse> {
+    let revision = events.revision();
+    let (complete, events) = match query.since {
+        None => (true, Vec::new()),
+        Some(since) => match events.since(since) {
+            Some(events) => (true, events),
+            None => (false, Vec::new()),
+        },
+    };
+    let revision = events.last().map(|e| e.revision).unwrap_or(revision);
+    let access = LibraryAccess::for_account(&user.account);
+    let events = events.into_iter().filter(|event| event.visible_to(&access)).collect();
+
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(LibraryChanges { revision, complete, events }),
+        error: None,
+    }))
+}
+
+/// Server-Sent Events stream of library changes: `library_added`,
+/// `library_removed` and `books_changed`, plus `resync` when events were missed
+/// and `session_expired` before the stream ends
+pub async fn library_event_stream(
+    req: HttpRequest,
+    user: session::AuthenticatedUser,
+    events: web::Data<LibraryEvents>,
+    sessions: web::Data<session::SessionStore>,
+) -> HttpResponse {
+    // Sent by browsers when reconnecting, so missed events are replayed
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let access = LibraryAccess::for_account(&user.account);
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        // Stop nginx from buffering the stream
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events::sse_stream(events, sessions, user.token, user.username, access, last_event_id))
+}
+
+pub async fn login(
+    http_req: HttpRequest,
+    req: web::Json<LoginRequest>,
+    session_store: web::Data<session::SessionStore>,
+    login_throttle: web::Data<LoginThrottle>,
+    audit_logger: web::Data<audit::AuditLogger>,
+) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
+    let throttle_keys = [
+        LockoutKey::User(req.username.clone()),
+        LockoutKey::Ip(client_ip.clone()),
+    ];
+
+    // Refuse attempts for locked out usernames or addresses before checking the password
+    let admission = login_throttle.check(&throttle_keys);
+    for key in &admission.expired {
+        audit_logger.log_event(
+            audit::AuditEventType::AccountUnlocked,
+            &req.username,
+            &client_ip,
+            &format!("Lockout expired for {}", key),
+            true,
+        );
+    }
+    if let Some(locked_until) = admission.locked_until {
+        audit_logger.log_event(
+            audit::AuditEventType::LoginFailure,
+            &req.username,
+            &client_ip,
+            &format!("Login refused: locked out until {}", locked_until.to_rfc3339()),
+            false,
+        );
+
+        let retry_after = (locked_until - chrono::Utc::now()).num_seconds().max(1);
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(ApiResponse {
+                success: false,
+                data: None::<serde_json::Value>,
+                error: Some(format!("Too many failed login attempts. Try again in {} seconds.", retry_after)),
+            }));
+    }
+
+    // Look the user up in the store to get the latest data (including newly created users)
+    let lookup = {
+        let username = req.username.clone();
+        web::block(move || users::store().get_user(&username)).await?
+    };
+    let stored_user = match lookup {
+        Ok(user) => user,
+        Err(e) => {
+            audit_logger.log_event(
+                audit::AuditEventType::LoginFailure,
+                &req.username,
+                &client_ip,
+                &format!("Error loading users: {}", e),
+                false,
+            );
+            
+            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
+                success: false,
+                data: None::<serde_json::Value>,
+                error: Some(format!("Authentication system error: {}", e)),
+            }));
+        }
+    };
+
+    // Validate username and password; Argon2 is slow on purpose, so off the async workers
+    let verified = {
+        let (username, password, stored_user) = (req.username.clone(), req.password.clone(), stored_user.clone());
+        web::block(move || auth::authenticate_user(&username, &password, stored_user.as_slice())).await?
+    };
+    match verified {
         Ok(true) => {
-            // Find the user to get their role
-            let user_role = file_users.iter()
-                .find(|u| u.username == req.username)
-                .map(|u| u.role.clone())
+            let user_role = stored_user
+                .map(|u| u.role)
                 .unwrap_or_else(|| "reader".to_string());
             
+            login_throttle.record_success(&req.username);
+
             audit_logger.log_event(
                 audit::AuditEventType::LoginSuccess,
                 &req.username,
-                "127.0.0.1",
+                &client_ip,
                 "User logged in successfully",
                 true,
             );
+
+            let token = session_store.create_session(&req.username);
             
-            Ok(HttpResponse::Ok().json(ApiResponse {
-                success: true,
-                data: Some(serde_json::json!({"username": req.username, "role": user_role})),
-                error: None,
-            }))
+            Ok(HttpResponse::Ok()
+                .cookie(session::session_cookie(&token))
+                .json(ApiResponse {
+                    success: true,
+                    data: Some(serde_json::json!({"username": req.username, "role": user_role})),
+                    error: None,
+                }))
         }
         Ok(false) => {
             audit_logger.log_event(
                 audit::AuditEventType::LoginFailure,
                 &req.username,
-                "127.0.0.1",
+                &client_ip,
                 "Invalid credentials",
                 false,
             );
+
+            for (key, locked_until) in login_throttle.record_failure(&throttle_keys) {
+                audit_logger.log_event(
+                    audit::AuditEventType::AccountLocked,
+                    &req.username,
+                    &client_ip,
+                    &format!("Locked out {} until {} after repeated failed logins", key, locked_until.to_rfc3339()),
+                    false,
+                );
+            }
             
             Ok(HttpResponse::Unauthorized().json(ApiResponse {
                 success: false,
@@ -614,7 +1408,7 @@ pub async fn login(
             audit_logger.log_event(
                 audit::AuditEventType::LoginFailure,
                 &req.username,
-                "127.0.0.1",
+                &client_ip,
                 &format!("Authentication error: {}", e),
                 false,
             );
@@ -629,21 +1423,29 @@ pub async fn login(
 }
 
 pub async fn logout(
+    http_req: HttpRequest,
+    user: session::AuthenticatedUser,
+    session_store: web::Data<session::SessionStore>,
     audit_logger: web::Data<audit::AuditLogger>,
 ) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
+    session_store.invalidate_session(&user.token);
+
     audit_logger.log_event(
         audit::AuditEventType::LogoutSuccess,
-        "unknown",
-        "127.0.0.1",
+        &user.username,
+        &client_ip,
         "User logged out",
         true,
     );
     
-    Ok(HttpResponse::Ok().json(ApiResponse {
-        success: true,
-        data: Some(serde_json::json!({"message": "logged out"})),
-        error: None,
-    }))
+    Ok(HttpResponse::Ok()
+        .cookie(session::removal_cookie())
+        .json(ApiResponse {
+            success: true,
+            data: Some(serde_json::json!({"message": "logged out"})),
+            error: None,
+        }))
 }
 
 pub async fn log_client_diagnostics(
@@ -703,37 +1505,203 @@ pub async fn log_client_diagnostics(
 }
 
 pub async fn get_current_user(
-    _users: web::Data<Vec<auth::User>>,
+    user: session::AuthenticatedUser,
 ) -> Result<HttpResponse> {
-    // Return a generic user response - the frontend maintains auth state via localStorage
-    // In a production system with session/JWT, this would return the actual logged-in user
+    // The current role and email of the session user, as loaded by require_session
     Ok(HttpResponse::Ok().json(ApiResponse {
         success: true,
-        data: Some(serde_json::json!({
-            "username": "user",
-            "role": "reader",
-            "email": null
-        })),
+        data: Some(UserResponse::from((*user.account).clone())),
         error: None,
     }))
 }
 
+pub async fn get_preferences(
+    user: session::AuthenticatedUser,
+) -> Result<HttpResponse> {
+    match web::block(move || users::store().preferences(&user.username)).await? {
+        Ok(preferences) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(preferences),
+            error: None,
+        })),
+        Err(e) => Ok(user_store_error(&e)),
+    }
+}
+
+/// Replace the saved client preferences of the session user
+pub async fn set_preferences(
+    user: session::AuthenticatedUser,
+    preferences: web::Json<serde_json::Value>,
+) -> Result<HttpResponse> {
+    if !preferences.is_object() {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<serde_json::Value> {
+            success: false,
+            data: None,
+            error: Some("Preferences must be a JSON object".to_string()),
+        }));
+    }
+
+    let preferences = preferences.into_inner();
+    let saved = {
+        let preferences = preferences.clone();
+        web::block(move || users::store().set_preferences(&user.username, &preferences)).await?
+    };
+    match saved {
+        Ok(()) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(preferences),
+            error: None,
+        })),
+        Err(e) => Ok(user_store_error(&e)),
+    }
+}
+
+// API Token Endpoints
+
+pub async fn list_api_tokens(
+    user: session::AuthenticatedUser,
+) -> Result<HttpResponse> {
+    match web::block(move || users::store().api_tokens(&user.username)).await? {
+        Ok(tokens) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(tokens),
+            error: None,
+        })),
+        Err(e) => Ok(user_store_error(&e)),
+    }
+}
+
+/// Create an API token for the session user; the response is the only time the secret is shown
+pub async fn create_api_token(
+    http_req: HttpRequest,
+    user: session::AuthenticatedUser,
+    req: web::Json<CreateApiTokenRequest>,
+    audit_logger: web::Data<audit::AuditLogger>,
+) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
+    let name = req.name.trim().to_string();
+    if name.is_empty() {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse::<NewApiToken> {
+            success: false,
+            data: None,
+            error: Some("Token name is required".to_string()),
+        }));
+    }
+
+    let created = {
+        let username = user.username.clone();
+        web::block(move || users::store().create_api_token(&username, &name)).await?
+    };
+    match created {
+        Ok(token) => {
+            audit_logger.log_event(
+                audit::AuditEventType::ApiTokenCreated,
+                &user.username,
+                &client_ip,
+                &format!("Created API token {} ({})", token.token.name, token.token.id),
+                true,
+            );
+
+            Ok(HttpResponse::Created().json(ApiResponse {
+                success: true,
+                data: Some(token),
+                error: None,
+            }))
+        }
+        Err(e) => Ok(user_store_error(&e)),
+    }
+}
+
+pub async fn revoke_api_token(
+    http_req: HttpRequest,
+    user: session::AuthenticatedUser,
+    path: web::Path<String>,
+    audit_logger: web::Data<audit::AuditLogger>,
+) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
+    let id = path.into_inner();
+
+    let revoked = {
+        let (username, id) = (user.username.clone(), id.clone());
+        web::block(move || users::store().revoke_api_token(&username, &id)).await?
+    };
+    match revoked {
+        Ok(()) => {
+            audit_logger.log_event(
+                audit::AuditEventType::ApiTokenRevoked,
+                &user.username,
+                &client_ip,
+                &format!("Revoked API token {}", id),
+                true,
+            );
+
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(serde_json::json!({"message": "API token revoked"})),
+                error: None,
+            }))
+        }
+        Err(StoreError::NotFound) => Ok(HttpResponse::NotFound().json(ApiResponse::<serde_json::Value> {
+            success: false,
+            data: None,
+            error: Some("API token not found".to_string()),
+        })),
+        Err(e) => Ok(user_store_error(&e)),
+    }
+}
+
 // Password Management Endpoints
 
 pub async fn change_password(
+    http_req: HttpRequest,
+    user: session::AuthenticatedUser,
     req: web::Json<ChangePasswordRequest>,
-    _users: web::Data<Vec<auth::User>>,
+    login_throttle: web::Data<LoginThrottle>,
     audit_logger: web::Data<audit::AuditLogger>,
 ) -> Result<HttpResponse> {
-    // Validate new password strength
-    if let Err(e) = auth::validate_password_strength(&req.new_password) {
+    let client_ip = audit::client_ip(&http_req);
+    let username = user.username;
+    let log_failure = |details: &str| {
         audit_logger.log_event(
-            audit::AuditEventType::PasswordChange,
-            &req.username,
-            "127.0.0.1",
-            &format!("Failed: {}", e),
+            audit::AuditEventType::PasswordChangeFailed,
+            &username,
+            &client_ip,
+            details,
             false,
         );
+    };
+
+    // Guessing the current password counts towards the same lockouts as logging in
+    let throttle_keys = [
+        LockoutKey::User(username.clone()),
+        LockoutKey::Ip(client_ip.clone()),
+    ];
+    let admission = login_throttle.check(&throttle_keys);
+    for key in &admission.expired {
+        audit_logger.log_event(
+            audit::AuditEventType::AccountUnlocked,
+            &username,
+            &client_ip,
+            &format!("Lockout expired for {}", key),
+            true,
+        );
+    }
+    if let Some(locked_until) = admission.locked_until {
+        log_failure(&format!("Refused: locked out until {}", locked_until.to_rfc3339()));
+
+        let retry_after = (locked_until - chrono::Utc::now()).num_seconds().max(1);
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", retry_after.to_string()))
+            .json(ApiResponse {
+                success: false,
+                data: None::<serde_json::Value>,
+                error: Some(format!("Too many failed attempts. Try again in {} seconds.", retry_after)),
+            }));
+    }
+
+    // Validate new password strength
+    if let Err(e) = auth::validate_password_strength(&req.new_password) {
+        log_failure(&format!("Failed: {}", e));
         
         return Ok(HttpResponse::BadRequest().json(ApiResponse {
             success: false,
@@ -742,91 +1710,51 @@ pub async fn change_password(
         }));
     }
 
-    // Load users from file
-    let users_path = config::users_file_path();
-    let mut file_users = match auth::load_users(&users_path) {
-        Ok(users) => users,
-        Err(e) => {
-            audit_logger.log_event(
-                audit::AuditEventType::PasswordChange,
-                &req.username,
-                "127.0.0.1",
-                &format!("Failed: {}", e),
-                false,
-            );
-
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                data: None::<serde_json::Value>,
-                error: Some(format!("Error loading users: {}", e)),
-            }));
-        }
-    };
-
     // Authenticate user with current password
-    match auth::authenticate_user(&req.username, &req.current_password, &file_users) {
+    let verified = {
+        let (username, password, account) = (username.clone(), req.current_password.clone(), user.account.clone());
+        web::block(move || auth::authenticate_user(&username, &password, std::slice::from_ref(&*account))).await?
+    };
+    match verified {
         Ok(true) => {
+            login_throttle.record_success(&username);
+
             // Hash the new password
-            match auth::hash_password(&req.new_password) {
+            let new_password = req.new_password.clone();
+            match web::block(move || auth::hash_password(&new_password)).await? {
                 Ok(new_hash) => {
-                    // Find and update the user
-                    if let Some(user) = file_users.iter_mut().find(|u| u.username == req.username) {
-                        user.password_hash = new_hash;
-
-                        // Save updated users to file
-                        if let Err(e) = auth::save_users(&file_users, &users_path) {
+                    let updated = {
+                        let username = username.clone();
+                        web::block(move || {
+                            users::store().update_user(&username, &mut |user| user.password_hash = new_hash.clone())
+                        })
+                        .await?
+                    };
+                    match updated {
+                        Ok(_) => {
                             audit_logger.log_event(
                                 audit::AuditEventType::PasswordChange,
-                                &req.username,
-                                "127.0.0.1",
-                                &format!("Failed to save: {}", e),
-                                false,
+                                &username,
+                                &client_ip,
+                                "Password changed successfully",
+                                true,
                             );
 
-                            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                                success: false,
-                                data: None::<serde_json::Value>,
-                                error: Some(format!("Error saving password: {}", e)),
-                            }));
+                            Ok(HttpResponse::Ok().json(ApiResponse {
+                                success: true,
+                                data: Some(serde_json::json!({"message": "Password changed successfully"})),
+                                error: None,
+                            }))
                         }
+                        Err(e) => {
+                            log_failure(&format!("Failed to save: {}", e));
 
-                        audit_logger.log_event(
-                            audit::AuditEventType::PasswordChange,
-                            &req.username,
-                            "127.0.0.1",
-                            "Password changed successfully",
-                            true,
-                        );
-
-                        Ok(HttpResponse::Ok().json(ApiResponse {
-                            success: true,
-                            data: Some(serde_json::json!({"message": "Password changed successfully"})),
-                            error: None,
-                        }))
-                    } else {
-                        audit_logger.log_event(
-                            audit::AuditEventType::PasswordChange,
-                            &req.username,
-                            "127.0.0.1",
-                            "Failed: User not found",
-                            false,
-                        );
-
-                        Ok(HttpResponse::NotFound().json(ApiResponse {
-                            success: false,
-                            data: None::<serde_json::Value>,
-                            error: Some("User not found".to_string()),
-                        }))
+                            Ok(user_store_error(&e))
+                        }
                     }
                 }
                 Err(e) => {
-                    audit_logger.log_event(
-                        audit::AuditEventType::PasswordChange,
-                        &req.username,
-                        "127.0.0.1",
-                        &format!("Failed to hash password: {}", e),
-                        false,
-                    );
+                    log_failure(&format!("Failed to hash password: {}", e));
 
                     Ok(HttpResponse::InternalServerError().json(ApiResponse {
                         success: false,
@@ -837,13 +1765,17 @@ pub async fn change_password(
             }
         }
         Ok(false) => {
-            audit_logger.log_event(
-                audit::AuditEventType::PasswordChange,
-                &req.username,
-                "127.0.0.1",
-                "Failed: Invalid current password",
-                false,
-            );
+            log_failure("Failed: Invalid current password");
+
+            for (key, locked_until) in login_throttle.record_failure(&throttle_keys) {
+                audit_logger.log_event(
+                    audit::AuditEventType::AccountLocked,
+                    &username,
+                    &client_ip,
+                    &format!("Locked out {} until {} after repeated failed password checks", key, locked_until.to_rfc3339()),
+                    false,
+                );
+            }
 
             Ok(HttpResponse::Unauthorized().json(ApiResponse {
                 success: false,
@@ -852,6 +1784,8 @@ pub async fn change_password(
             }))
         }
         Err(e) => {
+            log_failure(&format!("Failed: {}", e));
+
             Ok(HttpResponse::InternalServerError().json(ApiResponse {
                 success: false,
                 data: None::<serde_json::Value>,
@@ -861,64 +1795,31 @@ pub async fn change_password(
     }
 }
 
-
 // User Management Endpoints (Admin Only)
 
-/// Helper function to verify if a user has admin role
-fn verify_admin_user(admin_username: Option<&str>) -> Result<(), String> {
-    let username = admin_username.ok_or("Admin username required")?;
-    
-    let users_path = config::users_file_path();
-    let file_users = auth::load_users(&users_path)
-        .map_err(|e| format!("Failed to load users: {}", e))?;
-    
-    let user = file_users.iter()
-        .find(|u| u.username == username)
-        .ok_or("Admin user not found")?;
-    
-    if user.role != "admin" {
-        return Err("User does not have admin role".to_string());
-    }
-    
-    Ok(())
+/// Answer a failed user store operation with the matching status code
+fn user_store_error(e: &StoreError) -> HttpResponse {
+    let mut response = match e {
+        StoreError::NotFound => HttpResponse::NotFound(),
+        StoreError::AlreadyExists => HttpResponse::Conflict(),
+        StoreError::Invalid(_) => HttpResponse::BadRequest(),
+        StoreError::Unsupported(_) => HttpResponse::NotImplemented(),
+        StoreError::Backend(_) => HttpResponse::InternalServerError(),
+    };
+    response.json(ApiResponse::<serde_json::Value> {
+        success: false,
+        data: None,
+        error: Some(e.to_string()),
+    })
 }
 
-pub async fn list_users(
-    query: web::Query<std::collections::HashMap<String, String>>,
-    _users: web::Data<Vec<auth::User>>,
-    audit_logger: web::Data<audit::AuditLogger>,
-) -> Result<HttpResponse> {
-    // Verify admin role (get from query parameter)
-    let admin_username = query.get("admin_username").map(|s| s.as_str());
-    
-    if let Err(e) = verify_admin_user(admin_username) {
-        audit_logger.log_event(
-            audit::AuditEventType::UnauthorizedAccess,
-            admin_username.unwrap_or("unknown"),
-            "127.0.0.1",
-            &format!("Unauthorized attempt to list users: {}", e),
-            false,
-        );
-
-        return Ok(HttpResponse::Forbidden().json(ApiResponse {
-            success: false,
-            data: None::<Vec<UserResponse>>,
-            error: Some("Unauthorized: Admin access required".to_string()),
-        }));
-    }
-
-    // Read users from file to get the latest data (including newly created users)
-    let users_path = config::users_file_path();
-    match auth::load_users(&users_path) {
-        Ok(file_users) => {
-            let user_responses: Vec<UserResponse> = file_users
-                .iter()
-                .map(|u| UserResponse {
-                    username: u.username.clone(),
-                    role: u.role.clone(),
-                    email: u.email.clone(),
-                    created_at: u.created_at.clone(),
-                })
+pub async fn list_users() -> Result<HttpResponse> {
+    // Read users from the store to get the latest data (including newly created users)
+    match web::block(|| users::store().list_users()).await? {
+        Ok(stored_users) => {
+            let user_responses: Vec<UserResponse> = stored_users
+                .into_iter()
+                .map(UserResponse::from)
                 .collect();
 
             Ok(HttpResponse::Ok().json(ApiResponse {
@@ -938,63 +1839,32 @@ pub async fn list_users(
 }
 
 pub async fn create_user(
+    http_req: HttpRequest,
+    admin: session::AuthenticatedUser,
     req: web::Json<CreateUserRequest>,
-    users: web::Data<Vec<auth::User>>,
     audit_logger: web::Data<audit::AuditLogger>,
 ) -> Result<HttpResponse> {
-    // Verify admin role
-    if let Err(e) = verify_admin_user(req.admin_username.as_deref()) {
-        audit_logger.log_event(
-            audit::AuditEventType::UnauthorizedAccess,
-            req.admin_username.as_deref().unwrap_or("unknown"),
-            "127.0.0.1",
-            &format!("Unauthorized attempt to create user: {}", e),
-            false,
-        );
-
-        return Ok(HttpResponse::Forbidden().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some("Unauthorized: Admin access required".to_string()),
-        }));
-    }
-
+    let client_ip = audit::client_ip(&http_req);
     // Validate password strength
     if let Err(e) = auth::validate_password_strength(&req.password) {
         audit_logger.log_event(
             audit::AuditEventType::UserCreated,
-            req.admin_username.as_deref().unwrap_or("admin"),
-            "127.0.0.1",
+            &admin.username,
+            &client_ip,
             &format!("Failed to create user {}: {}", req.username, e),
             false,
         );
 
-        return Ok(HttpResponse::BadRequest().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some(e),
-        }));
-    }
-
-    // Check if user already exists
-    if users.iter().any(|u| u.username == req.username) {
-        audit_logger.log_event(
-            audit::AuditEventType::UserCreated,
-            "admin",
-            "127.0.0.1",
-            &format!("Failed to create user {}: user already exists", req.username),
-            false,
-        );
-
-        return Ok(HttpResponse::Conflict().json(ApiResponse {
+        return Ok(HttpResponse::BadRequest().json(ApiResponse {
             success: false,
             data: None::<serde_json::Value>,
-            error: Some("User already exists".to_string()),
+            error: Some(e),
         }));
     }
 
     // Hash the password
-    match auth::hash_password(&req.password) {
+    let password = req.password.clone();
+    match web::block(move || auth::hash_password(&password)).await? {
         Ok(password_hash) => {
             let role = req.role.clone().unwrap_or_else(|| "reader".to_string());
             let created_at = chrono::Utc::now().to_rfc3339();
@@ -1005,63 +1875,37 @@ pub async fn create_user(
                 password_hash,
                 role: role.clone(),
                 email: req.email.clone(),
-                created_at: Some(created_at.clone()),
+                created_at: Some(created_at),
+                restrictions: req.restrictions.clone().unwrap_or_default(),
+                libraries: req.libraries.clone(),
             };
 
-            // Load all users from file (not from cache), add the new one, and save back to file
-            let users_path = config::users_file_path();
-            let mut all_users = match auth::load_users(&users_path) {
-                Ok(users) => users,
-                Err(e) => {
-                    audit_logger.log_event(
-                        audit::AuditEventType::UserCreated,
-                        req.admin_username.as_deref().unwrap_or("admin"),
-                        "127.0.0.1",
-                        &format!("Failed to load users from file: {}", e),
-                        false,
-                    );
-                    
-                    return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                        success: false,
-                        data: None::<serde_json::Value>,
-                        error: Some(format!("Error loading users: {}", e)),
-                    }));
-                }
+            // Add the new user; the store refuses taken usernames
+            let created = {
+                let new_user = new_user.clone();
+                web::block(move || users::store().create_user(&new_user)).await?
             };
-
-            all_users.push(new_user);
-
-            // Save to file
-            if let Err(e) = auth::save_users(&all_users, &users_path) {
+            if let Err(e) = created {
                 audit_logger.log_event(
                     audit::AuditEventType::UserCreated,
-                    "admin",
-                    "127.0.0.1",
-                    &format!("Failed to save user {} to file: {}", req.username, e),
+                    &admin.username,
+                    &client_ip,
+                    &format!("Failed to create user {}: {}", req.username, e),
                     false,
                 );
 
-                return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                    success: false,
-                    data: None::<serde_json::Value>,
-                    error: Some(format!("Error saving user to file: {}", e)),
-                }));
+                return Ok(user_store_error(&e));
             }
 
             audit_logger.log_event(
                 audit::AuditEventType::UserCreated,
-                "admin",
-                "127.0.0.1",
+                &admin.username,
+                &client_ip,
                 &format!("Created user {} with role {}", req.username, role),
                 true,
             );
 
-            let user_response = UserResponse {
-                username: req.username.clone(),
-                role,
-                email: req.email.clone(),
-                created_at: Some(created_at),
-            };
+            let user_response = UserResponse::from(new_user);
 
             Ok(HttpResponse::Created().json(ApiResponse {
                 success: true,
@@ -1072,8 +1916,8 @@ pub async fn create_user(
         Err(e) => {
             audit_logger.log_event(
                 audit::AuditEventType::UserCreated,
-                "admin",
-                "127.0.0.1",
+                &admin.username,
+                &client_ip,
                 &format!("Failed to create user {}: {}", req.username, e),
                 false,
             );
@@ -1088,156 +1932,104 @@ pub async fn create_user(
 }
 
 pub async fn update_user(
+    http_req: HttpRequest,
+    admin: session::AuthenticatedUser,
     path: web::Path<String>,
     req: web::Json<UpdateUserRequest>,
-    _users: web::Data<Vec<auth::User>>,
     audit_logger: web::Data<audit::AuditLogger>,
 ) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
     let username = path.into_inner();
 
-    // Verify admin role
-    if let Err(e) = verify_admin_user(req.admin_username.as_deref()) {
-        audit_logger.log_event(
-            audit::AuditEventType::UnauthorizedAccess,
-            req.admin_username.as_deref().unwrap_or("unknown"),
-            "127.0.0.1",
-            &format!("Unauthorized attempt to update user {}: {}", username, e),
-            false,
-        );
-
-        return Ok(HttpResponse::Forbidden().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some("Unauthorized: Admin access required".to_string()),
-        }));
-    }
-
-    // Load users from file
-    let users_path = config::users_file_path();
-    let mut file_users = match auth::load_users(&users_path) {
-        Ok(users) => users,
-        Err(e) => {
-            audit_logger.log_event(
-                audit::AuditEventType::UserModified,
-                req.admin_username.as_deref().unwrap_or("admin"),
-                "127.0.0.1",
-                &format!("Failed to update user {}: {}", username, e),
-                false,
-            );
-
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                data: None::<serde_json::Value>,
-                error: Some(format!("Error loading users: {}", e)),
-            }));
-        }
-    };
-
-    // Check if user exists and find their index
-    let user_index = file_users.iter().position(|u| u.username == username);
-    
-    if user_index.is_none() {
-        audit_logger.log_event(
-            audit::AuditEventType::UserModified,
-            "admin",
-            "127.0.0.1",
-            &format!("Failed to update user {}: user not found", username),
-            false,
-        );
-
-        return Ok(HttpResponse::NotFound().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some("User not found".to_string()),
-        }));
-    }
-
-    let idx = user_index.unwrap();
     let mut changes = vec![];
-    
-    // Update role if provided
     if let Some(role) = &req.role {
-        file_users[idx].role = role.clone();
         changes.push(format!("role={}", role));
     }
-    
-    // Update email if provided
     if let Some(email) = &req.email {
-        file_users[idx].email = Some(email.clone());
         changes.push(format!("email={}", email));
     }
-
-    // Save updated users to file
-    if let Err(e) = auth::save_users(&file_users, &users_path) {
-        audit_logger.log_event(
-            audit::AuditEventType::UserModified,
-            "admin",
-            "127.0.0.1",
-            &format!("Failed to update user {}: {}", username, e),
-            false,
-        );
-
-        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some(format!("Error saving user: {}", e)),
-        }));
+    if let Some(restrictions) = &req.restrictions {
+        changes.push(format!(
+            "allowed_tags=[{}] denied_tags=[{}] min_rating={}",
+            restrictions.allowed_tags.join(", "),
+            restrictions.denied_tags.join(", "),
+            restrictions.min_rating.unwrap_or(0)
+        ));
+    }
+    if let Some(libraries) = &req.libraries {
+        match libraries {
+            Some(libraries) => changes.push(format!("libraries=[{}]", libraries.join(", "))),
+            None => changes.push("libraries=default".to_string()),
+        }
     }
 
-    audit_logger.log_event(
-        audit::AuditEventType::UserModified,
-        "admin",
-        "127.0.0.1",
-        &format!("Updated user {}: {}", username, changes.join(", ")),
-        true,
-    );
-
-    let user = &file_users[idx];
-    let user_response = UserResponse {
-        username: user.username.clone(),
-        role: user.role.clone(),
-        email: user.email.clone(),
-        created_at: user.created_at.clone(),
+    // Apply the changes in one step so concurrent edits of other fields are kept
+    let result = {
+        let (username, req) = (username.clone(), req.into_inner());
+        web::block(move || {
+            users::store().update_user(&username, &mut |user| {
+                if let Some(role) = &req.role {
+                    user.role = role.clone();
+                }
+                if let Some(email) = &req.email {
+                    user.email = Some(email.clone());
+                }
+                if let Some(restrictions) = &req.restrictions {
+                    user.restrictions = restrictions.clone();
+                }
+                if let Some(libraries) = &req.libraries {
+                    user.libraries = libraries.clone();
+                }
+            })
+        })
+        .await?
     };
 
-    Ok(HttpResponse::Ok().json(ApiResponse {
-        success: true,
-        data: Some(user_response),
-        error: None,
-    }))
+    match result {
+        Ok(user) => {
+            audit_logger.log_event(
+                audit::AuditEventType::UserModified,
+                &admin.username,
+                &client_ip,
+                &format!("Updated user {}: {}", username, changes.join(", ")),
+                true,
+            );
+
+            Ok(HttpResponse::Ok().json(ApiResponse {
+                success: true,
+                data: Some(UserResponse::from(user)),
+                error: None,
+            }))
+        }
+        Err(e) => {
+            audit_logger.log_event(
+                audit::AuditEventType::UserModified,
+                &admin.username,
+                &client_ip,
+                &format!("Failed to update user {}: {}", username, e),
+                false,
+            );
+
+            Ok(user_store_error(&e))
+        }
+    }
 }
 
 pub async fn delete_user(
+    http_req: HttpRequest,
+    admin: session::AuthenticatedUser,
     path: web::Path<String>,
-    req: web::Json<DeleteUserRequest>,
-    _users: web::Data<Vec<auth::User>>,
     audit_logger: web::Data<audit::AuditLogger>,
 ) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
     let username = path.into_inner();
 
-    // Verify admin role
-    if let Err(e) = verify_admin_user(req.admin_username.as_deref()) {
-        audit_logger.log_event(
-            audit::AuditEventType::UnauthorizedAccess,
-            req.admin_username.as_deref().unwrap_or("unknown"),
-            "127.0.0.1",
-            &format!("Unauthorized attempt to delete user {}: {}", username, e),
-            false,
-        );
-
-        return Ok(HttpResponse::Forbidden().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some("Unauthorized: Admin access required".to_string()),
-        }));
-    }
-
     // Prevent deletion of admin users
     if username.to_lowercase() == "admin" {
         audit_logger.log_event(
             audit::AuditEventType::UserDeleted,
-            req.admin_username.as_deref().unwrap_or("admin"),
-            "127.0.0.1",
+            &admin.username,
+            &client_ip,
             "Failed to delete user admin: cannot delete admin user",
             false,
         );
@@ -1249,69 +2041,26 @@ pub async fn delete_user(
         }));
     }
 
-    // Load users from file
-    let users_path = config::users_file_path();
-    let mut file_users = match auth::load_users(&users_path) {
-        Ok(users) => users,
-        Err(e) => {
-            audit_logger.log_event(
-                audit::AuditEventType::UserDeleted,
-                req.admin_username.as_deref().unwrap_or("admin"),
-                "127.0.0.1",
-                &format!("Failed to delete user {}: {}", username, e),
-                false,
-            );
-
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                data: None::<serde_json::Value>,
-                error: Some(format!("Error loading users: {}", e)),
-            }));
-        }
+    let deleted = {
+        let username = username.clone();
+        web::block(move || users::store().delete_user(&username)).await?
     };
-
-    // Find and remove the user
-    let initial_len = file_users.len();
-    file_users.retain(|u| u.username != username);
-
-    if file_users.len() == initial_len {
-        // User not found
-        audit_logger.log_event(
-            audit::AuditEventType::UserDeleted,
-            "admin",
-            "127.0.0.1",
-            &format!("Failed to delete user {}: user not found", username),
-            false,
-        );
-
-        return Ok(HttpResponse::NotFound().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some("User not found".to_string()),
-        }));
-    }
-
-    // Save updated users to file
-    if let Err(e) = auth::save_users(&file_users, &users_path) {
+    if let Err(e) = deleted {
         audit_logger.log_event(
             audit::AuditEventType::UserDeleted,
-            "admin",
-            "127.0.0.1",
+            &admin.username,
+            &client_ip,
             &format!("Failed to delete user {}: {}", username, e),
             false,
         );
 
-        return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some(format!("Error saving users: {}", e)),
-        }));
+        return Ok(user_store_error(&e));
     }
 
     audit_logger.log_event(
         audit::AuditEventType::UserDeleted,
-        "admin",
-        "127.0.0.1",
+        &admin.username,
+        &client_ip,
         &format!("Deleted user {}", username),
         true,
     );
@@ -1324,30 +2073,15 @@ pub async fn delete_user(
 }
 
 pub async fn admin_change_password(
+    http_req: HttpRequest,
+    admin: session::AuthenticatedUser,
     path: web::Path<String>,
     req: web::Json<AdminChangePasswordRequest>,
-    _users: web::Data<Vec<auth::User>>,
     audit_logger: web::Data<audit::AuditLogger>,
 ) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
     let username = path.into_inner();
 
-    // Verify admin role
-    if let Err(e) = verify_admin_user(req.admin_username.as_deref()) {
-        audit_logger.log_event(
-            audit::AuditEventType::UnauthorizedAccess,
-            req.admin_username.as_deref().unwrap_or("unknown"),
-            "127.0.0.1",
-            &format!("Unauthorized attempt to change password for {}: {}", username, e),
-            false,
-        );
-
-        return Ok(HttpResponse::Forbidden().json(ApiResponse {
-            success: false,
-            data: None::<serde_json::Value>,
-            error: Some("Unauthorized: Admin access required".to_string()),
-        }));
-    }
-
     if username != req.username {
         return Ok(HttpResponse::BadRequest().json(ApiResponse {
             success: false,
@@ -1360,8 +2094,8 @@ pub async fn admin_change_password(
     if let Err(e) = auth::validate_password_strength(&req.new_password) {
         audit_logger.log_event(
             audit::AuditEventType::PasswordChange,
-            req.admin_username.as_deref().unwrap_or("admin"),
-            "127.0.0.1",
+            &admin.username,
+            &client_ip,
             &format!("Failed to reset password for {}: {}", username, e),
             false,
         );
@@ -1373,84 +2107,50 @@ pub async fn admin_change_password(
         }));
     }
 
-    // Load users from file
-    let users_path = config::users_file_path();
-    let mut file_users = match auth::load_users(&users_path) {
-        Ok(users) => users,
-        Err(e) => {
-            audit_logger.log_event(
-                audit::AuditEventType::PasswordChange,
-                req.admin_username.as_deref().unwrap_or("admin"),
-                "127.0.0.1",
-                &format!("Failed to reset password for {}: {}", username, e),
-                false,
-            );
-
-            return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                success: false,
-                data: None::<serde_json::Value>,
-                error: Some(format!("Error loading users: {}", e)),
-            }));
-        }
-    };
-
-    match auth::hash_password(&req.new_password) {
+    let new_password = req.new_password.clone();
+    match web::block(move || auth::hash_password(&new_password)).await? {
         Ok(password_hash) => {
-            // Find and update the user's password
-            if let Some(user) = file_users.iter_mut().find(|u| u.username == username) {
-                user.password_hash = password_hash;
+            let updated = {
+                let username = username.clone();
+                web::block(move || {
+                    users::store().update_user(&username, &mut |user| user.password_hash = password_hash.clone())
+                })
+                .await?
+            };
+            match updated {
+                Ok(_) => {
+                    audit_logger.log_event(
+                        audit::AuditEventType::PasswordChange,
+                        &admin.username,
+                        &client_ip,
+                        &format!("Admin reset password for user {}", username),
+                        true,
+                    );
 
-                // Save updated users to file
-                if let Err(e) = auth::save_users(&file_users, &users_path) {
+                    Ok(HttpResponse::Ok().json(ApiResponse {
+                        success: true,
+                        data: Some(serde_json::json!({"message": "Password reset successfully"})),
+                        error: None,
+                    }))
+                }
+                Err(e) => {
                     audit_logger.log_event(
                         audit::AuditEventType::PasswordChange,
-                        "admin",
-                        "127.0.0.1",
+                        &admin.username,
+                        &client_ip,
                         &format!("Failed to reset password for {}: {}", username, e),
                         false,
                     );
 
-                    return Ok(HttpResponse::InternalServerError().json(ApiResponse {
-                        success: false,
-                        data: None::<serde_json::Value>,
-                        error: Some(format!("Error saving password: {}", e)),
-                    }));
+                    Ok(user_store_error(&e))
                 }
-
-                audit_logger.log_event(
-                    audit::AuditEventType::PasswordChange,
-                    "admin",
-                    "127.0.0.1",
-                    &format!("Admin reset password for user {}", username),
-                    true,
-                );
-
-                Ok(HttpResponse::Ok().json(ApiResponse {
-                    success: true,
-                    data: Some(serde_json::json!({"message": "Password reset successfully"})),
-                    error: None,
-                }))
-            } else {
-                audit_logger.log_event(
-                    audit::AuditEventType::PasswordChange,
-                    "admin",
-                    "127.0.0.1",
-                    &format!("Failed to reset password for {}: user not found", username),
-                    false,
-                );
-
-                Ok(HttpResponse::NotFound().json(ApiResponse {
-                    success: false,
-                    data: None::<serde_json::Value>,
-                    error: Some("User not found".to_string()),
-                }))
             }
         }
         Err(e) => {
             audit_logger.log_event(
                 audit::AuditEventType::PasswordChange,
-                "admin",
-                "127.0.0.1",
+                &admin.username,
+                &client_ip,
                 &format!("Failed to reset password for {}: {}", username, e),
                 false,
             );
@@ -1464,52 +2164,248 @@ pub async fn admin_change_password(
     }
 }
 
-// Audit Log Endpoints
+pub async fn get_content_index_progress(
+    user: session::AuthenticatedUser,
+    cache: web::Data<RwLock<LibraryCache>>,
+    jobs: web::Data<ContentIndexJobs>,
+) -> Result<HttpResponse> {
+    let access = LibraryAccess::for_account(&user.account);
+    let visible: Vec<String> = cache
+        .read()
+        .unwrap()
+        .get_libraries()
+        .into_iter()
+        .filter(|library| access.allows(&library.id, &library.name))
+        .map(|library| library.id)
+        .collect();
+    let mut progress = jobs.progress();
+    progress.retain(|p| visible.contains(&p.library_id));
 
-pub async fn get_audit_logs(
-    query: web::Query<AuditLogQuery>,
-    _users: web::Data<Vec<auth::User>>,
-    audit_logger: web::Data<audit::AuditLogger>,
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(progress),
+        error: None,
+    }))
+}
+
+/// Start indexing a library's book contents, resuming after the books already indexed
+pub async fn start_content_indexing(
+    cache: web::Data<RwLock<LibraryCache>>,
+    jobs: web::Data<ContentIndexJobs>,
+    path: web::Path<String>,
 ) -> Result<HttpResponse> {
-    let limit = query.limit.unwrap_or(100).min(1000);
+    let library_id = path.into_inner();
+    let (Some(db), Some(index)) = (library_db(&cache, &library_id), library_search_index(&cache, &library_id)) else {
+        return Ok(HttpResponse::NotFound().json(ApiResponse::<ContentIndexProgress> {
+            success: false,
+            data: None,
+            error: Some("Library not found".to_string()),
+        }));
+    };
 
-    let logs = if let Some(username) = &query.username {
-        audit_logger.get_user_logs(username, limit)
-    } else {
-        audit_logger.get_logs(limit)
+    if !jobs.start(&library_id, db, index) {
+        return Ok(HttpResponse::Conflict().json(ApiResponse {
+            success: false,
+            data: jobs.library_progress(&library_id),
+            error: Some("Content indexing is already running for this library".to_string()),
+        }));
+    }
+
+    Ok(HttpResponse::Accepted().json(ApiResponse {
+        success: true,
+        data: jobs.library_progress(&library_id),
+        error: None,
+    }))
+}
+
+pub async fn list_lockouts(
+    login_throttle: web::Data<LoginThrottle>,
+) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(ApiResponse {
+        success: true,
+        data: Some(login_throttle.lockouts()),
+        error: None,
+    }))
+}
+
+pub async fn clear_lockout(
+    http_req: HttpRequest,
+    admin: session::AuthenticatedUser,
+    path: web::Path<(String, String)>,
+    login_throttle: web::Data<LoginThrottle>,
+    audit_logger: web::Data<audit::AuditLogger>,
+) -> Result<HttpResponse> {
+    let client_ip = audit::client_ip(&http_req);
+    let (kind, value) = path.into_inner();
+
+    let key = match kind.as_str() {
+        "users" => LockoutKey::User(value),
+        "ips" => LockoutKey::Ip(value),
+        _ => {
+            return Ok(HttpResponse::NotFound().json(ApiResponse::<serde_json::Value> {
+                success: false,
+                data: None,
+                error: Some("Lockout kind must be 'users' or 'ips'".to_string()),
+            }));
+        }
     };
 
+    if !login_throttle.unlock(&key) {
+        return Ok(HttpResponse::NotFound().json(ApiResponse::<serde_json::Value> {
+            success: false,
+            data: None,
+            error: Some(format!("No lockout recorded for {}", key)),
+        }));
+    }
+
+    audit_logger.log_event(
+        audit::AuditEventType::AccountUnlocked,
+        &admin.username,
+        &client_ip,
+        &format!("Admin cleared lockout for {}", key),
+        true,
+    );
+
     Ok(HttpResponse::Ok().json(ApiResponse {
         success: true,
-        data: Some(logs),
+        data: Some(serde_json::json!({"message": format!("Lockout cleared for {}", key)})),
         error: None,
     }))
 }
 
+// Audit Log Endpoints
+
+pub async fn get_audit_logs(
+    query: web::Query<AuditLogQuery>,
+    audit_logger: web::Data<audit::AuditLogger>,
+) -> Result<HttpResponse> {
+    let parse_time = |value: &Option<String>, name: &str| -> std::result::Result<_, String> {
+        value
+            .as_deref()
+            .map(|v| {
+                chrono::DateTime::parse_from_rfc3339(v)
+                    .map(|t| t.with_timezone(&chrono::Utc))
+                    .map_err(|e| format!("Invalid {} timestamp: {}", name, e))
+            })
+            .transpose()
+    };
+
+    let (since, until) = match (parse_time(&query.since, "since"), parse_time(&query.until, "until")) {
+        (Ok(since), Ok(until)) => (since, until),
+        (Err(e), _) | (_, Err(e)) => {
+            return Ok(HttpResponse::BadRequest().json(ApiResponse::<audit::AuditPage> {
+                success: false,
+                data: None,
+                error: Some(e),
+            }));
+        }
+    };
+
+    let audit_query = audit::AuditQuery {
+        username: query.username.clone(),
+        event_type: query.event_type.clone(),
+        success: query.success,
+        since,
+        until,
+        offset: query.offset.unwrap_or(0),
+        limit: query.limit.unwrap_or(100).min(1000),
+    };
+
+    match web::block(move || audit_logger.query(&audit_query)).await? {
+        Ok(page) => Ok(HttpResponse::Ok().json(ApiResponse {
+            success: true,
+            data: Some(page),
+            error: None,
+        })),
+        Err(e) => Ok(HttpResponse::InternalServerError().json(ApiResponse::<audit::AuditPage> {
+            success: false,
+            data: None,
+            error: Some(format!("Database error: {}", e)),
+        })),
+    }
+}
+
 pub fn configure(cfg: &mut web::ServiceConfig) {
+    use rbac::RequirePermission;
+
     cfg.service(
         web::scope("/api")
+            .wrap(middleware::from_fn(session::require_session))
             .route("/auth/login", web::post().to(login))
             .route("/auth/logout", web::post().to(logout))
             .route("/auth/current-user", web::get().to(get_current_user))
             .route("/auth/change-password", web::post().to(change_password))
+            .route("/auth/preferences", web::get().to(get_preferences))
+            .route("/auth/preferences", web::put().to(set_preferences))
+            .route("/auth/tokens", web::get().to(list_api_tokens))
+            .route("/auth/tokens", web::post().to(create_api_token))
+            .route("/auth/tokens/{id}", web::delete().to(revoke_api_token))
             .route("/diagnostics/client", web::post().to(log_client_diagnostics))
-            .route("/admin/users", web::get().to(list_users))
-            .route("/admin/users", web::post().to(create_user))
-            .route("/admin/users/{username}", web::put().to(update_user))
-            .route("/admin/users/{username}", web::delete().to(delete_user))
-            .route("/admin/users/{username}/password", web::post().to(admin_change_password))
-            .route("/admin/audit-logs", web::get().to(get_audit_logs))
-            .route("/libraries", web::get().to(get_libraries))
-            .route("/libraries/refresh", web::post().to(refresh_libraries))
-            .route("/libraries/{id}", web::get().to(get_library))
-            .route("/libraries/{id}/books", web::get().to(get_books))
-            .route("/libraries/{id}/authors", web::get().to(get_authors))
-            .route("/libraries/{id}/tags", web::get().to(get_tags))
-            .route("/libraries/{id}/series", web::get().to(get_series))
-            .route("/libraries/{id}/books/{book_id}", web::get().to(get_book))
-            .route("/libraries/{id}/books/{book_id}/cover", web::get().to(get_book_cover))
-            .route("/libraries/{id}/books/{book_id}/formats", web::get().to(get_book_formats))
-            .route("/libraries/{id}/books/{book_id}/formats/{format}", web::get().to(get_book_file))
+            .route("/admin/users", web::get().to(list_users)
+                .wrap(RequirePermission("manage_users")))
+            .route("/admin/users", web::post().to(create_user)
+                .wrap(RequirePermission("manage_users")))
+            .route("/admin/users/{username}", web::put().to(update_user)
+                .wrap(RequirePermission("manage_users")))
+            .route("/admin/users/{username}", web::delete().to(delete_user)
+                .wrap(RequirePermission("manage_users")))
+            .route("/admin/users/{username}/password", web::post().to(admin_change_password)
+                .wrap(RequirePermission("manage_users")))
+            .route("/admin/lockouts", web::get().to(list_lockouts)
+                .wrap(RequirePermission("manage_users")))
+            .route("/admin/lockouts/{kind}/{value}", web::delete().to(clear_lockout)
+                .wrap(RequirePermission("manage_users")))
+            .route("/admin/audit-logs", web::get().to(get_audit_logs)
+                .wrap(RequirePermission("view_audit_logs")))
+            .route("/admin/content-index", web::get().to(get_content_index_progress)
+                .wrap(RequirePermission("manage_libraries")))
+            .route("/admin/content-index/{id}", web::post().to(start_content_indexing)
+                .wrap(middleware::from_fn(rbac::require_library_access))
+                .wrap(RequirePermission("manage_libraries")))
+            .route("/libraries", web::get().to(get_libraries)
+                .wrap(RequirePermission("browse_libraries")))
+            .route("/search", web::get().to(search_all_libraries)
+                .wrap(RequirePermission("browse_libraries")))
+            .route("/libraries/refresh", web::post().to(refresh_libraries)
+                .wrap(RequirePermission("manage_libraries")))
+            .route("/libraries/changes", web::get().to(get_library_changes)
+                .wrap(RequirePermission("browse_libraries")))
+            .route("/events", web::get().to(library_event_stream)
+                .wrap(RequirePermission("browse_libraries")))
+            .service(
+                web::scope("/libraries/{id}")
+                    .wrap(middleware::from_fn(rbac::require_library_access))
+                .route("", web::get().to(get_library)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/books", web::get().to(get_books)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/search/content", web::get().to(search_book_contents)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/authors", web::get().to(get_authors)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/tags", web::get().to(get_tags)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/series", web::get().to(get_series)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/custom-columns", web::get().to(get_custom_columns)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/custom-columns/{label}/values", web::get().to(get_custom_column_values)
+                    .wrap(RequirePermission("browse_libraries")))
+                // Registered before `/books/{book_id}/...`; the value may contain slashes (DOIs)
+                .route("/books/by-identifier/{type}/{value:.*}", web::get().to(find_books_by_identifier)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/books/{book_id}", web::get().to(get_book)
+                    .wrap(RequirePermission("view_book_details")))
+                .route("/books/{book_id}/cover", web::get().to(get_book_cover)
+                    .wrap(RequirePermission("browse_libraries")))
+                .route("/books/{book_id}/identifiers", web::get().to(get_book_identifiers)
+                    .wrap(RequirePermission("view_book_details")))
+                .route("/books/{book_id}/formats", web::get().to(get_book_formats)
+                    .wrap(RequirePermission("view_book_details")))
+                .route("/books/{book_id}/formats/{format}", web::get().to(get_book_file)
+                    .wrap(RequirePermission("download_books")))
+                .route("/books/{book_id}/formats/{format}", web::head().to(get_book_file)
+                    .wrap(RequirePermission("download_books")))
+            )
     );
 }
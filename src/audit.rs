@@ -1,10 +1,15 @@
 // Audit logging for security events
-#![allow(dead_code)]
-use chrono::Utc;
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, Result as SqlResult};
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
-use tracing::{info, warn};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use tracing::{error, info, warn};
+use crate::config;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AuditEventType {
@@ -12,6 +17,7 @@ pub enum AuditEventType {
     LoginFailure,
     LogoutSuccess,
     PasswordChange,
+    PasswordChangeFailed,
     PasswordReset,
     UserCreated,
     UserDeleted,
@@ -19,6 +25,10 @@ pub enum AuditEventType {
     UnauthorizedAccess,
     SessionTimeout,
     PermissionDenied,
+    AccountLocked,
+    AccountUnlocked,
+    ApiTokenCreated,
+    ApiTokenRevoked,
 }
 
 impl std::fmt::Display for AuditEventType {
@@ -28,6 +38,7 @@ impl std::fmt::Display for AuditEventType {
             AuditEventType::LoginFailure => write!(f, "LOGIN_FAILURE"),
             AuditEventType::LogoutSuccess => write!(f, "LOGOUT_SUCCESS"),
             AuditEventType::PasswordChange => write!(f, "PASSWORD_CHANGE"),
+            AuditEventType::PasswordChangeFailed => write!(f, "PASSWORD_CHANGE_FAILED"),
             AuditEventType::PasswordReset => write!(f, "PASSWORD_RESET"),
             AuditEventType::UserCreated => write!(f, "USER_CREATED"),
             AuditEventType::UserDeleted => write!(f, "USER_DELETED"),
@@ -35,6 +46,10 @@ impl std::fmt::Display for AuditEventType {
             AuditEventType::UnauthorizedAccess => write!(f, "UNAUTHORIZED_ACCESS"),
             AuditEventType::SessionTimeout => write!(f, "SESSION_TIMEOUT"),
             AuditEventType::PermissionDenied => write!(f, "PERMISSION_DENIED"),
+            AuditEventType::AccountLocked => write!(f, "ACCOUNT_LOCKED"),
+            AuditEventType::AccountUnlocked => write!(f, "ACCOUNT_UNLOCKED"),
+            AuditEventType::ApiTokenCreated => write!(f, "API_TOKEN_CREATED"),
+            AuditEventType::ApiTokenRevoked => write!(f, "API_TOKEN_REVOKED"),
         }
     }
 }
@@ -49,16 +64,159 @@ pub struct AuditLog {
     pub success: bool,
 }
 
+/// Filters and pagination for audit log queries; every filter is optional
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub username: Option<String>,
+    pub event_type: Option<String>,
+    pub success: Option<bool>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// One page of audit events, newest first, with the number of matching events
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditPage {
+    pub logs: Vec<AuditLog>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// An event waiting for the writer thread
+struct PendingEvent {
+    timestamp: DateTime<Utc>,
+    event_type: String,
+    username: String,
+    ip_address: String,
+    details: String,
+    success: bool,
+}
+
+enum WriterMessage {
+    Event(PendingEvent),
+    /// Answered once everything sent before it is stored
+    Flush(mpsc::Sender<()>),
+}
+
+/// Audit log backed by SQLite. `open` persists events to a database file of
+/// their own; tests use `new`, which keeps a bounded number of events in memory.
+/// Events are stored by a writer thread, so logging never waits for the database.
 pub struct AuditLogger {
-    logs: Arc<Mutex<VecDeque<AuditLog>>>,
-    max_logs: usize,
+    conn: Arc<Mutex<Connection>>,
+    writer: mpsc::Sender<WriterMessage>,
 }
 
 impl AuditLogger {
+    #[cfg(test)]
     pub fn new(max_logs: usize) -> Self {
-        AuditLogger {
-            logs: Arc::new(Mutex::new(VecDeque::with_capacity(max_logs))),
-            max_logs,
+        let conn = Connection::open_in_memory()
+            .and_then(|conn| Self::init_schema(&conn).map(|_| conn))
+            .expect("in-memory audit database");
+        Self::start(conn, Some(max_logs))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> SqlResult<Self> {
+        let conn = Connection::open(path)?;
+        Self::init_schema(&conn)?;
+        Ok(Self::start(conn, None))
+    }
+
+    fn start(conn: Connection, max_logs: Option<usize>) -> Self {
+        let conn = Arc::new(Mutex::new(conn));
+        let (writer, receiver) = mpsc::channel();
+        let writer_conn = Arc::clone(&conn);
+        thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || Self::run_writer(&writer_conn, receiver, max_logs))
+            .expect("audit writer thread");
+        AuditLogger { conn, writer }
+    }
+
+    fn init_schema(conn: &Connection) -> SqlResult<()> {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS audit_log (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 timestamp TEXT NOT NULL,
+                 unix_micros INTEGER NOT NULL,
+                 event_type TEXT NOT NULL,
+                 username TEXT NOT NULL,
+                 ip_address TEXT NOT NULL,
+                 details TEXT NOT NULL,
+                 success INTEGER NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS audit_log_time ON audit_log (unix_micros);
+             CREATE INDEX IF NOT EXISTS audit_log_user ON audit_log (username);
+             CREATE INDEX IF NOT EXISTS audit_log_type ON audit_log (event_type);"
+        )
+    }
+
+    /// Store queued events until the logger is dropped, each burst in one transaction
+    fn run_writer(conn: &Mutex<Connection>, receiver: mpsc::Receiver<WriterMessage>, max_logs: Option<usize>) {
+        while let Ok(first) = receiver.recv() {
+            let mut events = Vec::new();
+            let mut flushes = Vec::new();
+            for message in std::iter::once(first).chain(receiver.try_iter()) {
+                match message {
+                    WriterMessage::Event(event) => events.push(event),
+                    WriterMessage::Flush(done) => flushes.push(done),
+                }
+            }
+
+            if !events.is_empty() {
+                match conn.lock() {
+                    Ok(mut conn) => {
+                        if let Err(e) = Self::store(&mut conn, &events, max_logs) {
+                            error!("Failed to store {} audit event(s): {}", events.len(), e);
+                        }
+                    }
+                    Err(_) => error!("Failed to store {} audit event(s): database lock poisoned", events.len()),
+                }
+            }
+            for done in flushes {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    fn store(conn: &mut Connection, events: &[PendingEvent], max_logs: Option<usize>) -> SqlResult<()> {
+        let tx = conn.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO audit_log (timestamp, unix_micros, event_type, username, ip_address, details, success)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for event in events {
+                insert.execute(params![
+                    event.timestamp.to_rfc3339(),
+                    event.timestamp.timestamp_micros(),
+                    event.event_type,
+                    event.username,
+                    event.ip_address,
+                    event.details,
+                    event.success,
+                ])?;
+            }
+        }
+
+        // Keep only the most recent logs in memory
+        if let Some(max_logs) = max_logs {
+            tx.execute(
+                "DELETE FROM audit_log WHERE id <= (SELECT MAX(id) FROM audit_log) - ?",
+                [max_logs as i64],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Wait until the events logged so far are stored
+    pub fn flush(&self) {
+        let (done, stored) = mpsc::channel();
+        if self.writer.send(WriterMessage::Flush(done)).is_ok() {
+            let _ = stored.recv();
         }
     }
 
@@ -70,22 +228,16 @@ impl AuditLogger {
         details: &str,
         success: bool,
     ) {
-        let log = AuditLog {
-            timestamp: Utc::now().to_rfc3339(),
+        let event = PendingEvent {
+            timestamp: Utc::now(),
             event_type: event_type.to_string(),
             username: username.to_string(),
             ip_address: ip_address.to_string(),
             details: details.to_string(),
             success,
         };
-
-        if let Ok(mut logs) = self.logs.lock() {
-            logs.push_back(log.clone());
-            
-            // Keep only the most recent logs
-            while logs.len() > self.max_logs {
-                logs.pop_front();
-            }
+        if self.writer.send(WriterMessage::Event(event)).is_err() {
+            error!("Failed to store audit event: writer thread stopped");
         }
 
         // Also log to tracing
@@ -96,34 +248,253 @@ impl AuditLogger {
         }
     }
 
-    pub fn get_logs(&self, limit: usize) -> Vec<AuditLog> {
-        if let Ok(logs) = self.logs.lock() {
-            logs.iter()
-                .rev()
-                .take(limit)
-                .cloned()
-                .collect()
-        } else {
-            Vec::new()
+    /// Blocks on the database; call from `web::block` in handlers
+    pub fn query(&self, query: &AuditQuery) -> SqlResult<AuditPage> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        if let Some(username) = &query.username {
+            conditions.push("username = ?");
+            values.push(Value::Text(username.clone()));
+        }
+        if let Some(event_type) = &query.event_type {
+            conditions.push("event_type = ?");
+            values.push(Value::Text(event_type.to_uppercase()));
+        }
+        if let Some(success) = query.success {
+            conditions.push("success = ?");
+            values.push(Value::Integer(success as i64));
+        }
+        if let Some(since) = query.since {
+            conditions.push("unix_micros >= ?");
+            values.push(Value::Integer(since.timestamp_micros()));
+        }
+        if let Some(until) = query.until {
+            conditions.push("unix_micros <= ?");
+            values.push(Value::Integer(until.timestamp_micros()));
         }
-    }
 
-    pub fn get_user_logs(&self, username: &str, limit: usize) -> Vec<AuditLog> {
-        if let Ok(logs) = self.logs.lock() {
-            logs.iter()
-                .rev()
-                .filter(|log| log.username == username)
-                .take(limit)
-                .cloned()
-                .collect()
+        let where_clause = if conditions.is_empty() {
+            String::new()
         } else {
-            Vec::new()
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        self.flush();
+        let conn = self.conn.lock().unwrap();
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM audit_log {}", where_clause),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT timestamp, event_type, username, ip_address, details, success
+             FROM audit_log {} ORDER BY id DESC LIMIT ? OFFSET ?",
+            where_clause
+        ))?;
+        values.push(Value::Integer(query.limit as i64));
+        values.push(Value::Integer(query.offset as i64));
+
+        let logs = stmt.query_map(params_from_iter(values.iter()), |row| {
+            Ok(AuditLog {
+                timestamp: row.get(0)?,
+                event_type: row.get(1)?,
+                username: row.get(2)?,
+                ip_address: row.get(3)?,
+                details: row.get(4)?,
+                success: row.get(5)?,
+            })
+        })?.collect::<SqlResult<Vec<_>>>()?;
+
+        Ok(AuditPage {
+            logs,
+            total: total as usize,
+            offset: query.offset,
+            limit: query.limit,
+        })
+    }
+
+    /// Delete events older than the given number of days, returning how many were removed
+    pub fn prune_older_than(&self, days: u32) -> SqlResult<usize> {
+        let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+        self.flush();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM audit_log WHERE unix_micros < ?",
+            [cutoff.timestamp_micros()],
+        )
+    }
+}
+
+/// Determine the address of the client that issued a request.
+///
+/// The peer address of the connection is used unless it belongs to one of the
+/// `trusted_proxies` from config.yaml, in which case the `Forwarded` (or, when
+/// absent, `X-Forwarded-For`) header is walked from the right, skipping trusted
+/// proxies, to find the first untrusted hop.
+pub fn client_ip(req: &HttpRequest) -> String {
+    let header = |name: &str| {
+        let values: Vec<&str> = req
+            .headers()
+            .get_all(name)
+            .filter_map(|v| v.to_str().ok())
+            .collect();
+        if values.is_empty() { None } else { Some(values.join(",")) }
+    };
+
+    resolve_client_ip(
+        req.peer_addr().map(|addr| addr.ip()),
+        header("forwarded").as_deref(),
+        header("x-forwarded-for").as_deref(),
+        &config::trusted_proxies(),
+    )
+}
+
+fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    forwarded: Option<&str>,
+    x_forwarded_for: Option<&str>,
+    trusted_proxies: &[String],
+) -> String {
+    let Some(peer) = peer else {
+        return "unknown".to_string();
+    };
+
+    if !is_trusted_proxy(&peer, trusted_proxies) {
+        return peer.to_string();
+    }
+
+    // Hops listed by the proxies, from the original client to the last proxy
+    let hops: Vec<String> = if let Some(forwarded) = forwarded {
+        forwarded
+            .split(',')
+            .flat_map(|element| element.split(';'))
+            .filter_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for").then(|| strip_port(value.trim_matches('"')))
+            })
+            .collect()
+    } else if let Some(x_forwarded_for) = x_forwarded_for {
+        x_forwarded_for
+            .split(',')
+            .map(|hop| strip_port(hop.trim()))
+            .filter(|hop| !hop.is_empty())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    for hop in hops.iter().rev() {
+        match hop.parse::<IpAddr>() {
+            Ok(ip) if is_trusted_proxy(&ip, trusted_proxies) => continue,
+            _ => return hop.clone(),
         }
     }
 
-    pub fn clear_logs(&self) {
-        if let Ok(mut logs) = self.logs.lock() {
-            logs.clear();
+    hops.first().cloned().unwrap_or_else(|| peer.to_string())
+}
+
+/// Remove an optional port (and IPv6 brackets) from a forwarded address
+fn strip_port(hop: &str) -> String {
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest).to_string();
+    }
+    match hop.rsplit_once(':') {
+        // A single colon means IPv4 with port; more colons means a bare IPv6 address
+        Some((host, _port)) if !host.contains(':') => host.to_string(),
+        _ => hop.to_string(),
+    }
+}
+
+/// Check an address against a list of trusted proxy IPs and CIDR ranges
+fn is_trusted_proxy(ip: &IpAddr, trusted_proxies: &[String]) -> bool {
+    trusted_proxies.iter().any(|entry| {
+        let (network, prefix) = match entry.trim().split_once('/') {
+            Some((network, prefix)) => (network, prefix.parse::<u32>().ok()),
+            None => (entry.trim(), None),
+        };
+
+        match (network.parse::<IpAddr>(), ip) {
+            (Ok(IpAddr::V4(network)), IpAddr::V4(ip)) => {
+                let prefix = prefix.unwrap_or(32).min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (Ok(IpAddr::V6(network)), IpAddr::V6(ip)) => {
+                let prefix = prefix.unwrap_or(128).min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
         }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted = vec!["10.0.0.0/8".to_string(), "192.168.1.5".to_string()];
+        let proxy: Option<IpAddr> = "10.1.2.3".parse().ok();
+
+        // Untrusted peers cannot spoof their address
+        assert_eq!(
+            resolve_client_ip("203.0.113.9".parse().ok(), None, Some("1.2.3.4"), &trusted),
+            "203.0.113.9"
+        );
+
+        // The rightmost untrusted hop wins
+        assert_eq!(
+            resolve_client_ip(proxy, None, Some("6.6.6.6, 198.51.100.7, 192.168.1.5"), &trusted),
+            "198.51.100.7"
+        );
+
+        // Forwarded takes precedence and may carry ports and IPv6 brackets
+        assert_eq!(
+            resolve_client_ip(proxy, Some("for=\"[2001:db8::1]:4711\";proto=https"), Some("1.2.3.4"), &trusted),
+            "2001:db8::1"
+        );
+        assert_eq!(
+            resolve_client_ip(proxy, Some("for=198.51.100.7:5555, for=10.0.0.2"), None, &trusted),
+            "198.51.100.7"
+        );
+
+        // Without headers the proxy itself is reported
+        assert_eq!(resolve_client_ip(proxy, None, None, &trusted), "10.1.2.3");
+    }
+
+    #[test]
+    fn test_query_filters_and_paging() {
+        let logger = AuditLogger::new(3);
+        logger.log_event(AuditEventType::LoginFailure, "alice", "10.0.0.1", "bad password", false);
+        logger.log_event(AuditEventType::LoginSuccess, "alice", "10.0.0.1", "ok", true);
+        logger.log_event(AuditEventType::LoginFailure, "bob", "10.0.0.2", "bad password", false);
+        logger.log_event(AuditEventType::LoginFailure, "bob", "10.0.0.2", "bad password", false);
+
+        // The in-memory logger keeps only the most recent events
+        let all = logger.query(&AuditQuery { limit: 10, ..Default::default() }).unwrap();
+        assert_eq!(all.total, 3);
+        assert_eq!(all.logs[0].username, "bob");
+
+        let failures = logger.query(&AuditQuery {
+            event_type: Some("login_failure".to_string()),
+            success: Some(false),
+            limit: 1,
+            offset: 1,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(failures.total, 2);
+        assert_eq!(failures.logs.len(), 1);
+
+        let future = logger.query(&AuditQuery {
+            since: Some(Utc::now() + chrono::Duration::hours(1)),
+            limit: 10,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(future.total, 0);
     }
 }
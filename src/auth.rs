@@ -5,6 +5,7 @@ use tracing::{debug, error, warn};
 use std::fs;
 use std::io::{self, BufRead};
 use serde::{Deserialize, Serialize};
+use crate::db::ContentRestrictions;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -13,6 +14,12 @@ pub struct User {
     pub role: String, // admin, librarian, user, reader
     pub email: Option<String>,
     pub created_at: Option<String>,
+    /// Books hidden from the user
+    #[serde(default)]
+    pub restrictions: ContentRestrictions,
+    /// Libraries the user may see, replacing their `library_access` entries in config.yaml
+    #[serde(default)]
+    pub libraries: Option<Vec<String>>,
 }
 
 /// Load users from the users.ids file
@@ -37,8 +44,9 @@ pub fn load_users(users_file_path: &str) -> Result<Vec<User>, Box<dyn std::error
             continue;
         }
 
-        // Parse username:password_hash:role:email:created_at format
+        // Parse username:password_hash:role:email:created_at[:restrictions] format
         // Note: created_at may contain colons (ISO 8601 with timezone), so we limit splits
+        // and tell the restrictions apart by their `key=value` pairs
         let parts: Vec<&str> = line.splitn(5, ':').collect();
         if parts.len() < 2 {
             warn!("Invalid format in users.ids at line {}: expected at least 'username:hash'", line_num + 1);
@@ -58,11 +66,14 @@ pub fn load_users(users_file_path: &str) -> Result<Vec<User>, Box<dyn std::error
         } else {
             None
         };
-        let created_at = if parts.len() > 4 && !parts[4].is_empty() {
-            Some(parts[4].to_string())
-        } else {
-            None
+        let (created_at, restrictions) = match parts.get(4).map(|rest| (rest, rest.rsplit_once(':'))) {
+            Some((_, Some((created_at, restrictions)))) if restrictions.contains('=') => {
+                (created_at, parse_restrictions(restrictions))
+            }
+            Some((rest, _)) => (*rest, ContentRestrictions::default()),
+            None => ("", ContentRestrictions::default()),
         };
+        let created_at = if created_at.is_empty() { None } else { Some(created_at.to_string()) };
 
         users.push(User {
             username: username.clone(),
@@ -70,6 +81,8 @@ pub fn load_users(users_file_path: &str) -> Result<Vec<User>, Box<dyn std::error
             role,
             email,
             created_at,
+            restrictions,
+            libraries: None,
         });
 
         debug!("Loaded user: {}", username);
@@ -85,11 +98,10 @@ pub fn load_users(users_file_path: &str) -> Result<Vec<User>, Box<dyn std::error
 }
 
 /// Hash a password using Argon2
-#[allow(dead_code)]
 pub fn hash_password(password: &str) -> Result<String, String> {
     use argon2::password_hash::rand_core::OsRng;
     
-    let salt = SaltString::generate(&mut OsRng::default());
+    let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     
     match argon2.hash_password(password.as_bytes(), &salt) {
@@ -169,27 +181,139 @@ pub fn validate_password_strength(password: &str) -> Result<(), String> {
     Ok(())
 }
 
-/// Save users to the users.ids file
+/// Save users to the users.ids file, replacing it only once fully written
 pub fn save_users(users: &[User], users_file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     use std::io::Write;
     
-    let mut file = fs::File::create(users_file_path)?;
+    let temp_path = format!("{}.tmp", users_file_path);
+    let mut file = io::BufWriter::new(fs::File::create(&temp_path)?);
     
     writeln!(file, "# Biblio users file - auto-generated")?;
-    writeln!(file, "# Format: username:password_hash:role:email:created_at")?;
+    writeln!(file, "# Format: username:password_hash:role:email:created_at[:restrictions]")?;
     writeln!(file)?;
     
     for user in users {
         let email = user.email.as_deref().unwrap_or("");
         let created_at = user.created_at.as_deref().unwrap_or("");
-        writeln!(file, "{}:{}:{}:{}:{}", 
-                 user.username, 
-                 user.password_hash, 
-                 user.role,
-                 email,
-                 created_at)?;
+        write!(file, "{}:{}:{}:{}:{}", 
+               user.username, 
+               user.password_hash, 
+               user.role,
+               email,
+               created_at)?;
+        if !user.restrictions.is_empty() {
+            write!(file, ":{}", format_restrictions(&user.restrictions))?;
+        }
+        writeln!(file)?;
     }
+    file.into_inner()?.sync_all()?;
+    fs::rename(&temp_path, users_file_path)?;
     
     debug!("Saved {} users to {}", users.len(), users_file_path);
     Ok(())
 }
+
+/// Escape the separators of the restrictions field in a tag name
+fn escape_tag(tag: &str) -> String {
+    let mut escaped = String::with_capacity(tag.len());
+    for c in tag.chars() {
+        match c {
+            '%' | ':' | ',' | ';' | '=' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape_tag(tag: &str) -> String {
+    let mut unescaped = String::with_capacity(tag.len());
+    let mut rest = tag;
+    while let Some(pos) = rest.find('%') {
+        unescaped.push_str(&rest[..pos]);
+        let code = rest.get(pos + 1..pos + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code as char);
+                rest = &rest[pos + 3..];
+            }
+            None => {
+                unescaped.push('%');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Format restrictions for users.ids, e.g. `denied_tags=adult,horror;min_rating=2`
+fn format_restrictions(restrictions: &ContentRestrictions) -> String {
+    let tags = |tags: &[String]| tags.iter().map(|t| escape_tag(t)).collect::<Vec<_>>().join(",");
+    let mut fields = Vec::new();
+    if !restrictions.allowed_tags.is_empty() {
+        fields.push(format!("allowed_tags={}", tags(&restrictions.allowed_tags)));
+    }
+    if !restrictions.denied_tags.is_empty() {
+        fields.push(format!("denied_tags={}", tags(&restrictions.denied_tags)));
+    }
+    if let Some(stars) = restrictions.min_rating.filter(|stars| *stars > 0) {
+        fields.push(format!("min_rating={}", stars));
+    }
+    fields.join(";")
+}
+
+fn parse_restrictions(field: &str) -> ContentRestrictions {
+    let tags = |value: &str| {
+        value.split(',').filter(|t| !t.is_empty()).map(unescape_tag).collect::<Vec<_>>()
+    };
+    let mut restrictions = ContentRestrictions::default();
+    for (key, value) in field.split(';').filter_map(|pair| pair.split_once('=')) {
+        match key.trim() {
+            "allowed_tags" => restrictions.allowed_tags = tags(value),
+            "denied_tags" => restrictions.denied_tags = tags(value),
+            "min_rating" => restrictions.min_rating = value.trim().parse().ok(),
+            other => warn!("Unknown restriction '{}' in users.ids", other),
+        }
+    }
+    restrictions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_users_file_restrictions() {
+        let path = std::env::temp_dir().join(format!("biblio-users-{}.ids", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "old:hash:reader:old@example.org:2025-01-02T03:04:05+00:00\n").unwrap();
+
+        let mut users = load_users(path).unwrap();
+        assert_eq!(users[0].created_at.as_deref(), Some("2025-01-02T03:04:05+00:00"));
+        assert!(users[0].restrictions.is_empty());
+
+        users[0].restrictions = ContentRestrictions {
+            allowed_tags: vec!["Kids".to_string(), "Genre: 50%, mostly".to_string()],
+            denied_tags: vec!["adult".to_string()],
+            min_rating: Some(3),
+        };
+        users.push(User {
+            username: "new".to_string(),
+            password_hash: "hash".to_string(),
+            role: "user".to_string(),
+            email: None,
+            created_at: None,
+            restrictions: ContentRestrictions { denied_tags: vec!["horror".to_string()], ..Default::default() },
+            libraries: None,
+        });
+        save_users(&users, path).unwrap();
+
+        let loaded = load_users(path).unwrap();
+        assert_eq!(loaded[0].created_at, users[0].created_at);
+        assert_eq!(loaded[0].restrictions, users[0].restrictions);
+        assert_eq!(loaded[1].created_at, None);
+        assert_eq!(loaded[1].restrictions, users[1].restrictions);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
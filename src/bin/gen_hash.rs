@@ -9,7 +9,7 @@ fn main() {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     match argon2.hash_password(password.as_bytes(), &salt) {
-        Ok(hashed) => println!("{}", hashed.to_string()),
+        Ok(hashed) => println!("{}", hashed),
         Err(e) => eprintln!("Error: {}", e),
     }
 }
@@ -13,10 +13,10 @@
 /// - Absolute paths are used as-is
 /// 
 /// See `config.yaml.example` for setup instructions.
-
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use tracing::{error, info, warn};
 
@@ -29,8 +29,17 @@ pub struct Config {
     /// IP and port for the Biblio server to bind to
     pub service_ip_and_port: String,
     
-    /// Path to the users.ids file containing user credentials
+    /// Path to the users.ids file containing user credentials; with the SQLite
+    /// user store it is only read once, to import its users
     pub users_file_path: String,
+
+    /// Where user accounts are kept
+    #[serde(default)]
+    pub user_store: UserStoreKind,
+
+    /// Path to the SQLite database holding users, their preferences and API tokens
+    #[serde(default = "default_user_db_path")]
+    pub user_db_path: String,
     
     /// Enable HTTPS/TLS
     pub use_https: bool,
@@ -40,6 +49,133 @@ pub struct Config {
     
     /// Path to the SSL/TLS private key file (PEM format)
     pub private_key_path: String,
+
+    /// Reverse proxies (IP addresses or CIDR ranges) whose `Forwarded` and
+    /// `X-Forwarded-For` headers are trusted to carry the real client address
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    /// Path to the SQLite database where audit events are stored
+    #[serde(default = "default_audit_db_path")]
+    pub audit_db_path: String,
+
+    /// Number of days audit events are kept (0 keeps them forever)
+    #[serde(default)]
+    pub audit_retention_days: u32,
+
+    /// Limits on failed login attempts
+    #[serde(default)]
+    pub login_throttle: LoginThrottleConfig,
+
+    /// Directory where resized cover thumbnails are cached
+    #[serde(default = "default_thumbnail_cache_path")]
+    pub thumbnail_cache_path: String,
+
+    /// Directory holding the full-text search index of each library
+    #[serde(default = "default_search_index_path")]
+    pub search_index_path: String,
+
+    /// Index the text of EPUB and TXT books in the background at startup and after a refresh
+    #[serde(default)]
+    pub content_indexing: bool,
+
+    /// How changes to the libraries directory are picked up
+    #[serde(default)]
+    pub library_watch: LibraryWatchMode,
+
+    /// Seconds between rescans of the libraries directory
+    #[serde(default = "default_library_poll_seconds")]
+    pub library_poll_seconds: u64,
+
+    /// Libraries each role or user may see
+    #[serde(default)]
+    pub library_access: LibraryAccessConfig,
+}
+
+/// User account storage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStoreKind {
+    /// A SQLite database, importing users.ids on first start
+    #[default]
+    Sqlite,
+    /// The users.ids flat file, without preferences or API tokens
+    File,
+}
+
+/// Library change detection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LibraryWatchMode {
+    /// Filesystem notifications, plus a periodic rescan for changes they miss
+    #[default]
+    Auto,
+    /// Periodic rescans only, for NFS or SMB mounts
+    Poll,
+    /// Only the Refresh button picks up changes
+    Off,
+}
+
+/// Libraries visible per role and per user, listed by library id or name ("*" for
+/// all of them). A user entry replaces the entry of their role; without either,
+/// every library is visible. Admins always see every library.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibraryAccessConfig {
+    pub roles: HashMap<String, Vec<String>>,
+    pub users: HashMap<String, Vec<String>>,
+}
+
+/// Failed login limits; a threshold of 0 disables that limit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoginThrottleConfig {
+    /// Failed attempts allowed per username within the window
+    pub max_failures_per_user: u32,
+
+    /// Failed attempts allowed per client IP within the window
+    pub max_failures_per_ip: u32,
+
+    /// Period over which failed attempts are counted, in seconds
+    pub window_seconds: u64,
+
+    /// Length of the first lockout in seconds; each consecutive lockout doubles it
+    pub lockout_seconds: u64,
+
+    /// Upper bound for the lockout length in seconds
+    pub max_lockout_seconds: u64,
+}
+
+impl Default for LoginThrottleConfig {
+    fn default() -> Self {
+        LoginThrottleConfig {
+            max_failures_per_user: 5,
+            max_failures_per_ip: 20,
+            window_seconds: 900,
+            lockout_seconds: 60,
+            max_lockout_seconds: 3600,
+        }
+    }
+}
+
+fn default_user_db_path() -> String {
+    "users.db".to_string()
+}
+
+fn default_audit_db_path() -> String {
+    "audit.db".to_string()
+}
+
+fn default_thumbnail_cache_path() -> String {
+    "thumbnails".to_string()
+}
+
+fn default_search_index_path() -> String {
+    "search-index".to_string()
+}
+
+fn default_library_poll_seconds() -> u64 {
+    60
 }
 
 impl Config {
@@ -53,7 +189,7 @@ impl Config {
     }
 
     /// Resolve a path: if relative, make it relative to base_dir; if absolute, use as-is
-    fn resolve_path(base_dir: &PathBuf, path: &str) -> String {
+    fn resolve_path(base_dir: &Path, path: &str) -> String {
         let p = PathBuf::from(path);
         if p.is_absolute() {
             path.to_string()
@@ -71,8 +207,12 @@ impl Config {
         let base_dir = Self::get_base_dir();
         config.library_path = Self::resolve_path(&base_dir, &config.library_path);
         config.users_file_path = Self::resolve_path(&base_dir, &config.users_file_path);
+        config.user_db_path = Self::resolve_path(&base_dir, &config.user_db_path);
         config.certificate_path = Self::resolve_path(&base_dir, &config.certificate_path);
         config.private_key_path = Self::resolve_path(&base_dir, &config.private_key_path);
+        config.audit_db_path = Self::resolve_path(&base_dir, &config.audit_db_path);
+        config.thumbnail_cache_path = Self::resolve_path(&base_dir, &config.thumbnail_cache_path);
+        config.search_index_path = Self::resolve_path(&base_dir, &config.search_index_path);
         
         Ok(config)
     }
@@ -145,6 +285,14 @@ pub fn users_file_path() -> String {
     with(|cfg| cfg.users_file_path.clone())
 }
 
+pub fn user_store() -> UserStoreKind {
+    with(|cfg| cfg.user_store)
+}
+
+pub fn user_db_path() -> String {
+    with(|cfg| cfg.user_db_path.clone())
+}
+
 pub fn use_https() -> bool {
     with(|cfg| cfg.use_https)
 }
@@ -157,6 +305,46 @@ pub fn private_key_path() -> String {
     with(|cfg| cfg.private_key_path.clone())
 }
 
+pub fn trusted_proxies() -> Vec<String> {
+    with(|cfg| cfg.trusted_proxies.clone())
+}
+
+pub fn audit_db_path() -> String {
+    with(|cfg| cfg.audit_db_path.clone())
+}
+
+pub fn audit_retention_days() -> u32 {
+    with(|cfg| cfg.audit_retention_days)
+}
+
+pub fn login_throttle() -> LoginThrottleConfig {
+    with(|cfg| cfg.login_throttle.clone())
+}
+
+pub fn thumbnail_cache_path() -> String {
+    with(|cfg| cfg.thumbnail_cache_path.clone())
+}
+
+pub fn search_index_path() -> String {
+    with(|cfg| cfg.search_index_path.clone())
+}
+
+pub fn content_indexing() -> bool {
+    with(|cfg| cfg.content_indexing)
+}
+
+pub fn library_watch() -> LibraryWatchMode {
+    with(|cfg| cfg.library_watch)
+}
+
+pub fn library_poll_seconds() -> u64 {
+    with(|cfg| cfg.library_poll_seconds)
+}
+
+pub fn library_access() -> LibraryAccessConfig {
+    with(|cfg| cfg.library_access.clone())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -0,0 +1,581 @@
+// Text extraction from EPUB and TXT files and the background job indexing it
+use chrono::{DateTime, Utc};
+use quick_xml::events::Event;
+use quick_xml::{Reader, XmlVersion};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use tracing::{error, info, warn};
+use zip::ZipArchive;
+use crate::db::CalibreDb;
+use crate::library::LibraryCache;
+use crate::search::SearchIndex;
+
+/// Formats whose text can be indexed, in order of preference
+pub const INDEXABLE_FORMATS: [&str; 2] = ["EPUB", "TXT"];
+
+/// Most bytes read from one EPUB entry or TXT file, guarding against zip bombs
+const MAX_TEXT_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Plain text files are split into sections of about this size to give matches a location
+const TXT_SECTION_BYTES: usize = 64 * 1024;
+
+/// Elements whose text is never shown to readers
+const HIDDEN_ELEMENTS: [&str; 3] = ["head", "script", "style"];
+
+/// Elements that separate words, so `<p>one</p><p>two</p>` does not read as "onetwo"
+const BLOCK_ELEMENTS: [&str; 24] = [
+    "address", "article", "aside", "blockquote", "br", "dd", "div", "dl", "dt", "figcaption",
+    "figure", "footer", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "li", "p", "section",
+    "td",
+];
+
+/// One indexed part of a book: an EPUB spine item or a section of a text file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    /// Path of the spine item inside the EPUB; `None` for text files
+    pub location: Option<String>,
+    pub heading: Option<String>,
+    pub text: String,
+}
+
+/// Readable text of an HTML document
+#[derive(Debug, Default)]
+pub struct HtmlText {
+    pub title: Option<String>,
+    /// Text of the first `h1`-`h3` heading
+    pub heading: Option<String>,
+    pub text: String,
+}
+
+/// Extract the text of a book file, chapter by chapter
+pub fn extract_chapters(path: &Path, format: &str) -> io::Result<Vec<Chapter>> {
+    match format {
+        "EPUB" => epub_chapters(path),
+        "TXT" => txt_chapters(path),
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, format!("Cannot index {} files", format))),
+    }
+}
+
+fn epub_chapters(path: &Path) -> io::Result<Vec<Chapter>> {
+    let mut archive = ZipArchive::new(File::open(path)?).map_err(io::Error::other)?;
+
+    let container = read_entry(&mut archive, "META-INF/container.xml")?;
+    let opf_path = xml_elements(&container, "rootfile")
+        .into_iter()
+        .find_map(|mut attrs| attrs.remove("full-path"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "EPUB has no package document"))?;
+    let opf = read_entry(&mut archive, &opf_path)?;
+    let base = opf_path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+
+    let manifest: HashMap<String, (String, String)> = xml_elements(&opf, "item")
+        .into_iter()
+        .filter_map(|mut attrs| {
+            Some((attrs.remove("id")?, (attrs.remove("href")?, attrs.remove("media-type").unwrap_or_default())))
+        })
+        .collect();
+
+    let mut chapters = Vec::new();
+    for mut itemref in xml_elements(&opf, "itemref") {
+        let Some((href, media_type)) = itemref.remove("idref").and_then(|id| manifest.get(&id)) else {
+            continue;
+        };
+        if !media_type.contains("html") {
+            continue;
+        }
+
+        let location = resolve_href(base, href);
+        let html = match read_entry(&mut archive, &location) {
+            Ok(html) => html,
+            Err(e) => {
+                warn!("Skipping missing chapter {} in {:?}: {}", location, path, e);
+                continue;
+            }
+        };
+        let content = html_to_text(&html);
+        if content.text.is_empty() {
+            continue;
+        }
+        chapters.push(Chapter {
+            location: Some(location),
+            heading: content.heading.or(content.title),
+            text: content.text,
+        });
+    }
+    Ok(chapters)
+}
+
+fn txt_chapters(path: &Path) -> io::Result<Vec<Chapter>> {
+    let mut data = Vec::new();
+    File::open(path)?.take(MAX_TEXT_BYTES).read_to_end(&mut data)?;
+    let text = String::from_utf8_lossy(&data);
+
+    let mut chapters = Vec::new();
+    let mut section = String::new();
+    for line in text.lines() {
+        section.push_str(line);
+        section.push('\n');
+        if section.len() >= TXT_SECTION_BYTES {
+            chapters.push(std::mem::take(&mut section));
+        }
+    }
+    chapters.push(section);
+
+    Ok(chapters
+        .into_iter()
+        .map(|section| section.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|text| !text.is_empty())
+        .map(|text| Chapter { location: None, heading: None, text })
+        .collect())
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, name: &str) -> io::Result<String> {
+    let entry = archive.by_name(name).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    let mut data = Vec::new();
+    entry.take(MAX_TEXT_BYTES).read_to_end(&mut data)?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Attributes of every element named `name`, keyed by local name so namespace prefixes such as `opf:` don't matter
+fn xml_elements(xml: &str, name: &str) -> Vec<HashMap<String, String>> {
+    let mut reader = Reader::from_str(xml);
+    let mut elements = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == name => {
+                let attrs = e.attributes()
+                    .flatten()
+                    .filter_map(|attr| {
+                        let key = attr.key.local_name().as_ref().to_string();
+                        let value = attr.normalized_value(XmlVersion::Implicit1_0).ok()?.into_owned();
+                        Some((key, value))
+                    })
+                    .collect();
+                elements.push(attrs);
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    elements
+}
+
+/// Resolve a manifest href, relative to the package document's folder, to an archive path
+fn resolve_href(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or("");
+    let mut parts: Vec<String> = base.split('/').filter(|p| !p.is_empty()).map(str::to_string).collect();
+    for part in percent_decode(href).split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part.to_string()),
+        }
+    }
+    parts.join("/")
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = value.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Extract the readable text of an HTML or XHTML document.
+///
+/// Tags are dropped rather than parsed, so malformed markup still yields its text.
+pub fn html_to_text(html: &str) -> HtmlText {
+    let mut result = HtmlText::default();
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut hidden: Option<String> = None;
+    let mut title: Option<String> = None;
+    let mut heading: Option<(String, String)> = None;
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        let Some(start) = rest.find('<') else {
+            push_text(rest, &mut text, &mut title, &mut heading, hidden.is_some());
+            break;
+        };
+        push_text(&rest[..start], &mut text, &mut title, &mut heading, hidden.is_some());
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map(|end| &comment[end + 3..]).unwrap_or("");
+            continue;
+        }
+
+        let end = rest.find('>').map(|end| end + 1).unwrap_or(rest.len());
+        let tag = &rest[1..end.saturating_sub(1).max(1)];
+        rest = &rest[end..];
+
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let name = name.rsplit(':').next().unwrap_or("").to_string();
+
+        // The title sits in the hidden head but is still worth keeping as a chapter name
+        if name == "title" {
+            if !closing {
+                title = Some(String::new());
+            } else if let Some(t) = title.take() {
+                result.title.get_or_insert_with(|| collapse_whitespace(&t));
+            }
+            continue;
+        }
+
+        if let Some(open) = &hidden {
+            if closing && *open == name {
+                hidden = None;
+            }
+            continue;
+        }
+        if HIDDEN_ELEMENTS.contains(&name.as_str()) && !closing && !tag.ends_with('/') {
+            hidden = Some(name);
+            continue;
+        }
+
+        if matches!(name.as_str(), "h1" | "h2" | "h3") && result.heading.is_none() {
+            if !closing && heading.is_none() {
+                heading = Some((name.clone(), String::new()));
+            } else if closing && heading.as_ref().is_some_and(|(open, _)| *open == name) {
+                let (_, h) = heading.take().unwrap();
+                let h = collapse_whitespace(&h);
+                if !h.is_empty() {
+                    result.heading = Some(h);
+                }
+            }
+        }
+
+        if BLOCK_ELEMENTS.contains(&name.as_str()) {
+            text.push(' ');
+        }
+    }
+
+    result.text = collapse_whitespace(&text);
+    result
+}
+
+fn push_text(
+    raw: &str,
+    text: &mut String,
+    title: &mut Option<String>,
+    heading: &mut Option<(String, String)>,
+    hidden: bool,
+) {
+    if raw.is_empty() {
+        return;
+    }
+    let decoded = decode_entities(raw);
+    if let Some(title) = title {
+        title.push_str(&decoded);
+    }
+    if hidden {
+        return;
+    }
+    if let Some((_, heading)) = heading {
+        heading.push_str(&decoded);
+    }
+    text.push_str(&decoded);
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Decode character references and the named entities common in e-books
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..].find(';').filter(|end| *end <= 10).map(|end| &rest[1..end + 1]);
+        let value = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            "shy" => Some('\u{ad}'),
+            "ndash" => Some('–'),
+            "mdash" => Some('—'),
+            "hellip" => Some('…'),
+            "lsquo" => Some('‘'),
+            "rsquo" => Some('’'),
+            "ldquo" => Some('“'),
+            "rdquo" => Some('”'),
+            "laquo" => Some('«'),
+            "raquo" => Some('»'),
+            _ => {
+                let code = entity.strip_prefix('#')?;
+                let code = match code.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => code.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+
+        match (entity, value) {
+            (Some(entity), Some(value)) => {
+                decoded.push(value);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Progress of indexing one library's book contents
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ContentIndexProgress {
+    pub library_id: String,
+    pub running: bool,
+    /// Books with an EPUB or TXT file
+    pub total_books: usize,
+    /// Books whose current file is indexed, including those that failed
+    pub indexed_books: usize,
+    /// Books whose file could not be read
+    pub failed_books: usize,
+    pub current_book: Option<i32>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Why the last run stopped early, if it did
+    pub error: Option<String>,
+}
+
+/// Background content indexing runs, at most one per library.
+///
+/// Each book is committed as soon as it is indexed, together with the size and
+/// modification time of its file, so an interrupted run resumes where it stopped
+/// and later runs only index new or changed files.
+#[derive(Default)]
+pub struct ContentIndexJobs {
+    jobs: Mutex<HashMap<String, Arc<Mutex<ContentIndexProgress>>>>,
+}
+
+impl ContentIndexJobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start indexing every library that has a search index
+    pub fn start_all(&self, cache: &LibraryCache) {
+        for library in cache.get_libraries() {
+            if let (Some(db), Some(index)) = (cache.get_database(&library.id), cache.get_search_index(&library.id)) {
+                self.start(&library.id, db.clone(), index);
+            }
+        }
+    }
+
+    /// Index a library's book contents in the background.
+    /// Returns `false` when a run for this library is already in progress.
+    pub fn start(&self, library_id: &str, db: CalibreDb, index: Arc<SearchIndex>) -> bool {
+        let progress = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let progress = jobs.entry(library_id.to_string()).or_default().clone();
+            let mut state = progress.lock().unwrap();
+            if state.running {
+                return false;
+            }
+            *state = ContentIndexProgress {
+                library_id: library_id.to_string(),
+                running: true,
+                started_at: Some(Utc::now()),
+                ..Default::default()
+            };
+            drop(state);
+            progress
+        };
+
+        let library_id = library_id.to_string();
+        std::thread::spawn(move || {
+            let result = index_contents(&db, &index, &progress);
+            let mut state = progress.lock().unwrap();
+            state.running = false;
+            state.current_book = None;
+            state.finished_at = Some(Utc::now());
+            match result {
+                Ok(()) => info!(
+                    "Indexed contents of {} book(s) in library {} ({} failed)",
+                    state.indexed_books, library_id, state.failed_books
+                ),
+                Err(e) => {
+                    error!("Content indexing of library {} stopped: {}", library_id, e);
+                    state.error = Some(e.to_string());
+                }
+            }
+        });
+        true
+    }
+
+    pub fn progress(&self) -> Vec<ContentIndexProgress> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut progress: Vec<_> = jobs.values().map(|p| p.lock().unwrap().clone()).collect();
+        progress.sort_by(|a, b| a.library_id.cmp(&b.library_id));
+        progress
+    }
+
+    pub fn library_progress(&self, library_id: &str) -> Option<ContentIndexProgress> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(library_id).map(|p| p.lock().unwrap().clone())
+    }
+}
+
+/// Size and modification time of a file, identifying the version that was indexed
+fn file_stamp(format: &str, path: &Path) -> Option<String> {
+    let meta = fs::metadata(path).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    Some(format!("{}:{}:{}", format, mtime, meta.len()))
+}
+
+fn index_contents(db: &CalibreDb, index: &SearchIndex, progress: &Mutex<ContentIndexProgress>) -> rusqlite::Result<()> {
+    let indexed = index.content_stamps()?;
+
+    let mut pending = Vec::new();
+    let mut present = HashSet::new();
+    let (mut up_to_date, mut failed) = (0, 0);
+    for book_id in db.book_ids()? {
+        let Some(files) = db.get_book_files(book_id)? else { continue };
+        let Some((format, path, stamp)) = INDEXABLE_FORMATS.iter().find_map(|wanted| {
+            let (format, path) = files.formats.iter().find(|(format, _)| format == wanted)?;
+            Some((format.clone(), path.clone(), file_stamp(format, path)?))
+        }) else {
+            continue;
+        };
+
+        present.insert(book_id);
+        match indexed.get(&book_id) {
+            Some((indexed_stamp, ok)) if *indexed_stamp == stamp => {
+                up_to_date += 1;
+                if !ok {
+                    failed += 1;
+                }
+            }
+            _ => pending.push((book_id, format, path, stamp)),
+        }
+    }
+
+    // Forget books deleted from the library or left without an indexable file
+    for book_id in indexed.keys().filter(|id| !present.contains(id)) {
+        index.remove_content(*book_id)?;
+    }
+
+    {
+        let mut state = progress.lock().unwrap();
+        state.total_books = present.len();
+        state.indexed_books = up_to_date;
+        state.failed_books = failed;
+    }
+
+    for (book_id, format, path, stamp) in pending {
+        progress.lock().unwrap().current_book = Some(book_id);
+        let failure = match extract_chapters(&path, &format) {
+            Ok(chapters) => {
+                index.store_content(book_id, &stamp, &chapters, None)?;
+                false
+            }
+            Err(e) => {
+                warn!("Failed to extract text of book {} from {:?}: {}", book_id, path, e);
+                index.store_content(book_id, &stamp, &[], Some(&e.to_string()))?;
+                true
+            }
+        };
+
+        let mut state = progress.lock().unwrap();
+        state.indexed_books += 1;
+        if failure {
+            state.failed_books += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    #[test]
+    fn test_epub_chapters() {
+        let dir = std::env::temp_dir().join(format!("biblio-content-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("book.epub");
+
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let files = [
+            ("mimetype", "application/epub+zip"),
+            ("META-INF/container.xml", r#"<?xml version="1.0"?>
+                <container xmlns="urn:oasis:names:tc:opendocument:xmlns:container" version="1.0">
+                  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+                </container>"#),
+            ("OEBPS/content.opf", r#"<?xml version="1.0"?>
+                <opf:package xmlns:opf="http://www.idpf.org/2007/opf" version="2.0">
+                  <opf:manifest>
+                    <opf:item id="c2" href="Text/chapter%202.xhtml" media-type="application/xhtml+xml"/>
+                    <opf:item id="c1" href="Text/chapter1.xhtml" media-type="application/xhtml+xml"/>
+                    <opf:item id="css" href="style.css" media-type="text/css"/>
+                  </opf:manifest>
+                  <opf:spine><opf:itemref idref="c1"/><opf:itemref idref="css"/><opf:itemref idref="c2"/></opf:spine>
+                </opf:package>"#),
+            ("OEBPS/Text/chapter1.xhtml", r#"<html><head><title>One</title><style>p { color: red }</style></head>
+                <body><h1>Chapter <i>1</i></h1><p>It was a dark&nbsp;and stormy night;</p><p>the rain fell in torrents&#8212;</p></body></html>"#),
+            ("OEBPS/Text/chapter 2.xhtml", "<html><head><title>Two</title></head><body><!-- note --><p>Caf&#233; &amp; <b>cr</b>oissants</p></body></html>"),
+        ];
+        for (name, content) in files {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let chapters = extract_chapters(&path, "EPUB").unwrap();
+        assert_eq!(chapters, vec![
+            Chapter {
+                location: Some("OEBPS/Text/chapter1.xhtml".to_string()),
+                heading: Some("Chapter 1".to_string()),
+                text: "Chapter 1 It was a dark and stormy night; the rain fell in torrents—".to_string(),
+            },
+            Chapter {
+                location: Some("OEBPS/Text/chapter 2.xhtml".to_string()),
+                heading: Some("Two".to_string()),
+                text: "Café & croissants".to_string(),
+            },
+        ]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,9 @@
-use rusqlite::{Connection, Result as SqlResult, OptionalExtension, OpenFlags};
-use std::path::Path;
+use rusqlite::{Connection, Result as SqlResult, params_from_iter};
+use rusqlite::types::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use crate::pool::ConnectionPool;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,6 +23,13 @@ pub struct Book {
     pub has_cover: bool,
     pub formats: Vec<String>,
     pub sort: Option<String>,
+    pub last_modified: Option<String>,
+    pub uuid: Option<String>,
+    pub isbn: Option<String>,
+    /// Calibre identifiers by type, e.g. `isbn`, `goodreads`, `amazon`, `doi`
+    pub identifiers: BTreeMap<String, String>,
+    /// Custom column values by column label; columns without a value are omitted
+    pub custom_columns: BTreeMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,152 +55,768 @@ pub struct Series {
     pub book_count: i32,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Language {
+    pub id: i32,
+    /// ISO 639 code as stored by Calibre, e.g. `eng`
+    pub lang_code: String,
+    pub book_count: i32,
+}
+
+/// A book format with the number of books available in it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FormatCount {
+    pub format: String,
+    pub book_count: i32,
+}
+
+/// A user-defined Calibre column, stored in `custom_column_N` tables
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomColumn {
+    pub id: i32,
+    pub label: String,
+    pub name: String,
+    /// Calibre datatype: `text`, `comments`, `series`, `enumeration`, `int`, `float`,
+    /// `bool`, `datetime` or `rating`
+    pub datatype: String,
+    pub is_multiple: bool,
+    /// Values live in `custom_column_N` and are linked through `books_custom_column_N_link`
+    #[serde(skip)]
+    pub normalized: bool,
+}
+
+/// One distinct value of a custom column with the number of books carrying it
+#[derive(Debug, Clone, Serialize)]
+pub struct CustomColumnValue {
+    pub value: serde_json::Value,
+    pub book_count: i32,
+}
+
+/// Columns the books list can be ordered by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookSort {
+    #[default]
+    Timestamp,
+    Title,
+    AuthorSort,
+    Pubdate,
+    Rating,
+    Series,
+    /// Order of `BookQuery::book_ids`, i.e. search ranking; timestamp without them
+    Relevance,
+}
+
+impl BookSort {
+    /// Dates and ratings read best newest/highest first, names alphabetically
+    pub fn default_descending(&self) -> bool {
+        matches!(self, BookSort::Timestamp | BookSort::Pubdate | BookSort::Rating)
+    }
+}
+
+/// Filters, ordering and pagination for listing books.
+/// Within one filter list a book matches any of the values; separate filters must all match.
+#[derive(Debug, Clone, Default)]
+pub struct BookQuery {
+    pub search: Option<String>,
+    /// Restrict the list to these books, e.g. full-text search hits in ranked order
+    pub book_ids: Option<Vec<i32>>,
+    pub formats: Vec<String>,
+    /// Language codes as stored by Calibre, e.g. `eng`
+    pub languages: Vec<String>,
+    pub author_ids: Vec<i32>,
+    pub tag_ids: Vec<i32>,
+    pub series_ids: Vec<i32>,
+    /// Custom column filters as (column label, accepted values)
+    pub custom: Vec<(String, Vec<String>)>,
+    pub sort: BookSort,
+    pub descending: bool,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// One page of books along with the number of books matching the query
+#[derive(Debug, Clone, Serialize)]
+pub struct BookPage {
+    pub books: Vec<Book>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Books a user may see, set per user. Tags are compared case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContentRestrictions {
+    /// When not empty, only books carrying at least one of these tags are visible
+    pub allowed_tags: Vec<String>,
+    /// Books carrying any of these tags are hidden
+    pub denied_tags: Vec<String>,
+    /// Books rated below this many stars are hidden; unrated books stay visible
+    pub min_rating: Option<u8>,
+}
+
+impl ContentRestrictions {
+    pub fn is_empty(&self) -> bool {
+        self.allowed_tags.is_empty() && self.denied_tags.is_empty() && self.min_rating.unwrap_or(0) == 0
+    }
+
+    /// SQL condition selecting the visible books of `books b`, with its parameters
+    fn condition(&self) -> Option<(String, Vec<Value>)> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        let tagged = |n: usize| format!(
+            "EXISTS (SELECT 1 FROM books_tags_link rbtl INNER JOIN tags rt ON rt.id = rbtl.tag
+                     WHERE rbtl.book = b.id AND rt.name COLLATE NOCASE IN ({}))",
+            vec!["?"; n].join(", ")
+        );
+        if !self.allowed_tags.is_empty() {
+            conditions.push(tagged(self.allowed_tags.len()));
+            values.extend(self.allowed_tags.iter().map(|t| Value::Text(t.clone())));
+        }
+        if !self.denied_tags.is_empty() {
+            conditions.push(format!("NOT {}", tagged(self.denied_tags.len())));
+            values.extend(self.denied_tags.iter().map(|t| Value::Text(t.clone())));
+        }
+        if let Some(stars) = self.min_rating.filter(|stars| *stars > 0) {
+            // Calibre stores ratings as half-stars, 0 meaning unrated
+            conditions.push(
+                "NOT EXISTS (SELECT 1 FROM books_ratings_link rbrl INNER JOIN ratings rr ON rr.id = rbrl.rating
+                             WHERE rbrl.book = b.id AND rr.rating > 0 AND rr.rating < ?)".to_string(),
+            );
+            values.push(Value::Integer(stars as i64 * 2));
+        }
+        Some((conditions.join(" AND "), values))
+    }
+}
+
+/// Where a book's files live on disk
+#[derive(Debug, Clone)]
+pub struct BookFiles {
+    /// `cover.jpg` in the book folder `{library}/{books.path}`, when Calibre recorded a cover
+    pub cover: Option<PathBuf>,
+    /// Upper-case format name and the file holding it, `{data.name}.{format}`
+    pub formats: Vec<(String, PathBuf)>,
+    /// `books.last_modified`, bumped by Calibre whenever the book is edited
+    pub last_modified: Option<String>,
+}
+
+/// Read-only access to one Calibre library's metadata.db.
+///
+/// Cloning is cheap and clones share the connection pool, so handlers can take a
+/// handle out of the library cache and query it without holding the cache lock.
+#[derive(Clone)]
 pub struct CalibreDb {
-    conn: Connection,
+    pool: Arc<ConnectionPool>,
+    /// Library folder containing metadata.db, which `books.path` is relative to
+    root: Arc<Path>,
+    custom_columns: Arc<[CustomColumn]>,
+    /// Hides books from the listings, facets, book details and files; see `restricted`
+    restrictions: Arc<ContentRestrictions>,
 }
 
 impl CalibreDb {
     pub fn open<P: AsRef<Path>>(path: P) -> SqlResult<Self> {
-        let conn = Connection::open_with_flags(
-            path,
-            OpenFlags::SQLITE_OPEN_READ_ONLY,
-        )?;
-        Ok(CalibreDb { conn })
+        let root = path.as_ref().parent().unwrap_or(Path::new("")).into();
+        let pool = ConnectionPool::open(path)?;
+        let custom_columns = Self::discover_custom_columns(&*pool.get()?)?.into();
+        Ok(CalibreDb { pool: Arc::new(pool), root, custom_columns, restrictions: Arc::default() })
+    }
+
+    /// A handle on the same library that only shows the books `restrictions` allow
+    /// in `count_books`, `get_books_page`, `get_book`, `find_books_by_identifier`,
+    /// the facet lists, `get_book_files` and `get_book_formats`. `book_ids`, `book_stamps` and
+    /// `load_books` keep seeing every book, as indexing relies on them.
+    pub fn restricted(&self, restrictions: ContentRestrictions) -> CalibreDb {
+        CalibreDb { restrictions: Arc::new(restrictions), ..self.clone() }
+    }
+
+    /// ` <keyword> <book column> IN (visible books)` for restricted handles, with its
+    /// parameters, where `keyword` is `WHERE` or `AND`
+    fn visible_filter(&self, keyword: &str, book_column: &str) -> (String, Vec<Value>) {
+        match self.restrictions.condition() {
+            Some((condition, values)) => (
+                format!(" {} {} IN (SELECT b.id FROM books b WHERE {})", keyword, book_column, condition),
+                values,
+            ),
+            None => (String::new(), Vec::new()),
+        }
+    }
+
+    /// Facet rows left without visible books are dropped on restricted handles,
+    /// so that they do not give hidden books away
+    fn facet_having(&self, count: &str) -> String {
+        if self.restrictions.is_empty() {
+            String::new()
+        } else {
+            format!("HAVING {} > 0", count)
+        }
+    }
+
+    fn is_visible(&self, conn: &Connection, book_id: i32) -> SqlResult<bool> {
+        let (filter, mut values) = self.visible_filter("AND", "id");
+        if filter.is_empty() {
+            return Ok(true);
+        }
+        values.insert(0, Value::Integer(book_id as i64));
+        conn.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM books WHERE id = ?{})", filter),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )
     }
 
-    pub fn get_all_books(&self) -> SqlResult<Vec<Book>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, title, has_cover, sort FROM books ORDER BY timestamp DESC LIMIT 10000"
+    /// Ids of the books hidden by the restrictions, to filter search index hits with
+    pub fn hidden_book_ids(&self) -> SqlResult<HashSet<i32>> {
+        let Some((condition, values)) = self.restrictions.condition() else {
+            return Ok(HashSet::new());
+        };
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(&format!("SELECT b.id FROM books b WHERE NOT ({})", condition))?;
+        let ids = stmt.query_map(params_from_iter(values.iter()), |row| row.get(0))?
+            .collect::<SqlResult<HashSet<_>>>()?;
+        Ok(ids)
+    }
+
+    /// Read the custom column definitions. Composite columns are computed by
+    /// Calibre itself and have no stored values, so they are left out.
+    fn discover_custom_columns(conn: &Connection) -> SqlResult<Vec<CustomColumn>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, label, name, datatype, is_multiple, normalized FROM custom_columns
+             WHERE NOT mark_for_delete AND datatype != 'composite'
+             ORDER BY label"
         )?;
-        
-        let books = stmt.query_map([], |row| {
-            let book_id: i32 = row.get(0)?;
-            let title: String = row.get(1)?;
-            let has_cover: bool = row.get(2)?;
-            let sort: Option<String> = row.get(3).ok();
-            
-            let authors = self.get_book_authors(book_id).unwrap_or_default();
-            let (series, series_index) = self.get_book_series(book_id).unwrap_or_default();
-            let tags = self.get_book_tags(book_id).unwrap_or_default();
-            let formats = self.get_book_formats(book_id).unwrap_or_default();
-            let comments = self.get_book_comments(book_id).ok().flatten();
-            
-            Ok(Book {
-                id: book_id,
-                title,
-                authors,
-                series,
-                series_index,
-                tags,
-                comments,
-                publisher: None,
-                pubdate: None,
-                rating: None,
-                timestamp: None,
-                language: None,
-                has_cover,
-                formats,
-                sort,
+
+        let columns = stmt.query_map([], |row| {
+            Ok(CustomColumn {
+                id: row.get(0)?,
+                label: row.get(1)?,
+                name: row.get(2)?,
+                datatype: row.get(3)?,
+                is_multiple: row.get::<_, Option<bool>>(4)?.unwrap_or(false),
+                normalized: row.get(5)?,
             })
         })?.collect::<SqlResult<Vec<_>>>()?;
 
-        Ok(books)
+        Ok(columns)
+    }
+
+    pub fn custom_columns(&self) -> &[CustomColumn] {
+        &self.custom_columns
+    }
+
+    /// Find a custom column by label, with or without Calibre's `#` prefix
+    pub fn custom_column(&self, label: &str) -> Option<&CustomColumn> {
+        let label = label.trim_start_matches('#');
+        self.custom_columns.iter().find(|c| c.label.eq_ignore_ascii_case(label))
+    }
+
+    pub fn count_books(&self) -> SqlResult<usize> {
+        let (filter, values) = self.visible_filter("WHERE", "id");
+        let count: i64 = self.pool.get()?.query_row(
+            &format!("SELECT COUNT(*) FROM books{}", filter),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Ids of every book in the library
+    pub fn book_ids(&self) -> SqlResult<Vec<i32>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT id FROM books ORDER BY id")?;
+        let ids = stmt.query_map([], |row| row.get(0))?.collect::<SqlResult<Vec<_>>>()?;
+        Ok(ids)
+    }
+
+    /// `last_modified` of every book, to tell which books changed between two reads
+    pub fn book_stamps(&self) -> SqlResult<HashMap<i32, Option<String>>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT id, last_modified FROM books")?;
+        let stamps = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<SqlResult<HashMap<_, _>>>()?;
+        Ok(stamps)
+    }
+
+    /// List books matching `query`, filtered, sorted and paginated in SQL
+    pub fn get_books_page(&self, query: &BookQuery) -> SqlResult<BookPage> {
+        let conn = self.pool.get()?;
+        let mut conditions: Vec<String> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+
+        let placeholders = |n: usize| vec!["?"; n].join(", ");
+
+        if let Some(search) = query.search.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+            let pattern = format!(
+                "%{}%",
+                search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+            );
+            conditions.push(
+                "(b.title LIKE ? ESCAPE '\\' OR EXISTS (
+                    SELECT 1 FROM books_authors_link bal
+                    INNER JOIN authors a ON a.id = bal.author
+                    WHERE bal.book = b.id AND a.name LIKE ? ESCAPE '\\'))".to_string(),
+            );
+            values.push(Value::Text(pattern.clone()));
+            values.push(Value::Text(pattern));
+        }
+
+        if let Some((condition, restriction_values)) = self.restrictions.condition() {
+            conditions.push(condition);
+            values.extend(restriction_values);
+        }
+
+        if let Some(ids) = &query.book_ids {
+            conditions.push("b.id IN (SELECT value FROM json_each(?))".to_string());
+            values.push(Value::Text(serde_json::to_string(ids).unwrap_or_else(|_| "[]".to_string())));
+        }
+
+        if !query.formats.is_empty() {
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM data d WHERE d.book = b.id AND UPPER(d.format) IN ({}))",
+                placeholders(query.formats.len())
+            ));
+            values.extend(query.formats.iter().map(|f| Value::Text(f.to_uppercase())));
+        }
+
+        if !query.languages.is_empty() {
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM books_languages_link bll
+                         INNER JOIN languages l ON l.id = bll.lang_code
+                         WHERE bll.book = b.id AND l.lang_code IN ({}))",
+                placeholders(query.languages.len())
+            ));
+            values.extend(query.languages.iter().map(|l| Value::Text(l.to_lowercase())));
+        }
+
+        for (ids, table, column) in [
+            (&query.author_ids, "books_authors_link", "author"),
+            (&query.tag_ids, "books_tags_link", "tag"),
+            (&query.series_ids, "books_series_link", "series"),
+        ] {
+            if !ids.is_empty() {
+                conditions.push(format!(
+                    "EXISTS (SELECT 1 FROM {table} l WHERE l.book = b.id AND l.{column} IN ({}))",
+                    placeholders(ids.len())
+                ));
+                values.extend(ids.iter().map(|id| Value::Integer(*id as i64)));
+            }
+        }
+
+        for (label, accepted) in &query.custom {
+            let column = self.custom_column(label)
+                .ok_or_else(|| rusqlite::Error::InvalidParameterName(label.clone()))?;
+            if accepted.is_empty() {
+                continue;
+            }
+
+            // Dates match on their day, text case-insensitively
+            let expr = match column.datatype.as_str() {
+                "datetime" => "substr(v.value, 1, 10)",
+                "text" | "comments" | "series" | "enumeration" => "v.value COLLATE NOCASE",
+                _ => "v.value",
+            };
+            let source = if column.normalized {
+                format!(
+                    "books_custom_column_{id}_link l INNER JOIN custom_column_{id} v ON v.id = l.value
+                     WHERE l.book = b.id",
+                    id = column.id
+                )
+            } else {
+                format!("custom_column_{} v WHERE v.book = b.id", column.id)
+            };
+            conditions.push(format!(
+                "EXISTS (SELECT 1 FROM {} AND {} IN ({}))",
+                source, expr, placeholders(accepted.len())
+            ));
+            values.extend(accepted.iter().map(|value| {
+                if column.datatype == "bool" {
+                    match value.to_lowercase().as_str() {
+                        "true" | "yes" | "1" => Value::Integer(1),
+                        "false" | "no" | "0" => Value::Integer(0),
+                        _ => Value::Text(value.clone()),
+                    }
+                } else {
+                    Value::Text(value.clone())
+                }
+            }));
+        }
+
+        let where_clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        // Ranked lists keep their own order, so filter in SQL and paginate here
+        if let (BookSort::Relevance, Some(ranked)) = (query.sort, &query.book_ids) {
+            let mut stmt = conn.prepare(&format!("SELECT b.id FROM books b {}", where_clause))?;
+            let matching = stmt.query_map(params_from_iter(values.iter()), |row| row.get::<_, i32>(0))?
+                .collect::<SqlResult<HashSet<_>>>()?;
+            let ids: Vec<i32> = ranked.iter()
+                .filter(|id| matching.contains(id))
+                .skip(query.offset)
+                .take(query.limit)
+                .copied()
+                .collect();
+            return Ok(BookPage {
+                books: self.load_books_with(&conn, &ids)?,
+                total: matching.len(),
+                offset: query.offset,
+                limit: query.limit,
+            });
+        }
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM books b {}", where_clause),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+
+        let direction = if query.descending { "DESC" } else { "ASC" };
+        let (sort_expr, collation, tie_breaker) = match query.sort {
+            BookSort::Timestamp | BookSort::Relevance => ("b.timestamp", "", ""),
+            BookSort::Title => ("b.sort", " COLLATE NOCASE", ""),
+            BookSort::AuthorSort => ("b.author_sort", " COLLATE NOCASE", ""),
+            BookSort::Pubdate => ("b.pubdate", "", ""),
+            BookSort::Rating => (
+                "(SELECT r.rating FROM books_ratings_link brl
+                  INNER JOIN ratings r ON r.id = brl.rating
+                  WHERE brl.book = b.id)",
+                "",
+                "",
+            ),
+            BookSort::Series => (
+                "(SELECT s.sort FROM books_series_link bsl
+                  INNER JOIN series s ON s.id = bsl.series
+                  WHERE bsl.book = b.id)",
+                " COLLATE NOCASE",
+                "b.series_index ASC, ",
+            ),
+        };
+
+        // Books lacking the sort value always go last
+        let order_clause = format!(
+            "ORDER BY {sort_expr} IS NULL, {sort_expr}{collation} {direction}, {tie_breaker}b.id {direction}"
+        );
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT b.id FROM books b {} {} LIMIT ? OFFSET ?",
+            where_clause, order_clause
+        ))?;
+        values.push(Value::Integer(query.limit as i64));
+        values.push(Value::Integer(query.offset as i64));
+
+        let ids = stmt.query_map(params_from_iter(values.iter()), |row| row.get::<_, i32>(0))?
+            .collect::<SqlResult<Vec<_>>>()?;
+
+        Ok(BookPage {
+            books: self.load_books_with(&conn, &ids)?,
+            total: total as usize,
+            offset: query.offset,
+            limit: query.limit,
+        })
     }
 
     pub fn get_book(&self, book_id: i32) -> SqlResult<Option<Book>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT id, title, has_cover, sort FROM books WHERE id = ?"
+        let conn = self.pool.get()?;
+        if !self.is_visible(&conn, book_id)? {
+            return Ok(None);
+        }
+        Ok(self.load_books_with(&conn, &[book_id])?.pop())
+    }
+
+    /// Load complete `Book` values for the given ids, returned in the same order.
+    ///
+    /// Relations are fetched with one set-based query per link table instead of
+    /// one query per book, and assembled in memory. Unknown ids are skipped.
+    pub fn load_books(&self, book_ids: &[i32]) -> SqlResult<Vec<Book>> {
+        self.load_books_with(&*self.pool.get()?, book_ids)
+    }
+
+    fn load_books_with(&self, conn: &Connection, book_ids: &[i32]) -> SqlResult<Vec<Book>> {
+        if book_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // The ids are passed as a single JSON array to stay clear of SQLite's variable limit
+        let ids_json = serde_json::to_string(book_ids).unwrap_or_else(|_| "[]".to_string());
+        let mut books: HashMap<i32, Book> = HashMap::with_capacity(book_ids.len());
+
+        // Single-valued relations are resolved with subqueries, like Calibre's `meta` view
+        let mut stmt = conn.prepare_cached(
+            "SELECT b.id, b.title, b.has_cover, b.sort, b.series_index,
+                    b.pubdate, b.timestamp, b.last_modified, b.uuid,
+                    (SELECT p.name FROM books_publishers_link bpl
+                     INNER JOIN publishers p ON p.id = bpl.publisher
+                     WHERE bpl.book = b.id LIMIT 1),
+                    (SELECT r.rating FROM books_ratings_link brl
+                     INNER JOIN ratings r ON r.id = brl.rating
+                     WHERE brl.book = b.id LIMIT 1),
+                    (SELECT l.lang_code FROM books_languages_link bll
+                     INNER JOIN languages l ON l.id = bll.lang_code
+                     WHERE bll.book = b.id ORDER BY bll.item_order LIMIT 1),
+                    COALESCE((SELECT i.val FROM identifiers i
+                              WHERE i.book = b.id AND i.type = 'isbn' LIMIT 1),
+                             NULLIF(b.isbn, ''))
+             FROM books b
+             WHERE b.id IN (SELECT value FROM json_each(?1))"
         )?;
-        
-        let book = stmt.query_row([book_id], |row| {
+        let mut rows = stmt.query([&ids_json])?;
+        while let Some(row) = rows.next()? {
             let id: i32 = row.get(0)?;
-            let title: String = row.get(1)?;
-            let has_cover: bool = row.get(2)?;
-            let sort: Option<String> = row.get(3).ok();
-            
-            let authors = self.get_book_authors(id).unwrap_or_default();
-            let (series, series_index) = self.get_book_series(id).unwrap_or_default();
-            let tags = self.get_book_tags(id).unwrap_or_default();
-            let formats = self.get_book_formats(id).unwrap_or_default();
-            let comments = self.get_book_comments(id).ok().flatten();
-            
-            Ok(Book {
+            books.insert(id, Book {
                 id,
-                title,
-                authors,
-                series,
-                series_index,
-                tags,
-                comments,
-                publisher: None,
-                pubdate: None,
-                rating: None,
-                timestamp: None,
-                language: None,
-                has_cover,
-                formats,
-                sort,
-            })
-        }).optional()?;
+                title: row.get(1)?,
+                authors: Vec::new(),
+                series: None,
+                series_index: row.get(4)?,
+                tags: Vec::new(),
+                comments: None,
+                publisher: row.get(9)?,
+                pubdate: calibre_date(row.get(5)?),
+                rating: row.get::<_, Option<i32>>(10)?.filter(|r| *r > 0),
+                timestamp: calibre_date(row.get(6)?),
+                language: row.get(11)?,
+                has_cover: row.get::<_, Option<bool>>(2)?.unwrap_or(false),
+                formats: Vec::new(),
+                sort: row.get(3)?,
+                last_modified: calibre_date(row.get(7)?),
+                uuid: row.get(8)?,
+                isbn: row.get(12)?,
+                identifiers: BTreeMap::new(),
+                custom_columns: BTreeMap::new(),
+            });
+        }
 
-        Ok(book)
-    }
+        Self::for_each_related(
+            conn,
+            "SELECT bal.book, a.name FROM books_authors_link bal
+             INNER JOIN authors a ON a.id = bal.author
+             WHERE bal.book IN (SELECT value FROM json_each(?1))
+             ORDER BY bal.id",
+            &ids_json,
+            |book_id, name| {
+                if let Some(book) = books.get_mut(&book_id) {
+                    book.authors.push(name);
+                }
+            },
+        )?;
 
-    pub fn get_book_authors(&self, book_id: i32) -> SqlResult<Vec<String>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT a.name FROM authors a 
-             INNER JOIN books_authors_link bal ON a.id = bal.author 
-             WHERE bal.book = ?"
+        Self::for_each_related(
+            conn,
+            "SELECT btl.book, t.name FROM books_tags_link btl
+             INNER JOIN tags t ON t.id = btl.tag
+             WHERE btl.book IN (SELECT value FROM json_each(?1))
+             ORDER BY btl.id",
+            &ids_json,
+            |book_id, name| {
+                if let Some(book) = books.get_mut(&book_id) {
+                    book.tags.push(name);
+                }
+            },
         )?;
-        
-        let authors = stmt.query_map([book_id], |row| {
-            row.get::<_, String>(0)
-        })?.collect::<SqlResult<Vec<_>>>()?;
 
-        Ok(authors)
-    }
+        Self::for_each_related(
+            conn,
+            "SELECT bsl.book, s.name FROM books_series_link bsl
+             INNER JOIN series s ON s.id = bsl.series
+             WHERE bsl.book IN (SELECT value FROM json_each(?1))",
+            &ids_json,
+            |book_id, name| {
+                if let Some(book) = books.get_mut(&book_id) {
+                    book.series = Some(name);
+                }
+            },
+        )?;
 
-    pub fn get_book_tags(&self, book_id: i32) -> SqlResult<Vec<String>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT t.name FROM tags t 
-             INNER JOIN books_tags_link btl ON t.id = btl.tag 
-             WHERE btl.book = ?"
+        Self::for_each_related(
+            conn,
+            "SELECT book, format FROM data
+             WHERE book IN (SELECT value FROM json_each(?1))
+             ORDER BY format",
+            &ids_json,
+            |book_id, format| {
+                if let Some(book) = books.get_mut(&book_id) {
+                    book.formats.push(format);
+                }
+            },
         )?;
-        
-        let tags = stmt.query_map([book_id], |row| {
-            row.get::<_, String>(0)
+
+        Self::for_each_related(
+            conn,
+            "SELECT book, text FROM comments
+             WHERE book IN (SELECT value FROM json_each(?1))",
+            &ids_json,
+            |book_id, text| {
+                if let Some(book) = books.get_mut(&book_id) {
+                    book.comments = Some(text);
+                }
+            },
+        )?;
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT book, type, val FROM identifiers
+             WHERE book IN (SELECT value FROM json_each(?1))"
+        )?;
+        let mut rows = stmt.query([&ids_json])?;
+        while let Some(row) = rows.next()? {
+            if let Some(book) = books.get_mut(&row.get(0)?) {
+                book.identifiers.insert(row.get(1)?, row.get(2)?);
+            }
+        }
+
+        for column in self.custom_columns.iter() {
+            let sql = if column.normalized {
+                format!(
+                    "SELECT l.book, v.value{extra} FROM books_custom_column_{id}_link l
+                     INNER JOIN custom_column_{id} v ON v.id = l.value
+                     WHERE l.book IN (SELECT value FROM json_each(?1))
+                     ORDER BY l.id",
+                    id = column.id,
+                    extra = if column.datatype == "series" { ", l.extra" } else { "" },
+                )
+            } else {
+                format!(
+                    "SELECT book, value FROM custom_column_{}
+                     WHERE book IN (SELECT value FROM json_each(?1))",
+                    column.id
+                )
+            };
+
+            let mut stmt = conn.prepare_cached(&sql)?;
+            let mut rows = stmt.query([&ids_json])?;
+            while let Some(row) = rows.next()? {
+                let Some(book) = books.get_mut(&row.get(0)?) else { continue };
+                let mut value = custom_value(column, row.get(1)?);
+                if column.datatype == "series" {
+                    value = serde_json::json!({ "name": value, "index": row.get::<_, Option<f64>>(2)? });
+                }
+
+                if column.is_multiple {
+                    let entry = book.custom_columns.entry(column.label.clone())
+                        .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+                    if let serde_json::Value::Array(values) = entry {
+                        values.push(value);
+                    }
+                } else {
+                    book.custom_columns.insert(column.label.clone(), value);
+                }
+            }
+        }
+
+        // Only books in a series carry a meaningful index
+        for book in books.values_mut() {
+            if book.series.is_none() {
+                book.series_index = None;
+            }
+        }
+
+        Ok(book_ids.iter().filter_map(|id| books.remove(id)).collect())
+    }
+
+    /// List the distinct values of a custom column with their book counts, like `get_all_tags`.
+    /// Dates are grouped by day.
+    pub fn get_custom_column_values(&self, column: &CustomColumn) -> SqlResult<Vec<CustomColumnValue>> {
+        let conn = self.pool.get()?;
+        let (sql, filter_values) = if column.normalized {
+            let (filter, values) = self.visible_filter("AND", "l.book");
+            (format!(
+                "SELECT v.value, COUNT(l.book) FROM custom_column_{id} v
+                 LEFT JOIN books_custom_column_{id}_link l ON l.value = v.id{filter}
+                 GROUP BY v.id, v.value {having}
+                 ORDER BY v.value COLLATE NOCASE",
+                id = column.id,
+                having = self.facet_having("COUNT(l.book)"),
+            ), values)
+        } else if column.datatype == "datetime" {
+            let (filter, values) = self.visible_filter("WHERE", "book");
+            (format!(
+                "SELECT substr(value, 1, 10) AS day, COUNT(book) FROM custom_column_{}{}
+                 GROUP BY day ORDER BY day",
+                column.id, filter
+            ), values)
+        } else {
+            let (filter, values) = self.visible_filter("WHERE", "book");
+            (format!(
+                "SELECT value, COUNT(book) FROM custom_column_{}{}
+                 GROUP BY value ORDER BY value COLLATE NOCASE",
+                column.id, filter
+            ), values)
+        };
+
+        let mut stmt = conn.prepare(&sql)?;
+        let values = stmt.query_map(params_from_iter(filter_values.iter()), |row| {
+            let value: Value = row.get(0)?;
+            Ok(CustomColumnValue {
+                // Days are already trimmed to `YYYY-MM-DD`
+                value: match (&value, column.datatype.as_str()) {
+                    (Value::Text(day), "datetime") => serde_json::Value::String(day.clone()),
+                    _ => custom_value(column, value),
+                },
+                book_count: row.get(1)?,
+            })
         })?.collect::<SqlResult<Vec<_>>>()?;
 
-        Ok(tags)
+        Ok(values)
     }
 
-    pub fn get_book_series(&self, book_id: i32) -> SqlResult<(Option<String>, Option<f64>)> {
-        let mut stmt = self.conn.prepare(
-            "SELECT s.name, b.series_index FROM series s 
-             INNER JOIN books_series_link bsl ON s.id = bsl.series 
-             INNER JOIN books b ON bsl.book = b.id
-             WHERE bsl.book = ?"
-        )?;
-        
-        let result = stmt.query_row([book_id], |row| {
-            let name: String = row.get(0)?;
-            let index: f64 = row.get(1)?;
-            Ok((Some(name), Some(index)))
-        }).optional()?;
+    /// Find the books carrying an identifier, e.g. (`isbn`, `978-0-00-000000-4`).
+    ///
+    /// Types and values are compared case-insensitively; ISBNs also ignore hyphens and spaces.
+    pub fn find_books_by_identifier(&self, id_type: &str, value: &str) -> SqlResult<Vec<Book>> {
+        let id_type = id_type.trim().to_lowercase();
+        let mut value = value.trim().to_string();
+        let mut column = "val";
+        if id_type == "isbn" {
+            value.retain(|c| c != '-' && c != ' ');
+            column = "REPLACE(REPLACE(val, '-', ''), ' ', '')";
+        }
 
-        Ok(result.unwrap_or((None, None)))
+        let conn = self.pool.get()?;
+        let (filter, filter_values) = self.visible_filter("AND", "book");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT DISTINCT book FROM identifiers
+             WHERE LOWER(type) = ? AND {} = ? COLLATE NOCASE{}
+             ORDER BY book",
+            column, filter
+        ))?;
+        let values = [Value::Text(id_type), Value::Text(value)].into_iter().chain(filter_values);
+        let ids = stmt.query_map(params_from_iter(values), |row| row.get(0))?
+            .collect::<SqlResult<Vec<i32>>>()?;
+
+        self.load_books_with(&conn, &ids)
+    }
+
+    /// Run a `(book id, text)` query over a JSON array of book ids
+    fn for_each_related<F>(conn: &Connection, sql: &str, ids_json: &str, mut f: F) -> SqlResult<()>
+    where
+        F: FnMut(i32, String),
+    {
+        let mut stmt = conn.prepare_cached(sql)?;
+        let mut rows = stmt.query([ids_json])?;
+        while let Some(row) = rows.next()? {
+            f(row.get(0)?, row.get(1)?);
+        }
+        Ok(())
     }
 
     pub fn get_all_authors(&self) -> SqlResult<Vec<Author>> {
-        let mut stmt = self.conn.prepare(
+        let conn = self.pool.get()?;
+        let (filter, values) = self.visible_filter("AND", "ba.book");
+        let mut stmt = conn.prepare(&format!(
             "SELECT a.id, a.name, a.sort, COUNT(ba.book) as book_count 
              FROM authors a 
-             LEFT JOIN books_authors_link ba ON a.id = ba.author 
-             GROUP BY a.id, a.name, a.sort 
-             ORDER BY a.sort"
-        )?;
+             LEFT JOIN books_authors_link ba ON a.id = ba.author{} 
+             GROUP BY a.id, a.name, a.sort {}
+             ORDER BY a.sort",
+            filter, self.facet_having("book_count")
+        ))?;
         
-        let authors = stmt.query_map([], |row| {
+        let authors = stmt.query_map(params_from_iter(values.iter()), |row| {
             Ok(Author {
                 id: row.get(0)?,
                 name: row.get(1)?,
@@ -202,15 +829,18 @@ impl CalibreDb {
     }
 
     pub fn get_all_tags(&self) -> SqlResult<Vec<Tag>> {
-        let mut stmt = self.conn.prepare(
+        let conn = self.pool.get()?;
+        let (filter, values) = self.visible_filter("AND", "btl.book");
+        let mut stmt = conn.prepare(&format!(
             "SELECT t.id, t.name, COUNT(btl.book) as book_count 
              FROM tags t 
-             LEFT JOIN books_tags_link btl ON t.id = btl.tag 
-             GROUP BY t.id, t.name 
-             ORDER BY t.name"
-        )?;
+             LEFT JOIN books_tags_link btl ON t.id = btl.tag{} 
+             GROUP BY t.id, t.name {}
+             ORDER BY t.name",
+            filter, self.facet_having("book_count")
+        ))?;
         
-        let tags = stmt.query_map([], |row| {
+        let tags = stmt.query_map(params_from_iter(values.iter()), |row| {
             Ok(Tag {
                 id: row.get(0)?,
                 name: row.get(1)?,
@@ -222,15 +852,18 @@ impl CalibreDb {
     }
 
     pub fn get_all_series(&self) -> SqlResult<Vec<Series>> {
-        let mut stmt = self.conn.prepare(
+        let conn = self.pool.get()?;
+        let (filter, values) = self.visible_filter("AND", "bs.book");
+        let mut stmt = conn.prepare(&format!(
             "SELECT s.id, s.name, s.sort, COUNT(bs.book) as book_count 
              FROM series s 
-             LEFT JOIN books_series_link bs ON s.id = bs.series 
-             GROUP BY s.id, s.name, s.sort
-             ORDER BY s.sort"
-        )?;
+             LEFT JOIN books_series_link bs ON s.id = bs.series{} 
+             GROUP BY s.id, s.name, s.sort {}
+             ORDER BY s.sort",
+            filter, self.facet_having("book_count")
+        ))?;
         
-        let series = stmt.query_map([], |row| {
+        let series = stmt.query_map(params_from_iter(values.iter()), |row| {
             Ok(Series {
                 id: row.get(0)?,
                 name: row.get(1)?,
@@ -242,8 +875,109 @@ impl CalibreDb {
         Ok(series)
     }
 
+    pub fn get_all_languages(&self) -> SqlResult<Vec<Language>> {
+        let conn = self.pool.get()?;
+        let (filter, values) = self.visible_filter("AND", "bll.book");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT l.id, l.lang_code, COUNT(bll.book) as book_count
+             FROM languages l
+             LEFT JOIN books_languages_link bll ON l.id = bll.lang_code{}
+             GROUP BY l.id, l.lang_code {}
+             ORDER BY l.lang_code",
+            filter, self.facet_having("book_count")
+        ))?;
+
+        let languages = stmt.query_map(params_from_iter(values.iter()), |row| {
+            Ok(Language {
+                id: row.get(0)?,
+                lang_code: row.get(1)?,
+                book_count: row.get(2)?,
+            })
+        })?.collect::<SqlResult<Vec<_>>>()?;
+
+        Ok(languages)
+    }
+
+    pub fn get_all_formats(&self) -> SqlResult<Vec<FormatCount>> {
+        let conn = self.pool.get()?;
+        let (filter, values) = self.visible_filter("WHERE", "book");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT UPPER(format), COUNT(DISTINCT book) as book_count
+             FROM data{}
+             GROUP BY UPPER(format)
+             ORDER BY UPPER(format)",
+            filter
+        ))?;
+
+        let formats = stmt.query_map(params_from_iter(values.iter()), |row| {
+            Ok(FormatCount {
+                format: row.get(0)?,
+                book_count: row.get(1)?,
+            })
+        })?.collect::<SqlResult<Vec<_>>>()?;
+
+        Ok(formats)
+    }
+
+    /// Resolve the on-disk paths of a book's cover and formats from `books.path` and `data.name`.
+    ///
+    /// Returns `None` for unknown or hidden books and for paths that would leave the library folder.
+    pub fn get_book_files(&self, book_id: i32) -> SqlResult<Option<BookFiles>> {
+        let conn = self.pool.get()?;
+        if !self.is_visible(&conn, book_id)? {
+            return Ok(None);
+        }
+        let mut stmt = conn.prepare_cached(
+            "SELECT path, has_cover, last_modified FROM books WHERE id = ?"
+        )?;
+        let mut rows = stmt.query([book_id])?;
+        let Some(row) = rows.next()? else { return Ok(None) };
+        let relative: String = row.get(0)?;
+        let has_cover = row.get::<_, Option<bool>>(1)?.unwrap_or(false);
+        let last_modified: Option<String> = row.get(2)?;
+
+        let Some(dir) = self.library_path(&relative) else { return Ok(None) };
+
+        let mut stmt = conn.prepare_cached(
+            "SELECT format, name FROM data WHERE book = ? ORDER BY format"
+        )?;
+        let mut formats = Vec::new();
+        let mut rows = stmt.query([book_id])?;
+        while let Some(row) = rows.next()? {
+            let format: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            let file_name = format!("{}.{}", name, format.to_lowercase());
+            // data.name is a bare file stem; anything else is not trusted
+            if Path::new(&file_name).components().count() == 1 {
+                formats.push((format.to_uppercase(), dir.join(file_name)));
+            }
+        }
+
+        Ok(Some(BookFiles {
+            cover: has_cover.then(|| dir.join("cover.jpg")),
+            formats,
+            last_modified,
+        }))
+    }
+
+    /// Join a path stored in the database onto the library folder, refusing
+    /// absolute paths and `..` components
+    fn library_path(&self, relative: &str) -> Option<PathBuf> {
+        let relative = Path::new(relative);
+        if relative.as_os_str().is_empty()
+            || !relative.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+        Some(self.root.join(relative))
+    }
+
     pub fn get_book_formats(&self, book_id: i32) -> SqlResult<Vec<String>> {
-        let mut stmt = self.conn.prepare(
+        let conn = self.pool.get()?;
+        if !self.is_visible(&conn, book_id)? {
+            return Ok(Vec::new());
+        }
+        let mut stmt = conn.prepare(
             "SELECT format FROM data WHERE book = ? ORDER BY format"
         )?;
         
@@ -253,16 +987,479 @@ impl CalibreDb {
 
         Ok(formats)
     }
+}
 
-    pub fn get_book_comments(&self, book_id: i32) -> SqlResult<Option<String>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT text FROM comments WHERE book = ?"
-        )?;
-        
-        let comments = stmt.query_row([book_id], |row| {
-            row.get::<_, String>(0)
-        }).optional()?;
+/// Convert a stored custom column value to JSON according to the column's datatype
+fn custom_value(column: &CustomColumn, value: Value) -> serde_json::Value {
+    match (column.datatype.as_str(), value) {
+        (_, Value::Null) => serde_json::Value::Null,
+        ("bool", Value::Integer(i)) => serde_json::Value::Bool(i != 0),
+        ("datetime", Value::Text(text)) => calibre_date(Some(text)).into(),
+        (_, Value::Integer(i)) => i.into(),
+        (_, Value::Real(f)) => f.into(),
+        (_, Value::Text(text)) => text.into(),
+        (_, Value::Blob(_)) => serde_json::Value::Null,
+    }
+}
+
+/// Normalize a Calibre timestamp (`2021-03-04 12:34:56.123456+00:00`) to RFC 3339.
+///
+/// Calibre stores unknown dates as the year 101; those are reported as `None`.
+fn calibre_date(value: Option<String>) -> Option<String> {
+    let value = value?;
+    let parsed = chrono::DateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S%.f%:z")
+        .map(|date| date.with_timezone(&chrono::Utc))
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(&value, "%Y-%m-%d %H:%M:%S%.f")
+            .map(|date| date.and_utc()));
+    match parsed {
+        Ok(date) if chrono::Datelike::year(&date) <= 101 => None,
+        Ok(date) => Some(date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        Err(_) => Some(value),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Subset of the Calibre schema used by `CalibreDb`
+    const CALIBRE_SCHEMA: &str = "
+        CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT NOT NULL DEFAULT 'Unknown',
+            sort TEXT, timestamp TIMESTAMP, pubdate TIMESTAMP, series_index REAL NOT NULL DEFAULT 1.0,
+            author_sort TEXT, isbn TEXT DEFAULT '', lccn TEXT DEFAULT '', path TEXT NOT NULL DEFAULT '',
+            flags INTEGER NOT NULL DEFAULT 1, uuid TEXT, has_cover BOOL DEFAULT 0, last_modified TIMESTAMP);
+        CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT NOT NULL, sort TEXT, link TEXT NOT NULL DEFAULT '');
+        CREATE TABLE books_authors_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL, author INTEGER NOT NULL,
+            UNIQUE(book, author));
+        CREATE TABLE tags (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+        CREATE TABLE books_tags_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL, tag INTEGER NOT NULL,
+            UNIQUE(book, tag));
+        CREATE TABLE series (id INTEGER PRIMARY KEY, name TEXT NOT NULL, sort TEXT);
+        CREATE TABLE books_series_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL, series INTEGER NOT NULL,
+            UNIQUE(book));
+        CREATE TABLE publishers (id INTEGER PRIMARY KEY, name TEXT NOT NULL, sort TEXT);
+        CREATE TABLE books_publishers_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL,
+            publisher INTEGER NOT NULL, UNIQUE(book));
+        CREATE TABLE ratings (id INTEGER PRIMARY KEY, rating INTEGER);
+        CREATE TABLE books_ratings_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL, rating INTEGER NOT NULL,
+            UNIQUE(book, rating));
+        CREATE TABLE languages (id INTEGER PRIMARY KEY, lang_code TEXT NOT NULL);
+        CREATE TABLE books_languages_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL,
+            lang_code INTEGER NOT NULL, item_order INTEGER NOT NULL DEFAULT 0, UNIQUE(book, lang_code));
+        CREATE TABLE identifiers (id INTEGER PRIMARY KEY, book INTEGER NOT NULL,
+            type TEXT NOT NULL DEFAULT 'isbn', val TEXT NOT NULL, UNIQUE(book, type));
+        CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER NOT NULL, format TEXT NOT NULL,
+            uncompressed_size INTEGER NOT NULL, name TEXT NOT NULL, UNIQUE(book, format));
+        CREATE TABLE comments (id INTEGER PRIMARY KEY, book INTEGER NOT NULL, text TEXT NOT NULL, UNIQUE(book));
+        CREATE TABLE custom_columns (id INTEGER PRIMARY KEY, label TEXT NOT NULL, name TEXT NOT NULL,
+            datatype TEXT NOT NULL, mark_for_delete BOOL DEFAULT 0, editable BOOL DEFAULT 1,
+            display TEXT DEFAULT '{}', is_multiple BOOL DEFAULT 0, normalized BOOL NOT NULL);
+
+        INSERT INTO custom_columns (id, label, name, datatype, is_multiple, normalized) VALUES
+            (1, 'read', 'Read status', 'bool', 0, 0),
+            (2, 'shelf', 'Shelf', 'text', 1, 1),
+            (3, 'acquired', 'Date acquired', 'datetime', 0, 0),
+            (4, 'loaned', 'Loaned to', 'text', 0, 1),
+            (5, 'summary', 'Summary', 'composite', 0, 0);
+        CREATE TABLE custom_column_1 (id INTEGER PRIMARY KEY, book INTEGER, value BOOL NOT NULL, UNIQUE(book));
+        CREATE TABLE custom_column_2 (id INTEGER PRIMARY KEY, value TEXT NOT NULL COLLATE NOCASE,
+            link TEXT NOT NULL DEFAULT '', UNIQUE(value));
+        CREATE TABLE books_custom_column_2_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL,
+            value INTEGER NOT NULL, UNIQUE(book, value));
+        CREATE TABLE custom_column_3 (id INTEGER PRIMARY KEY, book INTEGER, value TIMESTAMP, UNIQUE(book));
+        CREATE TABLE custom_column_4 (id INTEGER PRIMARY KEY, value TEXT NOT NULL COLLATE NOCASE,
+            link TEXT NOT NULL DEFAULT '', UNIQUE(value));
+        CREATE TABLE books_custom_column_4_link (id INTEGER PRIMARY KEY, book INTEGER NOT NULL,
+            value INTEGER NOT NULL, UNIQUE(book, value));
+        INSERT INTO custom_column_2 (id, value) VALUES (1, 'Shelf A'), (2, 'Shelf B');
+        INSERT INTO custom_column_4 (id, value) VALUES (1, 'Smith, John');
+    ";
+
+    /// Create a Calibre-like metadata.db with `book_count` generated books.
+    ///
+    /// Book `n` is written by author `n % 7`, tagged with `n % 5` and `(n + 1) % 5`,
+    /// belongs to series `n % 3` when `n % 4 == 0`, is rated `n % 6` stars when
+    /// `n % 2 == 0` and has an EPUB, plus a PDF when `n % 3 == 0`. Its publication
+    /// date is left undefined when `n % 10 == 0`. Every book has an `isbn` identifier,
+    /// and even books also have a `goodreads` one.
+    ///
+    /// Custom columns: `read` is set unless `n % 3 == 0` (true for even books), `shelf`
+    /// holds "Shelf A" when `n % 2 == 0` and "Shelf B" when `n % 3 == 0`, `acquired` is
+    /// always set and `loaned` is "Smith, John" when `n % 5 == 0`.
+    pub(crate) fn fixture(book_count: usize) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("biblio-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metadata.db");
+
+        let mut conn = Connection::open(&path).unwrap();
+        conn.execute_batch(CALIBRE_SCHEMA).unwrap();
+
+        let tx = conn.transaction().unwrap();
+        for i in 0..7 {
+            tx.execute("INSERT INTO authors (id, name, sort) VALUES (?, ?, ?)",
+                (i + 1, format!("Author {}", i), format!("{}, Author", i))).unwrap();
+        }
+        for i in 0..5 {
+            tx.execute("INSERT INTO tags (id, name) VALUES (?, ?)", (i + 1, format!("Tag {}", i))).unwrap();
+        }
+        for i in 0..3 {
+            tx.execute("INSERT INTO series (id, name, sort) VALUES (?, ?, ?)",
+                (i + 1, format!("Series {}", i), format!("Series {}", i))).unwrap();
+        }
+        for i in 0..=5 {
+            tx.execute("INSERT INTO ratings (id, rating) VALUES (?, ?)", (i + 1, i * 2)).unwrap();
+        }
+        tx.execute("INSERT INTO publishers (id, name) VALUES (1, 'Publisher A')", []).unwrap();
+        tx.execute("INSERT INTO languages (id, lang_code) VALUES (1, 'eng')", []).unwrap();
+
+        for n in 1..=book_count as i64 {
+            let title = format!("Book {:05}", n);
+            let author = n % 7;
+            let book_path = format!("Author {}/{} ({})", author, title, n);
+            tx.execute(
+                "INSERT INTO books (id, title, sort, timestamp, pubdate, series_index, author_sort,
+                     path, uuid, has_cover, last_modified)
+                 VALUES (?1, ?2, ?2, datetime(1600000000 + ?1 * 60, 'unixepoch') || '+00:00',
+                     CASE WHEN ?1 % 10 = 0 THEN '0101-01-01 00:00:00+00:00'
+                          ELSE datetime(1000000000 + ?1 * 3600, 'unixepoch') || '+00:00' END,
+                     ?3, ?4, ?5, ?6, ?7, datetime(1600000000 + ?1 * 60, 'unixepoch') || '+00:00')",
+                rusqlite::params![n, title, (n % 10) as f64, format!("{}, Author", author),
+                    book_path, format!("uuid-{}", n), n % 2 == 0],
+            ).unwrap();
+            tx.execute("INSERT INTO books_authors_link (book, author) VALUES (?, ?)", (n, author + 1)).unwrap();
+            tx.execute("INSERT INTO books_tags_link (book, tag) VALUES (?, ?)", (n, n % 5 + 1)).unwrap();
+            tx.execute("INSERT INTO books_tags_link (book, tag) VALUES (?, ?)", (n, (n + 1) % 5 + 1)).unwrap();
+            if n % 4 == 0 {
+                tx.execute("INSERT INTO books_series_link (book, series) VALUES (?, ?)", (n, n % 3 + 1)).unwrap();
+            }
+            if n % 2 == 0 {
+                tx.execute("INSERT INTO books_ratings_link (book, rating) VALUES (?, ?)", (n, n % 6 + 1)).unwrap();
+            }
+            tx.execute("INSERT INTO books_publishers_link (book, publisher) VALUES (?, 1)", [n]).unwrap();
+            tx.execute("INSERT INTO books_languages_link (book, lang_code) VALUES (?, 1)", [n]).unwrap();
+            tx.execute("INSERT INTO identifiers (book, type, val) VALUES (?, 'isbn', ?)",
+                (n, format!("978{:010}", n))).unwrap();
+            if n % 2 == 0 {
+                tx.execute("INSERT INTO identifiers (book, type, val) VALUES (?, 'goodreads', ?)",
+                    (n, n.to_string())).unwrap();
+            }
+            tx.execute("INSERT INTO data (book, format, uncompressed_size, name) VALUES (?, 'EPUB', 1000, ?)",
+                (n, title.clone())).unwrap();
+            if n % 3 == 0 {
+                tx.execute("INSERT INTO data (book, format, uncompressed_size, name) VALUES (?, 'PDF', 2000, ?)",
+                    (n, title.clone())).unwrap();
+            }
+            tx.execute("INSERT INTO comments (book, text) VALUES (?, ?)",
+                (n, format!("<p>Description of book {}</p>", n))).unwrap();
+
+            if n % 3 != 0 {
+                tx.execute("INSERT INTO custom_column_1 (book, value) VALUES (?, ?)", (n, n % 2 == 0)).unwrap();
+            }
+            if n % 2 == 0 {
+                tx.execute("INSERT INTO books_custom_column_2_link (book, value) VALUES (?, 1)", [n]).unwrap();
+            }
+            if n % 3 == 0 {
+                tx.execute("INSERT INTO books_custom_column_2_link (book, value) VALUES (?, 2)", [n]).unwrap();
+            }
+            tx.execute("INSERT INTO custom_column_3 (book, value)
+                        VALUES (?1, datetime(1500000000 + ?1 * 86400, 'unixepoch') || '+00:00')", [n]).unwrap();
+            if n % 5 == 0 {
+                tx.execute("INSERT INTO books_custom_column_4_link (book, value) VALUES (?, 1)", [n]).unwrap();
+            }
+        }
+        tx.commit().unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_books_page_filters_sorts_and_paginates() {
+        let path = fixture(100);
+        let db = CalibreDb::open(&path).unwrap();
+
+        // Default ordering is most recently added first
+        let page = db.get_books_page(&BookQuery {
+            descending: true,
+            limit: 10,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(page.total, 100);
+        assert_eq!(page.books.len(), 10);
+        assert_eq!(page.books[0].id, 100);
+
+        // Facets are OR-ed within a list and AND-ed across lists
+        let page = db.get_books_page(&BookQuery {
+            author_ids: vec![1, 2],
+            formats: vec!["pdf".to_string()],
+            limit: 100,
+            ..Default::default()
+        }).unwrap();
+        assert!(page.books.iter().all(|b| b.formats.contains(&"PDF".to_string())));
+        assert!(page.books.iter().all(|b| b.authors == ["Author 0"] || b.authors == ["Author 1"]));
+        assert_eq!(page.total, (1..=100).filter(|n| n % 3 == 0 && n % 7 <= 1).count());
+
+        let page = db.get_books_page(&BookQuery {
+            languages: vec!["eng".to_string()],
+            limit: 1,
+            ..Default::default()
+        }).unwrap();
+        let english = db.get_all_languages().unwrap();
+        assert_eq!(page.total, english[0].book_count as usize);
+        let formats = db.get_all_formats().unwrap();
+        assert_eq!(formats.iter().map(|f| f.format.as_str()).collect::<Vec<_>>(), ["EPUB", "PDF"]);
+
+        // Books without a series are listed after the others
+        let page = db.get_books_page(&BookQuery {
+            sort: BookSort::Series,
+            offset: 20,
+            limit: 10,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(page.books[0].series.as_deref(), Some("Series 2"));
+        assert!(page.books[5].series.is_none());
+
+        let page = db.get_books_page(&BookQuery {
+            search: Some("book 0004".to_string()),
+            limit: 100,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(page.total, 10);
+
+        // Ranked id lists keep their order through the other filters
+        let page = db.get_books_page(&BookQuery {
+            book_ids: Some(vec![9, 4, 3, 6, 12]),
+            formats: vec!["PDF".to_string()],
+            sort: BookSort::Relevance,
+            offset: 1,
+            limit: 2,
+            ..Default::default()
+        }).unwrap();
+        assert_eq!(page.total, 4);
+        assert_eq!(page.books.iter().map(|b| b.id).collect::<Vec<_>>(), [3, 6]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_book_metadata() {
+        let path = fixture(10);
+        let db = CalibreDb::open(&path).unwrap();
+
+        let book = db.get_book(4).unwrap().unwrap();
+        assert_eq!(book.publisher.as_deref(), Some("Publisher A"));
+        assert_eq!(book.rating, Some(8));
+        assert_eq!(book.language.as_deref(), Some("eng"));
+        assert_eq!(book.pubdate.as_deref(), Some("2001-09-09T05:46:40Z"));
+        assert_eq!(book.timestamp.as_deref(), Some("2020-09-13T12:30:40Z"));
+        assert_eq!(book.last_modified, book.timestamp);
+        assert_eq!(book.uuid.as_deref(), Some("uuid-4"));
+        assert_eq!(book.isbn.as_deref(), Some("9780000000004"));
+        assert_eq!(book.identifiers.get("isbn").map(String::as_str), Some("9780000000004"));
+        assert_eq!(book.identifiers.get("goodreads").map(String::as_str), Some("4"));
+
+        let found = db.find_books_by_identifier("ISBN", "978-0-00-000000-4").unwrap();
+        assert_eq!(found.iter().map(|b| b.id).collect::<Vec<_>>(), [4]);
+        assert!(db.find_books_by_identifier("goodreads", "5").unwrap().is_empty());
+
+        // Zero-star ratings and Calibre's undefined date are reported as missing
+        let book = db.get_book(6).unwrap().unwrap();
+        assert_eq!(book.rating, None);
+        let book = db.get_book(10).unwrap().unwrap();
+        assert_eq!(book.pubdate, None);
+        assert!(book.timestamp.is_some());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_custom_columns() {
+        let path = fixture(30);
+        let db = CalibreDb::open(&path).unwrap();
+
+        let labels: Vec<&str> = db.custom_columns().iter().map(|c| c.label.as_str()).collect();
+        assert_eq!(labels, ["acquired", "loaned", "read", "shelf"]);
+
+        let book = db.get_book(10).unwrap().unwrap();
+        assert_eq!(book.custom_columns["read"], serde_json::json!(true));
+        assert_eq!(book.custom_columns["shelf"], serde_json::json!(["Shelf A"]));
+        assert_eq!(book.custom_columns["acquired"], serde_json::json!("2017-07-24T02:40:00Z"));
+        assert_eq!(book.custom_columns["loaned"], serde_json::json!("Smith, John"));
+        let book = db.get_book(9).unwrap().unwrap();
+        assert!(!book.custom_columns.contains_key("read"));
+        assert!(!book.custom_columns.contains_key("loaned"));
+
+        let query = |custom: Vec<(&str, Vec<&str>)>| BookQuery {
+            custom: custom.into_iter()
+                .map(|(l, v)| (l.to_string(), v.into_iter().map(str::to_string).collect()))
+                .collect(),
+            limit: 100,
+            ..Default::default()
+        };
+        let page = db.get_books_page(&query(vec![("#Shelf", vec!["shelf b"]), ("read", vec!["no"])])).unwrap();
+        assert_eq!(page.total, 0);
+        let page = db.get_books_page(&query(vec![("shelf", vec!["Shelf A", "Shelf B"])])).unwrap();
+        assert_eq!(page.total, 20);
+        let page = db.get_books_page(&query(vec![("loaned", vec!["smith, john"]), ("read", vec!["true"])])).unwrap();
+        assert_eq!(page.books.iter().map(|b| b.id).collect::<Vec<_>>(), [10, 20]);
+        let page = db.get_books_page(&query(vec![("acquired", vec!["2017-07-24"])])).unwrap();
+        assert_eq!(page.books.iter().map(|b| b.id).collect::<Vec<_>>(), [10]);
+        assert!(db.get_books_page(&query(vec![("missing", vec!["x"])])).is_err());
+
+        let shelf = db.custom_column("shelf").unwrap();
+        let values = db.get_custom_column_values(shelf).unwrap();
+        assert_eq!(values.iter().map(|v| (v.value.clone(), v.book_count)).collect::<Vec<_>>(),
+            [(serde_json::json!("Shelf A"), 15), (serde_json::json!("Shelf B"), 10)]);
+        let read = db.get_custom_column_values(db.custom_column("read").unwrap()).unwrap();
+        assert_eq!(read.iter().map(|v| (v.value.clone(), v.book_count)).collect::<Vec<_>>(),
+            [(serde_json::json!(false), 10), (serde_json::json!(true), 10)]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_content_restrictions() {
+        let path = fixture(30);
+        let db = CalibreDb::open(&path).unwrap();
+
+        // Book n carries tags `n % 5` and `(n + 1) % 5`; even books are rated `n % 6` stars
+        let restricted = db.restricted(ContentRestrictions {
+            denied_tags: vec!["tag 0".to_string()],
+            min_rating: Some(3),
+            ..Default::default()
+        });
+        let visible: Vec<i32> = (1..=30)
+            .filter(|n| n % 5 != 0 && (n + 1) % 5 != 0 && !(n % 2 == 0 && n % 6 == 2))
+            .collect();
+        assert_eq!(restricted.count_books().unwrap(), visible.len());
+        assert_eq!(restricted.hidden_book_ids().unwrap().len(), 30 - visible.len());
+        let page = restricted.get_books_page(&BookQuery { limit: 100, ..Default::default() }).unwrap();
+        assert_eq!(page.books.iter().map(|b| b.id).collect::<Vec<_>>(), visible);
+        assert!(restricted.get_book(5).unwrap().is_none());
+        assert!(restricted.get_book_files(8).unwrap().is_none());
+        assert!(restricted.get_book_formats(4).unwrap().is_empty());
+        assert!(restricted.get_book(1).unwrap().is_some());
+        assert!(db.get_book(5).unwrap().is_some());
+
+        // Facets only count visible books and leave out values without any
+        let tags = restricted.get_all_tags().unwrap();
+        assert!(tags.iter().all(|t| t.name != "Tag 0"));
+        assert_eq!(tags.iter().map(|t| t.book_count as usize).sum::<usize>(), visible.len() * 2);
+        let formats = restricted.get_all_formats().unwrap();
+        assert_eq!(formats[0].book_count as usize, visible.len());
+
+        let allowed = db.restricted(ContentRestrictions {
+            allowed_tags: vec!["Tag 1".to_string()],
+            ..Default::default()
+        });
+        let page = allowed.get_books_page(&BookQuery { limit: 100, ..Default::default() }).unwrap();
+        assert!(page.books.iter().all(|b| b.tags.contains(&"Tag 1".to_string())));
+        assert_eq!(page.total, 12);
+        assert_eq!(allowed.find_books_by_identifier("isbn", "9780000000002").unwrap().len(), 0);
+        assert_eq!(allowed.find_books_by_identifier("isbn", "9780000000001").unwrap().len(), 1);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_book_files() {
+        let path = fixture(3);
+        let db = CalibreDb::open(&path).unwrap();
+        let root = path.parent().unwrap();
+
+        let files = db.get_book_files(3).unwrap().unwrap();
+        assert_eq!(files.cover, None);
+        assert_eq!(files.formats, [
+            ("EPUB".to_string(), root.join("Author 3/Book 00003 (3)/Book 00003.epub")),
+            ("PDF".to_string(), root.join("Author 3/Book 00003 (3)/Book 00003.pdf")),
+        ]);
+        assert_eq!(db.get_book_files(2).unwrap().unwrap().cover,
+            Some(root.join("Author 2/Book 00002 (2)/cover.jpg")));
+        assert!(db.get_book_files(4).unwrap().is_none());
+
+        // Paths pointing outside the library are not resolved
+        let conn = Connection::open(&path).unwrap();
+        conn.execute("UPDATE books SET path = '../../etc' WHERE id = 1", []).unwrap();
+        conn.execute("UPDATE data SET name = '../secret' WHERE book = 2", []).unwrap();
+        assert!(db.get_book_files(1).unwrap().is_none());
+        assert!(db.get_book_files(2).unwrap().unwrap().formats.is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Load books the way the loader used to: one query per relation per book
+    fn load_books_per_row(db: &CalibreDb, ids: &[i32]) -> Vec<Book> {
+        let conn = db.pool.get().unwrap();
+        let related = |sql: &str, id: i32| -> Vec<String> {
+            let mut stmt = conn.prepare(sql).unwrap();
+            stmt.query_map([id], |row| row.get(0)).unwrap().map(|r| r.unwrap()).collect()
+        };
+
+        ids.iter().map(|&id| {
+            let (title, has_cover, sort): (String, bool, Option<String>) = conn
+                .query_row("SELECT title, has_cover, sort FROM books WHERE id = ?", [id],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+                .unwrap();
+            Book {
+                id,
+                title,
+                authors: related("SELECT a.name FROM authors a INNER JOIN books_authors_link bal
+                    ON a.id = bal.author WHERE bal.book = ?", id),
+                series: related("SELECT s.name FROM series s INNER JOIN books_series_link bsl
+                    ON s.id = bsl.series WHERE bsl.book = ?", id).pop(),
+                series_index: None,
+                tags: related("SELECT t.name FROM tags t INNER JOIN books_tags_link btl
+                    ON t.id = btl.tag WHERE btl.book = ?", id),
+                comments: related("SELECT text FROM comments WHERE book = ?", id).pop(),
+                publisher: None,
+                pubdate: None,
+                rating: None,
+                timestamp: None,
+                language: None,
+                has_cover,
+                formats: related("SELECT format FROM data WHERE book = ? ORDER BY format", id),
+                sort,
+                last_modified: None,
+                uuid: None,
+                isbn: None,
+                identifiers: BTreeMap::new(),
+                custom_columns: BTreeMap::new(),
+            }
+        }).collect()
+    }
+
+    /// Compare the batched loader with per-row queries on a 50k book library.
+    /// Run with `cargo test --release -- --ignored --nocapture bench_load_books`.
+    #[test]
+    #[ignore]
+    fn bench_load_books() {
+        let path = fixture(50_000);
+        let db = CalibreDb::open(&path).unwrap();
+        let all_ids: Vec<i32> = (1..=50_000).collect();
+
+        for count in [100, 1_000, 50_000] {
+            let ids = &all_ids[..count];
+
+            let start = std::time::Instant::now();
+            let per_row = load_books_per_row(&db, ids);
+            let per_row_time = start.elapsed();
+
+            let start = std::time::Instant::now();
+            let batched = db.load_books(ids).unwrap();
+            let batched_time = start.elapsed();
+
+            assert_eq!(per_row.len(), batched.len());
+            assert_eq!(per_row[count - 1].authors, batched[count - 1].authors);
+            assert_eq!(per_row[count - 1].tags, batched[count - 1].tags);
+            println!(
+                "{:>6} books: per-row {:>10.2?}  batched {:>10.2?}  ({:.1}x)",
+                count,
+                per_row_time,
+                batched_time,
+                per_row_time.as_secs_f64() / batched_time.as_secs_f64()
+            );
+        }
 
-        Ok(comments)
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
     }
 }
@@ -0,0 +1,277 @@
+// Library change notifications, numbered so clients can catch up on what they missed
+use actix_web::web::{self, Bytes, Data};
+use chrono::Utc;
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+use crate::library::LibraryChange;
+use crate::rbac::LibraryAccess;
+use crate::session::SessionStore;
+
+/// Events kept for clients asking what happened since a revision
+const HISTORY_LEN: usize = 256;
+
+/// Events buffered per stream; a stream falling further behind catches up from the history
+const CHANNEL_CAPACITY: usize = 64;
+
+/// How often streams send a keepalive comment and check that the session is still valid
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LibraryEvent {
+    /// Increases by one with every event, starting at 1
+    pub revision: u64,
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub change: LibraryChange,
+}
+
+impl LibraryEvent {
+    pub fn visible_to(&self, access: &LibraryAccess) -> bool {
+        access.allows(self.change.library_id(), self.change.name())
+    }
+}
+
+struct History {
+    revision: u64,
+    events: VecDeque<LibraryEvent>,
+}
+
+pub struct LibraryEvents {
+    history: Mutex<History>,
+    sender: broadcast::Sender<LibraryEvent>,
+}
+
+impl LibraryEvents {
+    pub fn new() -> Self {
+        LibraryEvents {
+            history: Mutex::new(History { revision: 0, events: VecDeque::new() }),
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+
+    /// Revision of the latest event, 0 before the first one
+    pub fn revision(&self) -> u64 {
+        self.history.lock().unwrap().revision
+    }
+
+    pub fn publish(&self, change: LibraryChange) {
+        let mut history = self.history.lock().unwrap();
+        history.revision += 1;
+        let event = LibraryEvent {
+            revision: history.revision,
+            timestamp: Utc::now().to_rfc3339(),
+            change,
+        };
+        if history.events.len() == HISTORY_LEN {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // Sent under the lock so streams get events in revision order; having no streams is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Receive the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<LibraryEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Events after `revision`, or `None` when some of them are no longer kept
+    /// and the client should reload everything instead
+    pub fn since(&self, revision: u64) -> Option<Vec<LibraryEvent>> {
+        let history = self.history.lock().unwrap();
+        let oldest = history.events.front().map(|e| e.revision).unwrap_or(history.revision + 1);
+        if revision > history.revision || revision + 1 < oldest {
+            return None;
+        }
+        Some(history.events.iter().filter(|e| e.revision > revision).cloned().collect())
+    }
+}
+
+/// One Server-Sent Events message; library events carry their revision as id
+fn sse_message(event: &LibraryEvent) -> Bytes {
+    let data = serde_json::to_value(event).unwrap_or_default();
+    let kind = data["type"].as_str().unwrap_or("message").to_string();
+    Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", event.revision, kind, data))
+}
+
+/// Tells the client that events were missed and it should reload everything
+fn sse_resync(revision: u64) -> Bytes {
+    Bytes::from(format!("id: {}\nevent: resync\ndata: {{\"revision\":{}}}\n\n", revision, revision))
+}
+
+struct StreamState {
+    events: Data<LibraryEvents>,
+    receiver: broadcast::Receiver<LibraryEvent>,
+    pending: VecDeque<Bytes>,
+    last_revision: u64,
+    sessions: Data<SessionStore>,
+    /// Session the stream was opened with; empty for HTTP Basic clients
+    token: String,
+    username: String,
+    /// Events about libraries outside it are skipped
+    access: LibraryAccess,
+    keepalive: tokio::time::Interval,
+    done: bool,
+}
+
+impl StreamState {
+    /// Queue the events after `last_revision`, or a resync when they are no longer kept
+    fn catch_up(&mut self) {
+        match self.events.since(self.last_revision) {
+            Some(missed) => {
+                for event in missed {
+                    self.last_revision = event.revision;
+                    if event.visible_to(&self.access) {
+                        self.pending.push_back(sse_message(&event));
+                    }
+                }
+            }
+            None => {
+                self.last_revision = self.events.revision();
+                self.pending.push_back(sse_resync(self.last_revision));
+            }
+        }
+    }
+
+    /// Look the user up again, so changes to their role or library access apply to
+    /// open streams. False once the user is deleted or cannot be looked up.
+    async fn refresh_access(&mut self) -> bool {
+        let username = self.username.clone();
+        let access = web::block(move || LibraryAccess::for_user(&username))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|access| access.map_err(|e| e.to_string()));
+        match access {
+            Ok(Some(access)) => {
+                self.access = access;
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                warn!("Closing event stream of {}: {}", self.username, e);
+                false
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        self.pending.push_back(Bytes::from_static(b"event: session_expired\ndata: {}\n\n"));
+        self.done = true;
+    }
+}
+
+/// Stream library events to a client as Server-Sent Events, starting after
+/// `last_event_id` when the client is reconnecting. Only events about libraries
+/// the user may see are sent; their access is looked up again before each event and
+/// keepalive. The stream ends with a `session_expired` event once the session it
+/// was opened with or the user is gone.
+pub fn sse_stream(
+    events: Data<LibraryEvents>,
+    sessions: Data<SessionStore>,
+    token: String,
+    username: String,
+    access: LibraryAccess,
+    last_event_id: Option<u64>,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    // Subscribe before reading the history so nothing falls in between; duplicates are skipped by revision
+    let receiver = events.subscribe();
+    let mut state = StreamState {
+        last_revision: last_event_id.unwrap_or_else(|| events.revision()),
+        events,
+        receiver,
+        pending: VecDeque::from([Bytes::from_static(b": connected\n\n")]),
+        sessions,
+        token,
+        username,
+        access,
+        keepalive: tokio::time::interval_at(tokio::time::Instant::now() + KEEPALIVE, KEEPALIVE),
+        done: false,
+    };
+    if last_event_id.is_some() {
+        state.catch_up();
+    }
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(message) = state.pending.pop_front() {
+                return Some((Ok(message), state));
+            }
+            if state.done {
+                return None;
+            }
+
+            tokio::select! {
+                received = state.receiver.recv() => match received {
+                    Ok(event) => {
+                        if event.revision > state.last_revision {
+                            if !state.refresh_access().await {
+                                state.expire();
+                                continue;
+                            }
+                            state.last_revision = event.revision;
+                            if event.visible_to(&state.access) {
+                                state.pending.push_back(sse_message(&event));
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => {
+                        if state.refresh_access().await {
+                            state.catch_up();
+                        } else {
+                            state.expire();
+                        }
+                    }
+                    Err(RecvError::Closed) => state.done = true,
+                },
+                _ = state.keepalive.tick() => {
+                    let session_gone = !state.token.is_empty() && !state.sessions.is_session_active(&state.token);
+                    if session_gone || !state.refresh_access().await {
+                        state.expire();
+                    } else {
+                        state.pending.push_back(Bytes::from_static(b": keepalive\n\n"));
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn removed(name: &str) -> LibraryChange {
+        LibraryChange::LibraryRemoved { library_id: name.to_string(), name: name.to_string() }
+    }
+
+    #[test]
+    fn test_history() {
+        let events = LibraryEvents::new();
+        assert_eq!(events.since(0).unwrap().len(), 0);
+
+        let mut receiver = events.subscribe();
+        events.publish(removed("a"));
+        events.publish(removed("b"));
+        assert_eq!(events.revision(), 2);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.revision, 1);
+        assert!(sse_message(&event).starts_with(b"id: 1\nevent: library_removed\ndata: {"));
+
+        let missed = events.since(1).unwrap();
+        assert_eq!(missed.len(), 1);
+        assert_eq!(serde_json::to_value(&missed[0]).unwrap()["type"], "library_removed");
+
+        // A revision from before a restart, or one too old to replay
+        assert!(events.since(5).is_none());
+        for n in 0..HISTORY_LEN {
+            events.publish(removed(&n.to_string()));
+        }
+        assert!(events.since(1).is_none());
+        assert_eq!(events.since(2).unwrap().len(), HISTORY_LEN);
+    }
+}
@@ -2,9 +2,13 @@ use std::path::{Path, PathBuf};
 use walkdir::WalkDir;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
+use crate::config;
 use crate::db::CalibreDb;
-use std::collections::HashMap;
-use tracing::{debug, warn, error};
+use crate::search::SearchIndex;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
+use tracing::{debug, info, warn, error};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LibraryMetadata {
@@ -29,6 +33,25 @@ impl LibraryScanner {
     pub fn scan(&self) -> std::io::Result<Vec<LibraryMetadata>> {
         let mut libraries = Vec::new();
 
+        for (path, metadata_db) in self.library_paths() {
+            if let Ok(lib_metadata) = self.create_library_metadata(&path, &metadata_db) {
+                libraries.push(lib_metadata);
+            } else {
+                warn!("Failed to create library metadata for path: {:?}", path);
+            }
+        }
+
+        // Sort by name
+        libraries.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(libraries)
+    }
+
+    /// Folders containing a metadata.db, with the path of that database.
+    /// Cheap enough to call on every change: no database is opened.
+    pub fn library_paths(&self) -> Vec<(PathBuf, PathBuf)> {
+        let mut paths = Vec::new();
+
         // Scan for directories containing metadata.db
         for entry in WalkDir::new(&self.base_path)
             .max_depth(2)
@@ -40,20 +63,13 @@ impl LibraryScanner {
 
             if metadata_db.exists() {
                 // This is a Calibre library
-                if let Ok(lib_metadata) = self.create_library_metadata(path, &metadata_db) {
-                    libraries.push(lib_metadata);
-                } else {
-                    warn!("Failed to create library metadata for path: {:?}", path);
-                }
+                paths.push((path.to_path_buf(), metadata_db));
             } else if path.is_dir() && entry.depth() <= 2 {
                 debug!("No metadata.db found in directory: {:?}", path);
             }
         }
 
-        // Sort by name
-        libraries.sort_by(|a, b| a.name.cmp(&b.name));
-
-        Ok(libraries)
+        paths
     }
 
     fn create_library_metadata(
@@ -67,49 +83,233 @@ impl LibraryScanner {
             .to_string_lossy()
             .to_string();
 
-        // Create a stable UUID from the library path using UUID v5 (SHA-1 namespace hash)
-        // This ensures the same path always produces the same ID
-        let path_str = path.to_string_lossy();
-        let id = Uuid::new_v5(&Uuid::NAMESPACE_DNS, path_str.as_bytes()).to_string();
-
-        // Try to count books in the database
-        let book_count = self.get_book_count(metadata_db_path).unwrap_or(0);
+        let id = library_id(path);
 
+        // Counted by `load_library`, which opens the database anyway
         Ok(LibraryMetadata {
             id,
             name: library_name,
             path: path.to_path_buf(),
             metadata_db_path: metadata_db_path.to_path_buf(),
-            book_count,
+            book_count: 0,
         })
     }
+}
 
-    fn get_book_count(&self, metadata_db_path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
-        match CalibreDb::open(metadata_db_path) {
-            Ok(db) => {
-                match db.get_all_books() {
-                    Ok(books) => {
-                        debug!("Successfully retrieved {} books from database", books.len());
-                        Ok(books.len())
-                    }
-                    Err(e) => {
-                        error!("Failed to access tables in database at {:?}: {}", metadata_db_path, e);
-                        Err(Box::new(e))
-                    }
-                }
-            }
+/// Create a stable UUID from the library path using UUID v5 (SHA-1 namespace hash)
+/// This ensures the same path always produces the same ID
+pub fn library_id(path: &Path) -> String {
+    let path_str = path.to_string_lossy();
+    Uuid::new_v5(&Uuid::NAMESPACE_DNS, path_str.as_bytes()).to_string()
+}
+
+/// Modification time and size of metadata.db and of its WAL file when there is one
+pub(crate) fn database_stamp(metadata_db_path: &Path) -> String {
+    let mut wal = metadata_db_path.as_os_str().to_owned();
+    wal.push("-wal");
+    [metadata_db_path, Path::new(&wal)]
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).ok())
+        .map(|meta| {
+            let mtime = meta.modified().ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos())
+                .unwrap_or(0);
+            format!("{}:{}", mtime, meta.len())
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// State of a library's database when it was loaded, to tell what changed since
+#[derive(Debug, Clone, Default)]
+struct LibrarySnapshot {
+    stamp: String,
+    /// `last_modified` of every book, bumped by Calibre on each edit
+    books: HashMap<i32, Option<String>>,
+}
+
+/// A library opened outside the cache lock, ready to be inserted
+struct LoadedLibrary {
+    metadata: LibraryMetadata,
+    db: CalibreDb,
+    index: Option<Arc<SearchIndex>>,
+    snapshot: LibrarySnapshot,
+}
+
+/// Open a library's database and search index. An index already open for the
+/// library is reused, so rebuilds of one index never run twice at once.
+fn load_library(mut lib: LibraryMetadata, index: Option<Arc<SearchIndex>>) -> Option<LoadedLibrary> {
+    // Stamp before reading, so a write landing meanwhile is seen by the next check
+    let stamp = database_stamp(&lib.metadata_db_path);
+    let db = match CalibreDb::open(&lib.metadata_db_path) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to open database for library '{}' at {:?}: {}", lib.name, lib.metadata_db_path, e);
+            return None;
+        }
+    };
+    debug!("Successfully loaded library '{}' from {:?}", lib.name, lib.metadata_db_path);
+
+    let books = match db.book_stamps() {
+        Ok(books) => books,
+        Err(e) => {
+            error!("Failed to read books of library '{}': {}", lib.name, e);
+            return None;
+        }
+    };
+    lib.book_count = books.len();
+
+    let index = index.or_else(|| {
+        let index_path = Path::new(&config::search_index_path()).join(format!("{}.db", lib.id));
+        match SearchIndex::open(&index_path, &lib.metadata_db_path) {
+            Ok(index) => Some(Arc::new(index)),
             Err(e) => {
-                error!("Failed to open database at {:?}: {}", metadata_db_path, e);
-                Err(Box::new(e))
+                warn!("Failed to open search index for library '{}' at {:?}, falling back to simple search: {}", lib.name, index_path, e);
+                None
             }
         }
+    });
+
+    Some(LoadedLibrary {
+        metadata: lib,
+        db,
+        index,
+        snapshot: LibrarySnapshot { stamp, books },
+    })
+}
+
+/// A difference found by `rescan_libraries`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LibraryChange {
+    LibraryAdded {
+        library_id: String,
+        name: String,
+    },
+    LibraryRemoved {
+        library_id: String,
+        name: String,
+    },
+    /// Books added or edited (`book_ids`) and deleted (`removed_book_ids`)
+    BooksChanged {
+        library_id: String,
+        name: String,
+        book_ids: Vec<i32>,
+        removed_book_ids: Vec<i32>,
+    },
+}
+
+impl LibraryChange {
+    pub fn library_id(&self) -> &str {
+        match self {
+            LibraryChange::LibraryAdded { library_id, .. }
+            | LibraryChange::LibraryRemoved { library_id, .. }
+            | LibraryChange::BooksChanged { library_id, .. } => library_id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            LibraryChange::LibraryAdded { name, .. }
+            | LibraryChange::LibraryRemoved { name, .. }
+            | LibraryChange::BooksChanged { name, .. } => name,
+        }
     }
 }
 
+/// Bring the cache in line with the libraries on disk: new library folders are
+/// loaded, vanished ones dropped, and libraries whose metadata.db changed are
+/// reopened. Untouched libraries are left alone. Databases are opened without
+/// holding the cache lock, which is only taken briefly to read stamps and to
+/// swap the results in.
+pub fn rescan_libraries(cache: &RwLock<LibraryCache>, base_path: &Path) -> Vec<LibraryChange> {
+    let found: Vec<(String, PathBuf, PathBuf)> = LibraryScanner::new(base_path)
+        .library_paths()
+        .into_iter()
+        .map(|(path, metadata_db)| (library_id(&path), path, metadata_db))
+        .collect();
+
+    let known: HashMap<String, (String, String, Option<Arc<SearchIndex>>)> = {
+        let cache = cache.read().unwrap();
+        cache.libraries.values()
+            .map(|lib| {
+                let stamp = cache.snapshots.get(&lib.id).map(|s| s.stamp.clone()).unwrap_or_default();
+                (lib.id.clone(), (lib.name.clone(), stamp, cache.search_indexes.get(&lib.id).cloned()))
+            })
+            .collect()
+    };
+
+    let mut loaded = Vec::new();
+    for (id, path, metadata_db) in &found {
+        let index = match known.get(id) {
+            Some((_, stamp, _)) if *stamp == database_stamp(metadata_db) => continue,
+            Some((_, _, index)) => index.clone(),
+            None => None,
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let lib = LibraryMetadata {
+            id: id.clone(),
+            name,
+            path: path.clone(),
+            metadata_db_path: metadata_db.clone(),
+            book_count: 0,
+        };
+        if let Some(library) = load_library(lib, index) {
+            loaded.push(library);
+        }
+    }
+
+    let found_ids: HashSet<&String> = found.iter().map(|(id, _, _)| id).collect();
+    let mut changes = Vec::new();
+    let mut cache = cache.write().unwrap();
+
+    for (id, (name, _, _)) in &known {
+        if !found_ids.contains(id) {
+            info!("Library '{}' was removed", name);
+            cache.remove(id);
+            changes.push(LibraryChange::LibraryRemoved { library_id: id.clone(), name: name.clone() });
+        }
+    }
+
+    for library in loaded {
+        let id = library.metadata.id.clone();
+        let name = library.metadata.name.clone();
+        match cache.snapshots.get(&id) {
+            None => {
+                info!("Library '{}' was added", name);
+                changes.push(LibraryChange::LibraryAdded { library_id: id, name });
+            }
+            Some(previous) => {
+                let current = &library.snapshot.books;
+                let mut book_ids: Vec<i32> = current.iter()
+                    .filter(|(book_id, modified)| previous.books.get(book_id) != Some(modified))
+                    .map(|(book_id, _)| *book_id)
+                    .collect();
+                let mut removed_book_ids: Vec<i32> = previous.books.keys()
+                    .filter(|book_id| !current.contains_key(book_id))
+                    .copied()
+                    .collect();
+                if !book_ids.is_empty() || !removed_book_ids.is_empty() {
+                    book_ids.sort_unstable();
+                    removed_book_ids.sort_unstable();
+                    info!("Library '{}' changed: {} book(s) added or edited, {} removed", name, book_ids.len(), removed_book_ids.len());
+                    changes.push(LibraryChange::BooksChanged { library_id: id, name, book_ids, removed_book_ids });
+                }
+            }
+        }
+        cache.insert(library);
+    }
+
+    changes
+}
+
 // In-memory cache of loaded libraries
 pub struct LibraryCache {
     libraries: HashMap<String, LibraryMetadata>,
     databases: HashMap<String, CalibreDb>,
+    search_indexes: HashMap<String, Arc<SearchIndex>>,
+    snapshots: HashMap<String, LibrarySnapshot>,
 }
 
 impl LibraryCache {
@@ -117,34 +317,42 @@ impl LibraryCache {
         LibraryCache {
             libraries: HashMap::new(),
             databases: HashMap::new(),
+            search_indexes: HashMap::new(),
+            snapshots: HashMap::new(),
         }
     }
 
-    pub fn clear(&mut self) {
-        self.libraries.clear();
-        self.databases.clear();
-    }
-
     pub fn load_libraries(&mut self, base_path: &Path) -> std::io::Result<()> {
         let scanner = LibraryScanner::new(base_path);
         let libraries = scanner.scan()?;
 
         for lib in libraries {
-            match CalibreDb::open(&lib.metadata_db_path) {
-                Ok(db) => {
-                    debug!("Successfully loaded library '{}' from {:?}", lib.name, lib.metadata_db_path);
-                    self.databases.insert(lib.id.clone(), db);
-                    self.libraries.insert(lib.id.clone(), lib);
-                }
-                Err(e) => {
-                    error!("Failed to open database for library '{}' at {:?}: {}", lib.name, lib.metadata_db_path, e);
-                }
+            if let Some(library) = load_library(lib, None) {
+                self.insert(library);
             }
         }
 
         Ok(())
     }
 
+    fn insert(&mut self, library: LoadedLibrary) {
+        let id = library.metadata.id.clone();
+        match library.index {
+            Some(index) => self.search_indexes.insert(id.clone(), index),
+            None => self.search_indexes.remove(&id),
+        };
+        self.databases.insert(id.clone(), library.db);
+        self.snapshots.insert(id.clone(), library.snapshot);
+        self.libraries.insert(id, library.metadata);
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.libraries.remove(id);
+        self.databases.remove(id);
+        self.search_indexes.remove(id);
+        self.snapshots.remove(id);
+    }
+
     pub fn get_libraries(&self) -> Vec<LibraryMetadata> {
         let mut libs: Vec<_> = self.libraries.values().cloned().collect();
         libs.sort_by(|a, b| a.name.cmp(&b.name));
@@ -158,4 +366,29 @@ impl LibraryCache {
     pub fn get_database(&self, id: &str) -> Option<&CalibreDb> {
         self.databases.get(id)
     }
+
+    pub fn get_search_index(&self, id: &str) -> Option<Arc<SearchIndex>> {
+        self.search_indexes.get(id).cloned()
+    }
+
+    /// Bring every search index up to date in the background, so the first
+    /// search after startup or a refresh does not wait for a rebuild
+    pub fn refresh_search_indexes(&self) {
+        for id in self.search_indexes.keys() {
+            self.refresh_search_index(id);
+        }
+    }
+
+    /// Bring one library's search index up to date in the background
+    pub fn refresh_search_index(&self, id: &str) {
+        if let (Some(index), Some(db)) = (self.search_indexes.get(id), self.databases.get(id).cloned()) {
+            let index = index.clone();
+            let id = id.to_string();
+            std::thread::spawn(move || {
+                if let Err(e) = index.ensure_fresh(&db) {
+                    error!("Failed to build search index for library {}: {}", id, e);
+                }
+            });
+        }
+    }
 }
@@ -6,11 +6,21 @@ mod auth;
 mod session;
 mod audit;
 mod rbac;
+mod throttle;
+mod pool;
+mod thumbnail;
+mod search;
+mod content;
+mod opds;
+mod opds2;
+mod events;
+mod watcher;
+mod users;
 
 use actix_web::{web, App, HttpServer, middleware};
 use actix_files::Files;
 use library::LibraryCache;
-use std::sync::Mutex;
+use std::sync::RwLock;
 use std::path::Path;
 use tracing::{warn, info, error};
 use rustls::{ServerConfig, pki_types::CertificateDer};
@@ -44,25 +54,61 @@ async fn main() -> std::io::Result<()> {
         ));
     }
 
-    // Load users for authentication
-    let users_path = config::users_file_path();
-    let users = match auth::load_users(&users_path) {
-        Ok(users) => {
-            info!("Loaded {} user(s) for authentication", users.len());
-            web::Data::new(users)
+    // Open the user store, importing users.ids on first start
+    if let Err(e) = users::init() {
+        error!("Check user_db_path, or set user_store: file in config.yaml to keep using users.ids without a database.");
+        return Err(std::io::Error::other(format!("User store initialization failed: {}", e)));
+    }
+
+    // Initialize session store (30 minute timeout)
+    let session_store = web::Data::new(session::SessionStore::new(30));
+
+
+    // Initialize audit logger backed by its own SQLite database
+    let audit_db_path = config::audit_db_path();
+    let audit_logger = match audit::AuditLogger::open(&audit_db_path) {
+        Ok(logger) => {
+            info!("Storing audit events in {}", audit_db_path);
+            web::Data::new(logger)
         }
         Err(e) => {
-            error!("Failed to load users: {}", e);
-            error!("Authentication will be disabled. Configure users_file_path in config.yaml");
-            web::Data::new(vec![])
+            error!("Check audit_db_path in config.yaml; the server does not start without an audit trail.");
+            return Err(std::io::Error::other(format!(
+                "Failed to open audit database at {}: {}",
+                audit_db_path, e
+            )));
         }
     };
 
-    // Initialize session store (30 minute timeout)
-    let session_store = web::Data::new(session::SessionStore::new(30));
+    // Initialize failed login tracking
+    let login_throttle = web::Data::new(throttle::LoginThrottle::new(config::login_throttle()));
+
+    // Periodically drop expired sessions, stale login failures and audit events past their retention
+    let cleanup_store = session_store.clone();
+    let cleanup_throttle = login_throttle.clone();
+    let cleanup_audit = audit_logger.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(300));
+        loop {
+            interval.tick().await;
+            cleanup_store.cleanup_expired_sessions();
+            cleanup_throttle.cleanup();
+
+            let retention_days = config::audit_retention_days();
+            if retention_days > 0 {
+                let audit = cleanup_audit.clone();
+                match web::block(move || audit.prune_older_than(retention_days)).await {
+                    Ok(Ok(0)) => {}
+                    Ok(Ok(removed)) => info!("Pruned {} audit event(s) older than {} days", removed, retention_days),
+                    Ok(Err(e)) => error!("Failed to prune audit events: {}", e),
+                    Err(e) => error!("Failed to prune audit events: {}", e),
+                }
+            }
+        }
+    });
 
-    // Initialize audit logger (keep last 1000 events)
-    let audit_logger = web::Data::new(audit::AuditLogger::new(1000));
+    // Background indexing of book contents
+    let content_jobs = web::Data::new(content::ContentIndexJobs::new());
 
     // Set up library cache
     let mut cache = LibraryCache::new();
@@ -72,12 +118,20 @@ async fn main() -> std::io::Result<()> {
     let libraries_path = Path::new(&library_path);
     if libraries_path.exists() {
         cache.load_libraries(libraries_path)?;
+        cache.refresh_search_indexes();
+        if config::content_indexing() {
+            content_jobs.start_all(&cache);
+        }
     } else {
         warn!("Libraries directory not found at {:?}", libraries_path);
         warn!("Please configure library_path in config.yaml");
     }
 
-    let cache = web::Data::new(Mutex::new(cache));
+    let cache = web::Data::new(RwLock::new(cache));
+
+    // Pick up library changes on disk and tell clients about them
+    let library_events = web::Data::new(events::LibraryEvents::new());
+    watcher::spawn(cache.clone(), library_events.clone(), content_jobs.clone());
 
     // Get service binding address
     let service_ip_and_port = config::service_ip_and_port();
@@ -86,18 +140,22 @@ async fn main() -> std::io::Result<()> {
     let protocol = if config::use_https() { "https" } else { "http" };
     info!("Starting Biblio server on {}://{}", protocol, service_ip_and_port);
 
+    let shutdown_audit = audit_logger.clone();
     let server_builder = HttpServer::new(move || {
         App::new()
             .app_data(cache.clone())
-            .app_data(users.clone())
             .app_data(session_store.clone())
             .app_data(audit_logger.clone())
+            .app_data(login_throttle.clone())
+            .app_data(content_jobs.clone())
+            .app_data(library_events.clone())
             .wrap(middleware::Logger::default())
             .configure(api::configure)
+            .configure(opds::configure)
             .service(Files::new("/", "./public").index_file("index.html"))
     });
 
-    if config::use_https() {
+    let result = if config::use_https() {
         info!("Setting up HTTPS with TLS");
         match load_tls_config() {
             Ok(tls_config) => {
@@ -115,7 +173,11 @@ async fn main() -> std::io::Result<()> {
         }
     } else {
         server_builder.bind(&service_ip_and_port)?.run().await
-    }
+    };
+
+    // Store the audit events still queued for the writer thread
+    shutdown_audit.flush();
+    result
 }
 
 // Helper function to load TLS configuration from certificate and key files
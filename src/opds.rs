@@ -0,0 +1,600 @@
+// OPDS 1.2 catalog, so e-reader apps can browse and download books
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse, Result};
+use chrono::{SecondsFormat, Utc};
+use serde::Deserialize;
+use std::sync::RwLock;
+use tracing::error;
+use crate::api::{format_content_type, visible_libraries};
+use crate::db::{Book, BookPage, BookQuery, BookSort, CalibreDb};
+use crate::library::LibraryCache;
+use crate::rbac::{self, PermissionChecker};
+use crate::opds2;
+use crate::search;
+use crate::session::AuthenticatedUser;
+
+const NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPENSEARCH: &str = "application/opensearchdescription+xml";
+
+/// Entries per page of a feed
+pub(crate) const PAGE_SIZE: usize = 50;
+
+/// Highest `?page=` accepted, so page offsets cannot overflow; later pages are just empty
+pub(crate) const MAX_PAGE: usize = usize::MAX / PAGE_SIZE;
+
+/// Width of the thumbnails linked from book entries
+pub(crate) const THUMBNAIL_WIDTH: u32 = 200;
+
+/// Escape text for XML content and attribute values
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode a query string value
+pub(crate) fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn link(rel: &str, href: &str, kind: &str) -> String {
+    format!(r#"<link rel="{}" href="{}" type="{}"/>"#, escape(rel), escape(href), escape(kind))
+}
+
+/// An Atom feed under construction
+struct Feed {
+    id: String,
+    title: String,
+    kind: &'static str,
+    links: Vec<String>,
+    entries: Vec<String>,
+    /// Total results, for paginated feeds
+    total: Option<usize>,
+    start_index: usize,
+}
+
+impl Feed {
+    fn new(id: &str, title: &str, kind: &'static str, self_href: &str) -> Self {
+        Feed {
+            id: id.to_string(),
+            title: title.to_string(),
+            kind,
+            links: vec![link("self", self_href, kind), link("start", "/opds", NAVIGATION)],
+            entries: Vec::new(),
+            total: None,
+            start_index: 1,
+        }
+    }
+
+    fn link(&mut self, rel: &str, href: &str, kind: &str) {
+        self.links.push(link(rel, href, kind));
+    }
+
+    /// Search links of a library, both as OpenSearch description and as direct template
+    fn search_links(&mut self, library_id: &str) {
+        self.link("search", &format!("/opds/libraries/{}/opensearch.xml", library_id), OPENSEARCH);
+        self.link("search", &format!("/opds/libraries/{}/search?q={{searchTerms}}", library_id), ACQUISITION);
+    }
+
+    /// Links to the neighbouring pages of `base`, which already carries any other query parameters
+    fn paginate(&mut self, base: &str, page: usize, total: usize) {
+        let separator = if base.contains('?') { '&' } else { '?' };
+        let pages = total.div_ceil(PAGE_SIZE).max(1);
+        let href = |page: usize| format!("{}{}page={}", base, separator, page);
+        self.link("first", &href(1), self.kind);
+        if page > 1 {
+            self.link("previous", &href(page - 1), self.kind);
+        }
+        if page < pages {
+            self.link("next", &href(page + 1), self.kind);
+        }
+        self.link("last", &href(pages), self.kind);
+        self.total = Some(total);
+        self.start_index = (page - 1) * PAGE_SIZE + 1;
+    }
+
+    fn navigation_entry(&mut self, id: &str, title: &str, content: &str, href: &str, kind: &str) {
+        self.entries.push(format!(
+            r#"<entry><title>{}</title><id>{}</id><updated>{}</updated><content type="text">{}</content>{}</entry>"#,
+            escape(title),
+            escape(id),
+            now(),
+            escape(content),
+            link("subsection", href, kind),
+        ));
+    }
+
+    fn book_entry(&mut self, library_id: &str, book: &Book, can_download: bool) {
+        let mut entry = String::from("<entry>");
+        entry.push_str(&format!("<title>{}</title>", escape(&book.title)));
+        let id = match &book.uuid {
+            Some(uuid) => format!("urn:uuid:{}", uuid),
+            None => format!("urn:biblio:{}:{}", library_id, book.id),
+        };
+        entry.push_str(&format!("<id>{}</id>", escape(&id)));
+        let updated = book.last_modified.clone().or(book.timestamp.clone()).unwrap_or_else(now);
+        entry.push_str(&format!("<updated>{}</updated>", escape(&updated)));
+        for author in &book.authors {
+            entry.push_str(&format!("<author><name>{}</name></author>", escape(author)));
+        }
+        if let Some(language) = &book.language {
+            entry.push_str(&format!("<dc:language>{}</dc:language>", escape(language)));
+        }
+        if let Some(publisher) = &book.publisher {
+            entry.push_str(&format!("<dc:publisher>{}</dc:publisher>", escape(publisher)));
+        }
+        if let Some(pubdate) = &book.pubdate {
+            entry.push_str(&format!("<dc:issued>{}</dc:issued>", escape(pubdate.get(..10).unwrap_or(pubdate))));
+        }
+        if let Some(isbn) = &book.isbn {
+            entry.push_str(&format!("<dc:identifier>urn:isbn:{}</dc:identifier>", escape(isbn)));
+        }
+        for tag in &book.tags {
+            entry.push_str(&format!(r#"<category term="{0}" label="{0}"/>"#, escape(tag)));
+        }
+        if let Some(series) = &book.series {
+            let summary = match book.series_index {
+                Some(index) => format!("{} [{}]", series, index),
+                None => series.clone(),
+            };
+            entry.push_str(&format!("<summary>{}</summary>", escape(&summary)));
+        }
+        if let Some(comments) = &book.comments {
+            entry.push_str(&format!(r#"<content type="html">{}</content>"#, escape(comments)));
+        }
+
+        let book_path = format!("/api/libraries/{}/books/{}", library_id, book.id);
+        if book.has_cover {
+            entry.push_str(&link("http://opds-spec.org/image", &format!("{}/cover", book_path), "image/jpeg"));
+            entry.push_str(&link(
+                "http://opds-spec.org/image/thumbnail",
+                &format!("{}/cover?w={}", book_path, THUMBNAIL_WIDTH),
+                "image/jpeg",
+            ));
+        }
+        if can_download {
+            for format in &book.formats {
+                entry.push_str(&format!(
+                    r#"<link rel="http://opds-spec.org/acquisition" href="{}" type="{}" title="{}"/>"#,
+                    escape(&format!("{}/formats/{}", book_path, format)),
+                    format_content_type(format),
+                    escape(format),
+                ));
+            }
+        }
+        entry.push_str("</entry>");
+        self.entries.push(entry);
+    }
+
+    fn render(&self) -> String {
+        let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        xml.push_str(
+            r#"<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/" xmlns:opds="http://opds-spec.org/2010/catalog" xmlns:opensearch="http://a9.com/-/spec/opensearch/1.1/">"#,
+        );
+        xml.push_str(&format!("<id>{}</id>", escape(&self.id)));
+        xml.push_str(&format!("<title>{}</title>", escape(&self.title)));
+        xml.push_str(&format!("<updated>{}</updated>", now()));
+        xml.push_str("<author><name>Biblio</name></author>");
+        if let Some(total) = self.total {
+            xml.push_str(&format!(
+                "<opensearch:totalResults>{}</opensearch:totalResults><opensearch:itemsPerPage>{}</opensearch:itemsPerPage><opensearch:startIndex>{}</opensearch:startIndex>",
+                total, PAGE_SIZE, self.start_index
+            ));
+        }
+        for link in &self.links {
+            xml.push_str(link);
+        }
+        for entry in &self.entries {
+            xml.push_str(entry);
+        }
+        xml.push_str("</feed>");
+        xml
+    }
+
+    fn respond(&self) -> HttpResponse {
+        HttpResponse::Ok().content_type(self.kind).body(self.render())
+    }
+}
+
+pub(crate) fn not_found(what: &str) -> HttpResponse {
+    HttpResponse::NotFound().content_type("text/plain; charset=utf-8").body(format!("{} not found", what))
+}
+
+pub(crate) fn server_error(e: impl std::fmt::Display) -> HttpResponse {
+    error!("Failed to build OPDS feed: {}", e);
+    HttpResponse::InternalServerError().content_type("text/plain; charset=utf-8").body("Database error")
+}
+
+/// Library scope middleware answering 404 for libraries the user may not see
+pub(crate) async fn require_library_access(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>> {
+    if rbac::can_access_requested_library(&req) {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+    Ok(req.into_response(not_found("Library")).map_into_right_body())
+}
+
+/// A library's database, limited to the books the user may see. Like the helpers
+/// below, a poisoned cache lock gives `None` rather than a panic.
+pub(crate) fn library_db(cache: &RwLock<LibraryCache>, user: &AuthenticatedUser, library_id: &str) -> Option<CalibreDb> {
+    let db = cache.read().ok()?.get_database(library_id).cloned()?;
+    Some(db.restricted(user.account.restrictions.clone()))
+}
+
+pub(crate) fn library_name(cache: &RwLock<LibraryCache>, library_id: &str) -> Option<String> {
+    cache.read().ok()?.get_library(library_id).map(|library| library.name.clone())
+}
+
+/// Root navigation feed listing the libraries
+pub async fn root(user: AuthenticatedUser, cache: web::Data<RwLock<LibraryCache>>) -> Result<HttpResponse> {
+    let mut feed = Feed::new("urn:biblio:root", "Biblio", NAVIGATION, "/opds");
+    for library in web::block(move || visible_libraries(&cache, &user)).await? {
+        feed.navigation_entry(
+            &format!("urn:biblio:{}", library.id),
+            &library.name,
+            &format!("{} books", library.book_count),
+            &format!("/opds/libraries/{}", library.id),
+            NAVIGATION,
+        );
+    }
+    Ok(feed.respond())
+}
+
+/// Navigation feed of one library: the ways its books can be browsed
+pub async fn library(cache: web::Data<RwLock<LibraryCache>>, path: web::Path<String>) -> Result<HttpResponse> {
+    let library_id = path.into_inner();
+    let Some(name) = library_name(&cache, &library_id) else {
+        return Ok(not_found("Library"));
+    };
+
+    let base = format!("/opds/libraries/{}", library_id);
+    let mut feed = Feed::new(&format!("urn:biblio:{}", library_id), &name, NAVIGATION, &base);
+    feed.search_links(&library_id);
+    let sections = [
+        ("recent", "Recently added", "Newest books first", format!("{}/books", base), ACQUISITION),
+        ("title", "Titles", "All books by title", format!("{}/books?sort=title", base), ACQUISITION),
+        ("authors", "Authors", "Books by author", format!("{}/authors", base), NAVIGATION),
+        ("series", "Series", "Books by series", format!("{}/series", base), NAVIGATION),
+        ("tags", "Tags", "Books by tag", format!("{}/tags", base), NAVIGATION),
+    ];
+    for (key, title, content, href, kind) in sections {
+        feed.navigation_entry(&format!("urn:biblio:{}:{}", library_id, key), title, content, &href, kind);
+    }
+    Ok(feed.respond())
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedSort {
+    #[default]
+    Recent,
+    Title,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeedQuery {
+    pub sort: Option<FeedSort>,
+    pub q: Option<String>,
+    pub page: Option<usize>,
+}
+
+impl FeedQuery {
+    fn page(&self) -> usize {
+        self.page.unwrap_or(1).clamp(1, MAX_PAGE)
+    }
+}
+
+/// Load one page of a book query, or the error response to send instead
+pub(crate) async fn load_page(
+    cache: &RwLock<LibraryCache>,
+    user: &AuthenticatedUser,
+    library_id: &str,
+    mut query: BookQuery,
+    page: usize,
+) -> Result<std::result::Result<BookPage, HttpResponse>> {
+    let index = cache.read().ok().and_then(|cache| cache.get_search_index(library_id));
+    let (Some(db), index) = (library_db(cache, user, library_id), index) else {
+        return Ok(Err(not_found("Library")));
+    };
+
+    let expression = match search::prepare_query(index.as_deref(), &mut query) {
+        Ok(expression) => expression,
+        Err(e) => return Ok(Err(HttpResponse::BadRequest().content_type("text/plain; charset=utf-8").body(e))),
+    };
+    query.offset = (page - 1) * PAGE_SIZE;
+    query.limit = PAGE_SIZE;
+
+    Ok(web::block(move || search::books_page(&db, index.as_deref(), query, expression)).await?
+        .map_err(server_error))
+}
+
+/// Whether the user may follow acquisition links
+pub(crate) fn can_download(user: &AuthenticatedUser) -> bool {
+    PermissionChecker::has_permission(&user.role(), "download_books")
+}
+
+/// Build an acquisition feed from one page of a book query
+async fn acquisition_feed(
+    cache: &RwLock<LibraryCache>,
+    user: &AuthenticatedUser,
+    library_id: &str,
+    title: &str,
+    base: &str,
+    query: BookQuery,
+    page: usize,
+) -> Result<HttpResponse> {
+    let books = match load_page(cache, user, library_id, query, page).await? {
+        Ok(books) => books,
+        Err(response) => return Ok(response),
+    };
+    let can_download = can_download(user);
+
+    let self_href = if page > 1 {
+        format!("{}{}page={}", base, if base.contains('?') { '&' } else { '?' }, page)
+    } else {
+        base.to_string()
+    };
+    let mut feed = Feed::new(&format!("urn:biblio:{}", base.trim_start_matches('/')), title, ACQUISITION, &self_href);
+    feed.link("up", &format!("/opds/libraries/{}", library_id), NAVIGATION);
+    feed.search_links(library_id);
+    feed.paginate(base, page, books.total);
+    for book in &books.books {
+        feed.book_entry(library_id, book, can_download);
+    }
+    Ok(feed.respond())
+}
+
+/// All books of a library, newest first or by title
+pub async fn books(
+    cache: web::Data<RwLock<LibraryCache>>,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+    query: web::Query<FeedQuery>,
+) -> Result<HttpResponse> {
+    let library_id = path.into_inner();
+    let Some(name) = library_name(&cache, &library_id) else {
+        return Ok(not_found("Library"));
+    };
+
+    let (sort, descending, title, base) = match query.sort.unwrap_or_default() {
+        FeedSort::Recent => (BookSort::Timestamp, true, format!("{}: Recently added", name), format!("/opds/libraries/{}/books", library_id)),
+        FeedSort::Title => (BookSort::Title, false, format!("{}: Titles", name), format!("/opds/libraries/{}/books?sort=title", library_id)),
+    };
+    let book_query = BookQuery { sort, descending, ..Default::default() };
+    acquisition_feed(&cache, &user, &library_id, &title, &base, book_query, query.page()).await
+}
+
+/// Books matching a search, best match first
+pub async fn search(
+    cache: web::Data<RwLock<LibraryCache>>,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+    query: web::Query<FeedQuery>,
+) -> Result<HttpResponse> {
+    let library_id = path.into_inner();
+    let Some(name) = library_name(&cache, &library_id) else {
+        return Ok(not_found("Library"));
+    };
+
+    let terms = query.q.clone().unwrap_or_default();
+    let base = format!("/opds/libraries/{}/search?q={}", library_id, encode(&terms));
+    let book_query = BookQuery {
+        search: Some(terms.clone()),
+        sort: BookSort::Relevance,
+        ..Default::default()
+    };
+    let title = format!("{}: {}", name, terms);
+    acquisition_feed(&cache, &user, &library_id, &title, &base, book_query, query.page()).await
+}
+
+/// OpenSearch description pointing readers at the search feed
+pub async fn opensearch(cache: web::Data<RwLock<LibraryCache>>, path: web::Path<String>) -> Result<HttpResponse> {
+    let library_id = path.into_inner();
+    let Some(name) = library_name(&cache, &library_id) else {
+        return Ok(not_found("Library"));
+    };
+
+    let xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/"><ShortName>{}</ShortName><Description>Search the {} library</Description><InputEncoding>UTF-8</InputEncoding><OutputEncoding>UTF-8</OutputEncoding><Url type="{}" template="{}"/></OpenSearchDescription>"#,
+        escape(&name.chars().take(16).collect::<String>()),
+        escape(&name),
+        escape(ACQUISITION),
+        escape(&format!("/opds/libraries/{}/search?q={{searchTerms}}", library_id)),
+    );
+    Ok(HttpResponse::Ok().content_type(OPENSEARCH).body(xml))
+}
+
+/// Groupings of books that can be browsed
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Authors,
+    Series,
+    Tags,
+}
+
+impl Category {
+    fn key(&self) -> &'static str {
+        match self {
+            Category::Authors => "authors",
+            Category::Series => "series",
+            Category::Tags => "tags",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Category::Authors => "Authors",
+            Category::Series => "Series",
+            Category::Tags => "Tags",
+        }
+    }
+}
+
+/// Navigation feed listing the authors, series or tags of a library
+pub async fn category(
+    cache: web::Data<RwLock<LibraryCache>>,
+    user: AuthenticatedUser,
+    path: web::Path<(String, Category)>,
+    query: web::Query<FeedQuery>,
+) -> Result<HttpResponse> {
+    let (library_id, category) = path.into_inner();
+    let (Some(name), Some(db)) = (library_name(&cache, &library_id), library_db(&cache, &user, &library_id)) else {
+        return Ok(not_found("Library"));
+    };
+
+    let items = web::block(move || -> rusqlite::Result<Vec<(i32, String, i32)>> {
+        Ok(match category {
+            Category::Authors => db.get_all_authors()?.into_iter().map(|a| (a.id, a.name, a.book_count)).collect(),
+            Category::Series => db.get_all_series()?.into_iter().map(|s| (s.id, s.name, s.book_count)).collect(),
+            Category::Tags => db.get_all_tags()?.into_iter().map(|t| (t.id, t.name, t.book_count)).collect(),
+        })
+    }).await?;
+    let items = match items {
+        Ok(items) => items,
+        Err(e) => return Ok(server_error(e)),
+    };
+
+    let page = query.page();
+    let base = format!("/opds/libraries/{}/{}", library_id, category.key());
+    let self_href = if page > 1 { format!("{}?page={}", base, page) } else { base.clone() };
+    let mut feed = Feed::new(
+        &format!("urn:biblio:{}:{}", library_id, category.key()),
+        &format!("{}: {}", name, category.title()),
+        NAVIGATION,
+        &self_href,
+    );
+    feed.link("up", &format!("/opds/libraries/{}", library_id), NAVIGATION);
+    feed.search_links(&library_id);
+    feed.paginate(&base, page, items.len());
+    for (id, item, book_count) in items.into_iter().skip((page - 1) * PAGE_SIZE).take(PAGE_SIZE) {
+        feed.navigation_entry(
+            &format!("urn:biblio:{}:{}:{}", library_id, category.key(), id),
+            &item,
+            &format!("{} book{}", book_count, if book_count == 1 { "" } else { "s" }),
+            &format!("{}/{}", base, id),
+            ACQUISITION,
+        );
+    }
+    Ok(feed.respond())
+}
+
+/// Books of one author, series or tag
+pub async fn category_books(
+    cache: web::Data<RwLock<LibraryCache>>,
+    user: AuthenticatedUser,
+    path: web::Path<(String, Category, i32)>,
+    query: web::Query<FeedQuery>,
+) -> Result<HttpResponse> {
+    let (library_id, category, item_id) = path.into_inner();
+    let Some(name) = library_name(&cache, &library_id) else {
+        return Ok(not_found("Library"));
+    };
+
+    let book_query = match category {
+        Category::Authors => BookQuery { author_ids: vec![item_id], sort: BookSort::Title, ..Default::default() },
+        Category::Series => BookQuery { series_ids: vec![item_id], sort: BookSort::Series, ..Default::default() },
+        Category::Tags => BookQuery { tag_ids: vec![item_id], sort: BookSort::Title, ..Default::default() },
+    };
+    let base = format!("/opds/libraries/{}/{}/{}", library_id, category.key(), item_id);
+    let title = format!("{}: {}", name, category.title());
+    acquisition_feed(&cache, &user, &library_id, &title, &base, book_query, query.page()).await
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    use actix_web::middleware;
+    use rbac::RequirePermission;
+    use crate::session;
+
+    cfg.service(
+        web::scope("/opds")
+            .wrap(RequirePermission("browse_libraries"))
+            .wrap(middleware::from_fn(session::require_basic_auth))
+            .route("", web::get().to(root))
+            .service(
+                web::scope("/libraries/{id}")
+                    .wrap(middleware::from_fn(require_library_access))
+                    .route("", web::get().to(library))
+                    .route("/books", web::get().to(books))
+                    .route("/search", web::get().to(search))
+                    .route("/opensearch.xml", web::get().to(opensearch))
+                    .route("/{category:authors|series|tags}", web::get().to(category))
+                    .route("/{category:authors|series|tags}/{item_id}", web::get().to(category_books)),
+            )
+            .service(opds2::scope()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_book_entry() {
+        let book = Book {
+            id: 7,
+            title: "Dungeons & Dragons".to_string(),
+            authors: vec!["Ursula K. Le Guin".to_string()],
+            series: Some("Earthsea".to_string()),
+            series_index: Some(1.0),
+            tags: vec!["Fantasy".to_string()],
+            comments: Some("<p>A wizard</p>".to_string()),
+            publisher: None,
+            pubdate: Some("1968-11-01T00:00:00Z".to_string()),
+            rating: None,
+            timestamp: None,
+            language: Some("eng".to_string()),
+            has_cover: true,
+            formats: vec!["EPUB".to_string()],
+            sort: None,
+            last_modified: Some("2024-01-01T00:00:00Z".to_string()),
+            uuid: Some("abc".to_string()),
+            isbn: None,
+            identifiers: Default::default(),
+            custom_columns: Default::default(),
+        };
+
+        let mut feed = Feed::new("urn:test", "Test", ACQUISITION, "/opds/libraries/lib/books");
+        feed.paginate("/opds/libraries/lib/books?sort=title", 2, 120);
+        feed.book_entry("lib", &book, true);
+        let xml = feed.render();
+
+        assert!(xml.contains("<title>Dungeons &amp; Dragons</title>"));
+        assert!(xml.contains("<id>urn:uuid:abc</id>"));
+        assert!(xml.contains(r#"<content type="html">&lt;p&gt;A wizard&lt;/p&gt;</content>"#));
+        assert!(xml.contains("<dc:issued>1968-11-01</dc:issued>"));
+        assert!(xml.contains(r#"href="/api/libraries/lib/books/7/cover?w=200""#));
+        assert!(xml.contains(r#"href="/api/libraries/lib/books/7/formats/EPUB" type="application/epub+zip""#));
+        assert!(xml.contains(r#"<link rel="next" href="/opds/libraries/lib/books?sort=title&amp;page=3""#));
+        assert!(xml.contains("<opensearch:startIndex>51</opensearch:startIndex>"));
+
+        feed.entries.clear();
+        feed.book_entry("lib", &book, false);
+        assert!(!feed.render().contains("opds-spec.org/acquisition"));
+    }
+}
@@ -0,0 +1,512 @@
+// OPDS 2.0 catalog: JSON feeds whose publications are Readium Web Publication Manifests
+use actix_web::{middleware, web, HttpResponse, Result, Scope};
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use crate::api::{format_content_type, visible_libraries};
+use crate::db::{Book, BookQuery, BookSort};
+use crate::library::LibraryCache;
+use crate::opds::{self, FeedSort, MAX_PAGE, PAGE_SIZE, THUMBNAIL_WIDTH};
+use crate::session::AuthenticatedUser;
+
+const FEED: &str = "application/opds+json";
+const PUBLICATION: &str = "application/opds-publication+json";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Link {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rel: Option<&'static str>,
+    href: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    templated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    properties: Option<LinkProperties>,
+}
+
+impl Link {
+    fn new(rel: &'static str, href: impl Into<String>, kind: &'static str) -> Self {
+        Link { rel: Some(rel), href: href.into(), kind, title: None, templated: false, properties: None }
+    }
+
+    /// A titled link, with the number of publications behind it when known
+    fn titled(href: impl Into<String>, kind: &'static str, title: impl Into<String>, count: Option<usize>) -> Self {
+        Link {
+            rel: None,
+            href: href.into(),
+            kind,
+            title: Some(title.into()),
+            templated: false,
+            properties: count.map(|number_of_items| LinkProperties { number_of_items }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LinkProperties {
+    number_of_items: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FeedMetadata {
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    number_of_items: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    items_per_page: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_page: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct Feed {
+    metadata: FeedMetadata,
+    links: Vec<Link>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    navigation: Vec<Link>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    facets: Vec<Facet>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    publications: Vec<Publication>,
+}
+
+impl Feed {
+    fn new(title: impl Into<String>, self_href: impl Into<String>) -> Self {
+        Feed {
+            metadata: FeedMetadata { title: title.into(), number_of_items: None, items_per_page: None, current_page: None },
+            links: vec![Link::new("self", self_href, FEED), Link::new("start", "/opds/v2", FEED)],
+            navigation: Vec::new(),
+            facets: Vec::new(),
+            publications: Vec::new(),
+        }
+    }
+
+    fn search_link(&mut self, library_id: &str) {
+        let mut link = Link::new("search", format!("/opds/v2/libraries/{}/books{{?q}}", library_id), FEED);
+        link.templated = true;
+        self.links.push(link);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Facet {
+    metadata: FacetMetadata,
+    links: Vec<Link>,
+}
+
+#[derive(Debug, Serialize)]
+struct FacetMetadata {
+    title: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct Publication {
+    metadata: PublicationMetadata,
+    links: Vec<Link>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<Image>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PublicationMetadata {
+    #[serde(rename = "@type")]
+    kind: &'static str,
+    identifier: String,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort_as: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    author: Vec<Named>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    publisher: Vec<Named>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    published: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    subject: Vec<Named>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    belongs_to: Option<BelongsTo>,
+}
+
+#[derive(Debug, Serialize)]
+struct Named {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct BelongsTo {
+    series: Vec<SeriesRef>,
+}
+
+#[derive(Debug, Serialize)]
+struct SeriesRef {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Image {
+    href: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+}
+
+fn named(names: &[String]) -> Vec<Named> {
+    names.iter().map(|name| Named { name: name.clone() }).collect()
+}
+
+/// Describe a book as a Readium Web Publication Manifest
+fn publication(library_id: &str, book: &Book, can_download: bool) -> Publication {
+    let book_path = format!("/api/libraries/{}/books/{}", library_id, book.id);
+
+    let mut links = vec![Link::new("self", format!("/opds/v2/libraries/{}/books/{}", library_id, book.id), PUBLICATION)];
+    if can_download {
+        links.extend(book.formats.iter().map(|format| Link {
+            title: Some(format.clone()),
+            ..Link::new("http://opds-spec.org/acquisition", format!("{}/formats/{}", book_path, format), format_content_type(format))
+        }));
+    }
+
+    let images = if book.has_cover {
+        vec![
+            Image { href: format!("{}/cover", book_path), kind: "image/jpeg", width: None },
+            Image { href: format!("{}/cover?w={}", book_path, THUMBNAIL_WIDTH), kind: "image/jpeg", width: Some(THUMBNAIL_WIDTH) },
+        ]
+    } else {
+        Vec::new()
+    };
+
+    Publication {
+        metadata: PublicationMetadata {
+            kind: "http://schema.org/Book",
+            identifier: match &book.uuid {
+                Some(uuid) => format!("urn:uuid:{}", uuid),
+                None => format!("urn:biblio:{}:{}", library_id, book.id),
+            },
+            title: book.title.clone(),
+            sort_as: book.sort.clone(),
+            author: named(&book.authors),
+            publisher: named(book.publisher.as_slice()),
+            language: book.language.clone(),
+            published: book.pubdate.as_ref().map(|date| date.get(..10).unwrap_or(date).to_string()),
+            modified: book.last_modified.clone(),
+            description: book.comments.clone(),
+            subject: named(&book.tags),
+            belongs_to: book.series.as_ref().map(|name| BelongsTo {
+                series: vec![SeriesRef { name: name.clone(), position: book.series_index }],
+            }),
+        },
+        links,
+        images,
+    }
+}
+
+fn respond<T: Serialize>(kind: &'static str, body: &T) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().content_type(kind).body(serde_json::to_string(body)?))
+}
+
+/// Root feed navigating to the libraries
+pub async fn root(user: AuthenticatedUser, cache: web::Data<RwLock<LibraryCache>>) -> Result<HttpResponse> {
+    let mut feed = Feed::new("Biblio", "/opds/v2");
+    for library in web::block(move || visible_libraries(&cache, &user)).await? {
+        feed.navigation.push(Link::titled(
+            format!("/opds/v2/libraries/{}", library.id),
+            FEED,
+            library.name.clone(),
+            Some(library.book_count),
+        ));
+    }
+    respond(FEED, &feed)
+}
+
+/// Navigation feed of one library
+pub async fn library(cache: web::Data<RwLock<LibraryCache>>, path: web::Path<String>) -> Result<HttpResponse> {
+    let library_id = path.into_inner();
+    let Some(name) = opds::library_name(&cache, &library_id) else {
+        return Ok(opds::not_found("Library"));
+    };
+
+    let base = format!("/opds/v2/libraries/{}", library_id);
+    let mut feed = Feed::new(name, base.clone());
+    feed.links.push(Link::new("up", "/opds/v2", FEED));
+    feed.search_link(&library_id);
+    feed.navigation.push(Link::titled(format!("{}/books", base), FEED, "Recently added", None));
+    feed.navigation.push(Link::titled(format!("{}/books?sort=title", base), FEED, "Titles", None));
+    respond(FEED, &feed)
+}
+
+/// Search, filters and page of a publications feed, one value per facet
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FeedQuery {
+    pub q: Option<String>,
+    pub sort: Option<FeedSort>,
+    pub author: Option<i32>,
+    pub series: Option<i32>,
+    pub tag: Option<i32>,
+    pub language: Option<String>,
+    pub format: Option<String>,
+    pub page: Option<usize>,
+}
+
+impl FeedQuery {
+    fn page(&self) -> usize {
+        self.page.unwrap_or(1).clamp(1, MAX_PAGE)
+    }
+
+    fn href(&self, library_id: &str) -> String {
+        let mut params = Vec::new();
+        if let Some(q) = &self.q {
+            params.push(format!("q={}", opds::encode(q)));
+        }
+        match self.sort {
+            Some(FeedSort::Recent) => params.push("sort=recent".to_string()),
+            Some(FeedSort::Title) => params.push("sort=title".to_string()),
+            None => {}
+        }
+        for (key, id) in [("author", self.author), ("series", self.series), ("tag", self.tag)] {
+            if let Some(id) = id {
+                params.push(format!("{}={}", key, id));
+            }
+        }
+        for (key, value) in [("language", &self.language), ("format", &self.format)] {
+            if let Some(value) = value {
+                params.push(format!("{}={}", key, opds::encode(value)));
+            }
+        }
+        if let Some(page) = self.page.filter(|page| *page > 1) {
+            params.push(format!("page={}", page));
+        }
+
+        let base = format!("/opds/v2/libraries/{}/books", library_id);
+        if params.is_empty() { base } else { format!("{}?{}", base, params.join("&")) }
+    }
+
+    fn book_query(&self) -> BookQuery {
+        let searching = self.q.as_deref().is_some_and(|q| !q.trim().is_empty());
+        let (sort, descending) = match self.sort {
+            Some(FeedSort::Title) => (BookSort::Title, false),
+            Some(FeedSort::Recent) => (BookSort::Timestamp, true),
+            None if searching => (BookSort::Relevance, false),
+            None if self.series.is_some() => (BookSort::Series, false),
+            None => (BookSort::Timestamp, true),
+        };
+        BookQuery {
+            search: self.q.clone(),
+            author_ids: self.author.into_iter().collect(),
+            series_ids: self.series.into_iter().collect(),
+            tag_ids: self.tag.into_iter().collect(),
+            languages: self.language.iter().cloned().collect(),
+            formats: self.format.iter().cloned().collect(),
+            sort,
+            descending,
+            ..Default::default()
+        }
+    }
+
+    /// The same feed on another page
+    fn with_page(&self, page: usize) -> FeedQuery {
+        FeedQuery { page: Some(page), ..self.clone() }
+    }
+}
+
+/// Facet values as (value, title, number of books), with the library-wide counts
+struct FacetValues {
+    authors: Vec<(i32, String, usize)>,
+    series: Vec<(i32, String, usize)>,
+    tags: Vec<(i32, String, usize)>,
+    languages: Vec<(String, usize)>,
+    formats: Vec<(String, usize)>,
+}
+
+/// One facet group: a link clearing the facet followed by a link per value
+fn facet<T: PartialEq>(
+    title: &'static str,
+    query: &FeedQuery,
+    library_id: &str,
+    active: Option<T>,
+    values: impl IntoIterator<Item = (T, String, usize)>,
+    select: impl Fn(&mut FeedQuery, Option<T>),
+) -> Facet {
+    let link = |value: Option<T>, title: String, count: Option<usize>| {
+        let is_active = value == active;
+        let mut target = FeedQuery { page: None, ..query.clone() };
+        select(&mut target, value);
+        let mut link = Link::titled(target.href(library_id), FEED, title, count);
+        if is_active {
+            link.rel = Some("self");
+        }
+        link
+    };
+
+    let mut links = vec![link(None, "All".to_string(), None)];
+    links.extend(values.into_iter().map(|(value, title, count)| link(Some(value), title, Some(count))));
+    Facet { metadata: FacetMetadata { title }, links }
+}
+
+/// Publications feed with facets and pagination
+pub async fn books(
+    cache: web::Data<RwLock<LibraryCache>>,
+    user: AuthenticatedUser,
+    path: web::Path<String>,
+    query: web::Query<FeedQuery>,
+) -> Result<HttpResponse> {
+    let library_id = path.into_inner();
+    let query = query.into_inner();
+    let (Some(name), Some(db)) = (opds::library_name(&cache, &library_id), opds::library_db(&cache, &user, &library_id)) else {
+        return Ok(opds::not_found("Library"));
+    };
+
+    let facet_values = web::block(move || -> rusqlite::Result<FacetValues> {
+        Ok(FacetValues {
+            authors: db.get_all_authors()?.into_iter().map(|a| (a.id, a.name, a.book_count as usize)).collect(),
+            series: db.get_all_series()?.into_iter().map(|s| (s.id, s.name, s.book_count as usize)).collect(),
+            tags: db.get_all_tags()?.into_iter().map(|t| (t.id, t.name, t.book_count as usize)).collect(),
+            languages: db.get_all_languages()?.into_iter().map(|l| (l.lang_code, l.book_count as usize)).collect(),
+            formats: db.get_all_formats()?.into_iter().map(|f| (f.format, f.book_count as usize)).collect(),
+        })
+    });
+    let page = query.page();
+    let books = match opds::load_page(&cache, &user, &library_id, query.book_query(), page).await? {
+        Ok(books) => books,
+        Err(response) => return Ok(response),
+    };
+    let facet_values = match facet_values.await? {
+        Ok(values) => values,
+        Err(e) => return Ok(opds::server_error(e)),
+    };
+
+    let title = match (&query.q, query.sort) {
+        (Some(q), _) => format!("{}: {}", name, q),
+        (None, Some(FeedSort::Title)) => format!("{}: Titles", name),
+        (None, _) => format!("{}: Recently added", name),
+    };
+    let mut feed = Feed::new(title, query.href(&library_id));
+    feed.metadata.number_of_items = Some(books.total);
+    feed.metadata.items_per_page = Some(PAGE_SIZE);
+    feed.metadata.current_page = Some(page);
+    feed.links.push(Link::new("up", format!("/opds/v2/libraries/{}", library_id), FEED));
+    feed.search_link(&library_id);
+
+    let pages = books.total.div_ceil(PAGE_SIZE).max(1);
+    feed.links.push(Link::new("first", query.with_page(1).href(&library_id), FEED));
+    if page > 1 {
+        feed.links.push(Link::new("previous", query.with_page(page - 1).href(&library_id), FEED));
+    }
+    if page < pages {
+        feed.links.push(Link::new("next", query.with_page(page + 1).href(&library_id), FEED));
+    }
+    feed.links.push(Link::new("last", query.with_page(pages).href(&library_id), FEED));
+
+    let FacetValues { authors, series, tags, languages, formats } = facet_values;
+    let keyed = |values: Vec<(String, usize)>| values.into_iter().map(|(value, count)| (value.clone(), value, count));
+    feed.facets = vec![
+        facet("Tags", &query, &library_id, query.tag, tags, |q, v| q.tag = v),
+        facet("Series", &query, &library_id, query.series, series, |q, v| q.series = v),
+        facet("Authors", &query, &library_id, query.author, authors, |q, v| q.author = v),
+        facet("Language", &query, &library_id, query.language.clone(), keyed(languages), |q, v| q.language = v),
+        facet("Format", &query, &library_id, query.format.as_ref().map(|f| f.to_uppercase()), keyed(formats), |q, v| q.format = v),
+    ];
+
+    let can_download = opds::can_download(&user);
+    feed.publications = books.books.iter().map(|book| publication(&library_id, book, can_download)).collect();
+    respond(FEED, &feed)
+}
+
+/// Manifest of a single publication
+pub async fn book(
+    cache: web::Data<RwLock<LibraryCache>>,
+    user: AuthenticatedUser,
+    path: web::Path<(String, i32)>,
+) -> Result<HttpResponse> {
+    let (library_id, book_id) = path.into_inner();
+    let Some(db) = opds::library_db(&cache, &user, &library_id) else {
+        return Ok(opds::not_found("Library"));
+    };
+
+    match web::block(move || db.get_book(book_id)).await? {
+        Ok(Some(book)) => respond(PUBLICATION, &publication(&library_id, &book, opds::can_download(&user))),
+        Ok(None) => Ok(opds::not_found("Book")),
+        Err(e) => Ok(opds::server_error(e)),
+    }
+}
+
+/// Routes of the OPDS 2.0 catalog, nested in the `/opds` scope for its authentication
+pub fn scope() -> Scope {
+    web::scope("/v2")
+        .route("", web::get().to(root))
+        .service(
+            web::scope("/libraries/{id}")
+                .wrap(middleware::from_fn(opds::require_library_access))
+                .route("", web::get().to(library))
+                .route("/books", web::get().to(books))
+                .route("/books/{book_id}", web::get().to(book)),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publication_and_facet_links() {
+        let book = Book {
+            id: 7,
+            title: "A Wizard of Earthsea".to_string(),
+            authors: vec!["Ursula K. Le Guin".to_string()],
+            series: Some("Earthsea".to_string()),
+            series_index: Some(1.0),
+            tags: vec!["Fantasy".to_string()],
+            comments: None,
+            publisher: Some("Parnassus".to_string()),
+            pubdate: Some("1968-11-01T00:00:00Z".to_string()),
+            rating: None,
+            timestamp: None,
+            language: Some("eng".to_string()),
+            has_cover: true,
+            formats: vec!["EPUB".to_string(), "PDF".to_string()],
+            sort: None,
+            last_modified: None,
+            uuid: Some("abc".to_string()),
+            isbn: None,
+            identifiers: Default::default(),
+            custom_columns: Default::default(),
+        };
+
+        let json = serde_json::to_value(publication("lib", &book, true)).unwrap();
+        assert_eq!(json["metadata"]["@type"], "http://schema.org/Book");
+        assert_eq!(json["metadata"]["identifier"], "urn:uuid:abc");
+        assert_eq!(json["metadata"]["published"], "1968-11-01");
+        assert_eq!(json["metadata"]["belongsTo"]["series"][0]["position"], 1.0);
+        assert_eq!(json["links"][1]["href"], "/api/libraries/lib/books/7/formats/EPUB");
+        assert_eq!(json["links"][2]["type"], "application/pdf");
+        assert_eq!(json["images"][1]["width"], THUMBNAIL_WIDTH);
+        assert!(json["metadata"].get("description").is_none());
+
+        let json = serde_json::to_value(publication("lib", &book, false)).unwrap();
+        assert_eq!(json["links"].as_array().unwrap().len(), 1);
+
+        // Selecting a facet value keeps the other filters and starts over on the first page
+        let query = FeedQuery { q: Some("le guin".to_string()), tag: Some(3), page: Some(4), ..Default::default() };
+        let tags = facet("Tags", &query, "lib", query.tag, vec![(3, "Fantasy".to_string(), 12), (5, "SF".to_string(), 2)], |q, v| q.tag = v);
+        let links = serde_json::to_value(&tags.links).unwrap();
+        assert_eq!(links[0]["href"], "/opds/v2/libraries/lib/books?q=le%20guin");
+        assert_eq!(links[1]["rel"], "self");
+        assert_eq!(links[1]["properties"]["numberOfItems"], 12);
+        assert_eq!(links[2]["href"], "/opds/v2/libraries/lib/books?q=le%20guin&tag=5");
+        assert_eq!(query.with_page(2).href("lib"), "/opds/v2/libraries/lib/books?q=le%20guin&tag=3&page=2");
+    }
+}
@@ -0,0 +1,111 @@
+// Pool of SQLite connections to one database file
+use rusqlite::{Connection, OpenFlags, Result as SqlResult};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Idle connections kept open per database. Busy moments open extra
+/// connections, which are closed instead of returned once the pool is full.
+const MAX_IDLE: usize = 4;
+
+pub struct ConnectionPool {
+    path: PathBuf,
+    flags: OpenFlags,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl ConnectionPool {
+    /// Open a pool of read-only connections, as used for Calibre databases
+    pub fn open<P: AsRef<Path>>(path: P) -> SqlResult<Self> {
+        Self::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+    }
+
+    /// Open a pool of connections that may write, creating the database if needed
+    pub fn open_read_write<P: AsRef<Path>>(path: P) -> SqlResult<Self> {
+        Self::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE)
+    }
+
+    /// Open the first connection right away so a missing or corrupt database is reported early
+    fn open_with_flags<P: AsRef<Path>>(path: P, flags: OpenFlags) -> SqlResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let conn = Connection::open_with_flags(&path, flags)?;
+        Ok(ConnectionPool {
+            path,
+            flags,
+            idle: Mutex::new(vec![conn]),
+        })
+    }
+
+    /// Take an idle connection, or open a new one when all are in use
+    pub fn get(&self) -> SqlResult<PooledConnection<'_>> {
+        let idle = self.idle.lock().unwrap().pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => Connection::open_with_flags(&self.path, self.flags)?,
+        };
+        Ok(PooledConnection {
+            pool: self,
+            conn: Some(conn),
+        })
+    }
+}
+
+/// A connection borrowed from a `ConnectionPool`, handed back when dropped
+pub struct PooledConnection<'a> {
+    pool: &'a ConnectionPool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let mut idle = self.pool.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE {
+                idle.push(conn);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_reuses_connections() {
+        let dir = std::env::temp_dir().join(format!("biblio-pool-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("metadata.db");
+        Connection::open(&path).unwrap().execute_batch("CREATE TABLE books (id INTEGER PRIMARY KEY)").unwrap();
+
+        let pool = ConnectionPool::open(&path).unwrap();
+        {
+            // A second concurrent borrower gets a connection of its own
+            let first = pool.get().unwrap();
+            let second = pool.get().unwrap();
+            assert!(pool.idle.lock().unwrap().is_empty());
+            let count: i64 = second.query_row("SELECT COUNT(*) FROM books", [], |row| row.get(0)).unwrap();
+            assert_eq!(count, 0);
+            drop(first);
+        }
+        assert_eq!(pool.idle.lock().unwrap().len(), 2);
+
+        // Connections are read-only
+        assert!(pool.get().unwrap().execute("INSERT INTO books (id) VALUES (1)", []).is_err());
+
+        // Only MAX_IDLE connections are kept around
+        let borrowed: Vec<_> = (0..MAX_IDLE + 2).map(|_| pool.get().unwrap()).collect();
+        drop(borrowed);
+        assert_eq!(pool.idle.lock().unwrap().len(), MAX_IDLE);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,21 @@
 // Role-based access control
-#![allow(dead_code)]
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
 use serde::{Deserialize, Serialize};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::RwLock;
+use std::task::{Context, Poll};
+use crate::api::ApiResponse;
+use crate::audit::{client_ip, AuditEventType, AuditLogger};
+use crate::auth::User;
+use crate::config::{self, LibraryAccessConfig};
+use crate::library::LibraryCache;
+use crate::session::AuthenticatedUser;
+use crate::users::{self, StoreResult};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UserRole {
@@ -55,12 +70,6 @@ impl UserRole {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Permission {
-    pub name: String,
-    pub description: String,
-}
-
 pub struct PermissionChecker;
 
 impl PermissionChecker {
@@ -91,3 +100,210 @@ impl PermissionChecker {
         }
     }
 }
+
+/// The libraries a user may see under `library_access`
+#[derive(Debug, Clone)]
+pub struct LibraryAccess {
+    /// Allowed library ids and names; `None` allows every library
+    allowed: Option<Vec<String>>,
+}
+
+impl LibraryAccess {
+    /// Libraries the account of an authenticated request may see
+    pub fn for_account(user: &User) -> Self {
+        let role = UserRole::from_str(&user.role);
+        Self::resolve(&config::library_access(), &user.username, Some(role), user.libraries.as_ref())
+    }
+
+    /// Looks the user up in the user store, for callers without an `AuthenticatedUser`;
+    /// `None` once the user is deleted. Store errors are returned rather than treated
+    /// as a missing user or no restrictions.
+    pub fn for_user(username: &str) -> StoreResult<Option<Self>> {
+        Ok(users::store().get_user(username)?.map(|user| Self::for_account(&user)))
+    }
+
+    /// `own` is the user's entry in the user store, which takes precedence over config.yaml
+    fn resolve(rules: &LibraryAccessConfig, username: &str, role: Option<UserRole>, own: Option<&Vec<String>>) -> Self {
+        let allowed = match role {
+            Some(UserRole::Admin) => None,
+            // Deleted accounts whose session is still around see nothing
+            None => Some(Vec::new()),
+            Some(role) => own
+                .or_else(|| rules.users.get(username))
+                .or_else(|| {
+                    let role = role.to_string();
+                    rules.roles.iter().find(|(name, _)| name.eq_ignore_ascii_case(&role)).map(|(_, l)| l)
+                })
+                .filter(|libraries| !libraries.iter().any(|l| l == "*"))
+                .cloned(),
+        };
+        LibraryAccess { allowed }
+    }
+
+    pub fn allows(&self, library_id: &str, library_name: &str) -> bool {
+        match &self.allowed {
+            None => true,
+            Some(allowed) => allowed.iter().any(|l| l == library_id || l == library_name),
+        }
+    }
+}
+
+/// Whether the authenticated user may see the library in the `{id}` segment of
+/// the request path. Libraries that do not exist are left to the handler.
+pub fn can_access_requested_library(req: &ServiceRequest) -> bool {
+    let Some(library_id) = req.match_info().get("id") else {
+        return true;
+    };
+    let Some(access) = req.extensions().get::<AuthenticatedUser>().map(|u| LibraryAccess::for_account(&u.account)) else {
+        return false;
+    };
+    let Some(cache) = req.app_data::<web::Data<RwLock<LibraryCache>>>() else {
+        return false;
+    };
+    // Deny rather than panic when a writer panicked while holding the cache
+    let Ok(cache) = cache.read() else {
+        return false;
+    };
+    match cache.get_library(library_id) {
+        Some(library) => access.allows(library_id, &library.name),
+        None => true,
+    }
+}
+
+/// Scope middleware for `/libraries/{id}` routes answering 404 for libraries
+/// the user may not see, so they cannot be told apart from missing ones. Must
+/// run inside `session::require_session`.
+pub async fn require_library_access(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if can_access_requested_library(&req) {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+    let response = HttpResponse::NotFound().json(ApiResponse::<()> {
+        success: false,
+        data: None,
+        error: Some("Library not found".to_string()),
+    });
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// Route middleware that only lets the request through if the session user's
+/// role grants the named permission. Must run inside `session::require_session`.
+///
+/// ```ignore
+/// web::post().to(refresh_libraries).wrap(RequirePermission("manage_libraries"))
+/// ```
+#[derive(Clone, Copy)]
+pub struct RequirePermission(pub &'static str);
+
+impl<S, B> Transform<S, ServiceRequest> for RequirePermission
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequirePermissionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequirePermissionMiddleware {
+            service: Rc::new(service),
+            permission: self.0,
+        }))
+    }
+}
+
+pub struct RequirePermissionMiddleware<S> {
+    service: Rc<S>,
+    permission: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequirePermissionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let permission = self.permission;
+
+        Box::pin(async move {
+            let caller = req
+                .extensions()
+                .get::<AuthenticatedUser>()
+                .map(|user| (user.username.clone(), user.role()));
+
+            let allowed = match &caller {
+                Some((_, role)) => PermissionChecker::require_permission(role, permission),
+                None => Err(format!("Permission denied: {}", permission)),
+            };
+            let username = caller.map(|(username, _)| username);
+
+            match allowed {
+                Ok(()) => service.call(req).await.map(ServiceResponse::map_into_left_body),
+                Err(e) => {
+                    if let Some(audit_logger) = req.app_data::<web::Data<AuditLogger>>() {
+                        audit_logger.log_event(
+                            AuditEventType::PermissionDenied,
+                            username.as_deref().unwrap_or("unknown"),
+                            &client_ip(req.request()),
+                            &format!("{} on {} {}", e, req.method(), req.path()),
+                            false,
+                        );
+                    }
+
+                    let response = HttpResponse::Forbidden().json(ApiResponse::<()> {
+                        success: false,
+                        data: None,
+                        error: Some(e),
+                    });
+                    Ok(req.into_response(response).map_into_right_body())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_library_access() {
+        let mut rules = LibraryAccessConfig::default();
+        rules.roles.insert("Reader".to_string(), vec!["Kids".to_string()]);
+        rules.roles.insert("user".to_string(), vec!["*".to_string()]);
+        rules.users.insert("alice".to_string(), vec!["Kids".to_string(), "0f3c".to_string()]);
+
+        let reader = LibraryAccess::resolve(&rules, "bob", Some(UserRole::Reader), None);
+        assert!(reader.allows("1a2b", "Kids"));
+        assert!(!reader.allows("0f3c", "Restricted"));
+
+        // A user entry replaces the role entry, and matches ids as well as names
+        let alice = LibraryAccess::resolve(&rules, "alice", Some(UserRole::User), None);
+        assert!(alice.allows("0f3c", "Restricted"));
+        assert!(!alice.allows("9d8e", "Fiction"));
+
+        assert!(LibraryAccess::resolve(&rules, "carol", Some(UserRole::User), None).allows("0f3c", "Restricted"));
+        assert!(LibraryAccess::resolve(&rules, "dave", Some(UserRole::Librarian), None).allows("0f3c", "Restricted"));
+        assert!(LibraryAccess::resolve(&rules, "alice", Some(UserRole::Admin), None).allows("9d8e", "Fiction"));
+        assert!(!LibraryAccess::resolve(&rules, "gone", None, None).allows("1a2b", "Kids"));
+
+        // An entry in the user store replaces the one in config.yaml
+        let own = vec!["Fiction".to_string()];
+        let alice = LibraryAccess::resolve(&rules, "alice", Some(UserRole::User), Some(&own));
+        assert!(alice.allows("9d8e", "Fiction"));
+        assert!(!alice.allows("0f3c", "Restricted"));
+    }
+}
@@ -0,0 +1,550 @@
+// Full-text search over book metadata, kept in an FTS5 index per library
+use rusqlite::{Connection, OptionalExtension, Result as SqlResult};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::info;
+use crate::content::{Chapter, html_to_text};
+use crate::db::{Book, BookPage, BookQuery, CalibreDb};
+use crate::library;
+use crate::pool::{ConnectionPool, PooledConnection};
+
+/// Bumped whenever the indexed columns change, forcing a rebuild
+const SCHEMA_VERSION: &str = "1";
+
+/// Books loaded from the Calibre database per batch while rebuilding
+const REBUILD_BATCH: usize = 1000;
+
+/// How long a statement waits for a lock held by another connection. Biblio's own
+/// writes take turns on `SearchIndex::writer`, so a long rebuild never runs into it.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Content rows of a book use rowids `book_id * CHAPTER_SLOTS + chapter`,
+/// so a book's rows can be replaced without scanning the table
+const CHAPTER_SLOTS: i64 = 100_000;
+
+/// Markers around matched words in content snippets, swapped for `<mark>` once the text is escaped
+const MATCH_START: char = '\u{1}';
+const MATCH_END: char = '\u{2}';
+
+/// bm25 weights of the indexed columns, in table order: a hit in the title
+/// outranks one in the authors, which outranks one in the description
+const COLUMN_WEIGHTS: &str = "10.0, 8.0, 5.0, 4.0, 2.0, 1.0, 6.0";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS index_state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE VIRTUAL TABLE IF NOT EXISTS books_fts USING fts5(
+        title, authors, series, tags, publisher, comments, identifiers,
+        tokenize = 'unicode61 remove_diacritics 2',
+        prefix = '2 3'
+    );
+    CREATE TABLE IF NOT EXISTS content_books (
+        book INTEGER PRIMARY KEY,
+        stamp TEXT NOT NULL,
+        chapters INTEGER NOT NULL,
+        error TEXT
+    );
+    CREATE VIRTUAL TABLE IF NOT EXISTS content_fts USING fts5(
+        text, location UNINDEXED, heading UNINDEXED,
+        tokenize = 'unicode61 remove_diacritics 2'
+    );
+";
+
+/// Query prefixes accepted by `parse_query` and the index column each one searches
+const FIELDS: [(&str, &str); 14] = [
+    ("title", "title"),
+    ("author", "authors"),
+    ("authors", "authors"),
+    ("series", "series"),
+    ("tag", "tags"),
+    ("tags", "tags"),
+    ("publisher", "publisher"),
+    ("comment", "comments"),
+    ("comments", "comments"),
+    ("description", "comments"),
+    ("isbn", "identifiers"),
+    ("identifier", "identifiers"),
+    ("identifiers", "identifiers"),
+    ("id", "identifiers"),
+];
+
+/// Search index of one library, stored in a sidecar database owned by biblio
+/// and rebuilt whenever the library's `metadata.db` changes
+pub struct SearchIndex {
+    pool: ConnectionPool,
+    metadata_db_path: PathBuf,
+    /// Held by every write, so rebuilds and content indexing queue up instead
+    /// of waiting on SQLite's busy timeout
+    writer: Mutex<()>,
+}
+
+impl SearchIndex {
+    pub fn open<P: AsRef<Path>>(index_path: P, metadata_db_path: &Path) -> SqlResult<Self> {
+        let index_path = index_path.as_ref();
+        if let Some(parent) = index_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+
+        let pool = ConnectionPool::open_read_write(index_path)?;
+        {
+            let conn = pool.get()?;
+            // WAL lets searches keep reading the previous index during a rebuild
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            conn.execute_batch(SCHEMA)?;
+
+            let version: Option<String> = conn.query_row(
+                "SELECT value FROM index_state WHERE key = 'schema_version'",
+                [],
+                |row| row.get(0),
+            ).optional()?;
+            if version.as_deref() != Some(SCHEMA_VERSION) {
+                conn.execute_batch(&format!(
+                    "DROP TABLE books_fts;
+                     DROP TABLE content_fts;
+                     DROP TABLE content_books;
+                     DELETE FROM index_state;
+                     {SCHEMA}
+                     INSERT INTO index_state (key, value) VALUES ('schema_version', '{SCHEMA_VERSION}');"
+                ))?;
+            }
+        }
+
+        Ok(SearchIndex {
+            pool,
+            metadata_db_path: metadata_db_path.to_path_buf(),
+            writer: Mutex::new(()),
+        })
+    }
+
+    fn conn(&self) -> SqlResult<PooledConnection<'_>> {
+        let conn = self.pool.get()?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(conn)
+    }
+
+    /// Modification time and size of `metadata.db` and its WAL, recorded with each
+    /// rebuild; the same stamp the library cache uses
+    fn source_stamp(&self) -> String {
+        library::database_stamp(&self.metadata_db_path)
+    }
+
+    fn indexed_stamp(&self) -> SqlResult<Option<String>> {
+        self.conn()?.query_row(
+            "SELECT value FROM index_state WHERE key = 'source_stamp'",
+            [],
+            |row| row.get(0),
+        ).optional()
+    }
+
+    /// Rebuild the index if `metadata.db` changed since it was last built.
+    /// Returns whether a rebuild happened.
+    pub fn ensure_fresh(&self, db: &CalibreDb) -> SqlResult<bool> {
+        let stamp = self.source_stamp();
+        if self.indexed_stamp()?.as_deref() == Some(stamp.as_str()) {
+            return Ok(false);
+        }
+
+        // Concurrent callers wait for one rebuild instead of each running their own
+        let _writing = self.writer.lock().unwrap();
+        if self.indexed_stamp()?.as_deref() == Some(stamp.as_str()) {
+            return Ok(false);
+        }
+
+        let conn = self.conn()?;
+        let tx = conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM books_fts", [])?;
+        let ids = db.book_ids()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO books_fts (rowid, title, authors, series, tags, publisher, comments, identifiers)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for batch in ids.chunks(REBUILD_BATCH) {
+                for book in db.load_books(batch)? {
+                    insert.execute(rusqlite::params![
+                        book.id,
+                        book.title,
+                        author_terms(&book),
+                        book.series,
+                        book.tags.join(" "),
+                        book.publisher,
+                        book.comments.as_deref().map(|html| html_to_text(html).text),
+                        identifier_terms(&book),
+                    ])?;
+                }
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO index_state (key, value) VALUES ('source_stamp', ?)",
+            [&stamp],
+        )?;
+        tx.commit()?;
+
+        info!("Indexed {} book(s) from {:?} for search", ids.len(), self.metadata_db_path);
+        Ok(true)
+    }
+
+    /// Ids of the books matching an FTS5 expression from `parse_query`, best match first
+    pub fn search(&self, expression: &str) -> SqlResult<Vec<i32>> {
+        Ok(self.search_ranked(expression)?.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// Like `search`, along with each book's bm25 score; lower scores are better matches
+    pub fn search_ranked(&self, expression: &str) -> SqlResult<Vec<(i32, f64)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT rowid, bm25(books_fts, {COLUMN_WEIGHTS}) AS score
+             FROM books_fts WHERE books_fts MATCH ?
+             ORDER BY score, rowid"
+        ))?;
+        let hits = stmt.query_map([expression], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<SqlResult<Vec<_>>>()?;
+        Ok(hits)
+    }
+
+    /// File stamp of every book whose contents were indexed, and whether its text could be read
+    pub fn content_stamps(&self) -> SqlResult<HashMap<i32, (String, bool)>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT book, stamp, error IS NULL FROM content_books")?;
+        let stamps = stmt.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?
+            .collect::<SqlResult<HashMap<_, _>>>()?;
+        Ok(stamps)
+    }
+
+    /// Replace the indexed contents of a book. Books whose file could not be read
+    /// are stored without chapters so they are not retried until the file changes.
+    pub fn store_content(&self, book_id: i32, stamp: &str, chapters: &[Chapter], error: Option<&str>) -> SqlResult<()> {
+        let _writing = self.writer.lock().unwrap();
+        let conn = self.conn()?;
+        let tx = conn.unchecked_transaction()?;
+        Self::delete_content(&tx, book_id)?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO content_fts (rowid, text, location, heading) VALUES (?, ?, ?, ?)",
+            )?;
+            for (chapter, content) in chapters.iter().take(CHAPTER_SLOTS as usize).enumerate() {
+                insert.execute(rusqlite::params![
+                    book_id as i64 * CHAPTER_SLOTS + chapter as i64,
+                    content.text,
+                    content.location,
+                    content.heading,
+                ])?;
+            }
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO content_books (book, stamp, chapters, error) VALUES (?, ?, ?, ?)",
+            rusqlite::params![book_id, stamp, chapters.len() as i64, error],
+        )?;
+        tx.commit()
+    }
+
+    /// Forget the contents of a book that left the library
+    pub fn remove_content(&self, book_id: i32) -> SqlResult<()> {
+        let _writing = self.writer.lock().unwrap();
+        let conn = self.conn()?;
+        let tx = conn.unchecked_transaction()?;
+        Self::delete_content(&tx, book_id)?;
+        tx.execute("DELETE FROM content_books WHERE book = ?", [book_id])?;
+        tx.commit()
+    }
+
+    fn delete_content(conn: &Connection, book_id: i32) -> SqlResult<()> {
+        let first = book_id as i64 * CHAPTER_SLOTS;
+        conn.execute(
+            "DELETE FROM content_fts WHERE rowid BETWEEN ? AND ?",
+            [first, first + CHAPTER_SLOTS - 1],
+        )?;
+        Ok(())
+    }
+
+    /// Chapters matching an expression from `parse_content_query`, best match first.
+    /// Snippets are HTML with matched words wrapped in `<mark>`.
+    pub fn search_content(&self, expression: &str, limit: usize) -> SqlResult<Vec<ContentMatch>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT rowid, location, heading, snippet(content_fts, 0, char(1), char(2), '…', 24)
+             FROM content_fts WHERE content_fts MATCH ?
+             ORDER BY bm25(content_fts), rowid
+             LIMIT ?",
+        )?;
+        let matches = stmt.query_map(rusqlite::params![expression, limit as i64], |row| {
+            let rowid: i64 = row.get(0)?;
+            let snippet: String = row.get(3)?;
+            Ok(ContentMatch {
+                book_id: (rowid / CHAPTER_SLOTS) as i32,
+                chapter: (rowid % CHAPTER_SLOTS) as usize + 1,
+                location: row.get(1)?,
+                heading: row.get(2)?,
+                snippet: highlight(&snippet),
+            })
+        })?.collect::<SqlResult<Vec<_>>>()?;
+        Ok(matches)
+    }
+}
+
+/// A chapter of a book whose text matches a content search
+#[derive(Debug, Clone, Serialize)]
+pub struct ContentMatch {
+    pub book_id: i32,
+    /// Position of the chapter in the book, starting at 1
+    pub chapter: usize,
+    /// Path of the chapter inside the EPUB; `null` for text files
+    pub location: Option<String>,
+    pub heading: Option<String>,
+    pub snippet: String,
+}
+
+/// Escape a snippet for HTML and turn the match markers into `<mark>` elements
+fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len() + 32);
+    for c in snippet.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Prepare a book query for the library's full-text index, if it has one: the search is
+/// taken off the query and parsed into an index expression, or `Err` when it is invalid.
+/// Without an index the search stays on the query and matches titles and author names.
+pub fn prepare_query(index: Option<&SearchIndex>, query: &mut BookQuery) -> Result<Option<String>, String> {
+    match (index, query.search.take()) {
+        (Some(_), Some(search)) => parse_query(&search),
+        (_, search) => {
+            query.search = search;
+            Ok(None)
+        }
+    }
+}
+
+/// List the books of a query prepared by `prepare_query`, restricted to the hits of its
+/// search expression in relevance order. A query with nothing to match, such as `*`, lists every book.
+pub fn books_page(db: &CalibreDb, index: Option<&SearchIndex>, mut query: BookQuery, expression: Option<String>) -> SqlResult<BookPage> {
+    if let (Some(index), Some(expression)) = (index, expression) {
+        index.ensure_fresh(db)?;
+        query.book_ids = Some(index.search(&expression)?);
+    }
+    db.get_books_page(&query)
+}
+
+/// Author names, plus each name's trailing words run together so that
+/// `leguin` finds "Ursula K. Le Guin" and `delcastillo` finds "Ana del Castillo"
+fn author_terms(book: &Book) -> String {
+    let mut terms = Vec::new();
+    for name in &book.authors {
+        terms.push(name.clone());
+        let words: Vec<&str> = name
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .collect();
+        for start in 0..words.len().saturating_sub(1) {
+            terms.push(words[start..].concat());
+        }
+    }
+    terms.join(" ")
+}
+
+/// Identifier values, with ISBNs also indexed without their hyphens
+fn identifier_terms(book: &Book) -> String {
+    let mut terms = Vec::new();
+    for value in book.identifiers.values() {
+        terms.push(value.clone());
+        let compact: String = value.chars().filter(|c| *c != '-' && *c != ' ').collect();
+        if compact != *value {
+            terms.push(compact);
+        }
+    }
+    terms.join(" ")
+}
+
+/// Translate a user query into an FTS5 expression for the metadata index.
+///
+/// Words match any field and also match longer words they start with, `"quoted phrases"`
+/// match exactly, `field:word` restricts a word or phrase to one field (`title`, `author`,
+/// `series`, `tag`, `publisher`, `comments`, `isbn`) and `-word` excludes books.
+/// All terms must match. Returns `Ok(None)` for a blank query.
+pub fn parse_query(input: &str) -> Result<Option<String>, String> {
+    parse_terms(input, &FIELDS)
+}
+
+/// Translate a user query into an FTS5 expression for the content index.
+/// Same syntax as `parse_query`, without field prefixes.
+pub fn parse_content_query(input: &str) -> Result<Option<String>, String> {
+    parse_terms(input, &[])
+}
+
+fn parse_terms(input: &str, fields: &[(&str, &str)]) -> Result<Option<String>, String> {
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+
+        let negated = chars.next_if_eq(&'-').is_some();
+
+        let mut word = String::new();
+        let mut column = None;
+        let mut quoted = false;
+        loop {
+            match chars.peek() {
+                None => break,
+                Some(c) if c.is_whitespace() => break,
+                Some('"') => {
+                    chars.next();
+                    quoted = true;
+                    for c in chars.by_ref() {
+                        if c == '"' {
+                            break;
+                        }
+                        word.push(c);
+                    }
+                    break;
+                }
+                Some(':') if column.is_none() => {
+                    chars.next();
+                    let prefix = word.to_lowercase();
+                    match fields.iter().find(|(name, _)| *name == prefix) {
+                        Some((_, col)) => {
+                            column = Some(*col);
+                            word.clear();
+                        }
+                        // Not a field name, so the colon is part of the word
+                        None => word.push(':'),
+                    }
+                }
+                Some(c) => {
+                    word.push(*c);
+                    chars.next();
+                }
+            }
+        }
+
+        // Terms without letters or digits leave the tokenizer nothing to match
+        if !word.chars().any(char::is_alphanumeric) {
+            continue;
+        }
+
+        // Quoting every term keeps FTS5 operators and punctuation from being interpreted
+        let mut term = format!("\"{}\"", word.replace('"', "\"\""));
+        if !quoted {
+            term.push('*');
+        }
+        if let Some(column) = column {
+            term = format!("{} : {}", column, term);
+        }
+
+        if negated {
+            exclude.push(term);
+        } else {
+            include.push(term);
+        }
+    }
+
+    if include.is_empty() {
+        return if exclude.is_empty() {
+            Ok(None)
+        } else {
+            Err("Search needs at least one term that is not excluded".to_string())
+        };
+    }
+
+    let mut expression = include.join(" AND ");
+    if !exclude.is_empty() {
+        expression = format!("({}) NOT ({})", expression, exclude.join(" OR "));
+    }
+    Ok(Some(expression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::fixture;
+    use rusqlite::Connection;
+
+    #[test]
+    fn test_search_index() {
+        assert_eq!(parse_query("  ").unwrap(), None);
+        assert!(parse_query("-dragons").is_err());
+        assert_eq!(
+            parse_query(r#"author:leguin Tag:"science fiction" -dragons note:x"#).unwrap().unwrap(),
+            r#"(authors : "leguin"* AND tags : "science fiction" AND "note:x"*) NOT ("dragons"*)"#
+        );
+
+        let path = fixture(30);
+        let index_path = path.with_file_name("search.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute("UPDATE books SET title = 'Les Misérables' WHERE id = 7", []).unwrap();
+            conn.execute("UPDATE authors SET name = 'Ursula K. Le Guin' WHERE id = 2", []).unwrap();
+        }
+
+        let db = CalibreDb::open(&path).unwrap();
+        let index = SearchIndex::open(&index_path, &path).unwrap();
+        assert!(index.ensure_fresh(&db).unwrap());
+        assert!(!index.ensure_fresh(&db).unwrap());
+
+        let search = |q: &str| index.search(&parse_query(q).unwrap().unwrap()).unwrap();
+        let sorted = |mut ids: Vec<i32>| {
+            ids.sort();
+            ids
+        };
+
+        // Diacritics are folded and words match by prefix
+        assert_eq!(search("miserable"), vec![7]);
+        assert_eq!(search("les mis"), vec![7]);
+
+        // Author 1 is linked to books n where n % 7 == 1
+        assert_eq!(sorted(search("author:leguin")), vec![1, 8, 15, 22, 29]);
+        assert_eq!(sorted(search("author:leguin -title:\"book 00015\"")), vec![1, 8, 22, 29]);
+        assert_eq!(search("isbn:9780000000012"), vec![12]);
+
+        // A hit in the title ranks above a hit in the description
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute("UPDATE comments SET text = '<p>A tale of <b>Zanzibar</b></p>' WHERE book = 3", []).unwrap();
+            conn.execute("UPDATE books SET title = 'Zanzibar' WHERE id = 20", []).unwrap();
+        }
+        assert!(index.ensure_fresh(&db).unwrap());
+        assert_eq!(search("zanzibar"), vec![20, 3]);
+
+        // Book contents are searched chapter by chapter and snippets are escaped
+        let chapters = [
+            Chapter { location: Some("ch1.xhtml".to_string()), heading: None, text: "The <dark> tower".to_string() },
+            Chapter { location: Some("ch2.xhtml".to_string()), heading: None, text: "A dark and stormy night".to_string() },
+        ];
+        index.store_content(4, "EPUB:1:100", &chapters, None).unwrap();
+        index.store_content(6, "TXT:1:100", &[], Some("unreadable")).unwrap();
+        let stamps = index.content_stamps().unwrap();
+        assert_eq!(stamps[&4], ("EPUB:1:100".to_string(), true));
+        assert_eq!(stamps[&6], ("TXT:1:100".to_string(), false));
+
+        let search_content = |q: &str| index.search_content(&parse_content_query(q).unwrap().unwrap(), 10).unwrap();
+        let hits = search_content("dark");
+        assert_eq!(hits.iter().map(|h| (h.book_id, h.chapter)).collect::<Vec<_>>(), [(4, 1), (4, 2)]);
+        assert_eq!(hits[0].snippet, "The &lt;<mark>dark</mark>&gt; tower");
+        assert!(search_content("author:dark").is_empty());
+
+        index.remove_content(4).unwrap();
+        assert!(search_content("dark").is_empty());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
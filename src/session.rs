@@ -1,10 +1,40 @@
 // Session management for Biblio authentication
-#![allow(dead_code)]
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use chrono::{DateTime, Utc, Duration};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::future::{ready, Ready};
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::api::ApiResponse;
+use crate::audit::{self, AuditEventType, AuditLogger};
+use crate::auth;
+use crate::config;
+use crate::rbac::UserRole;
+use crate::throttle::{LockoutKey, LoginThrottle};
+use crate::users;
+
+/// Name of the cookie carrying the session token
+pub const SESSION_COOKIE: &str = "biblio_session";
+
+/// API paths that can be reached without a valid session
+const PUBLIC_PATHS: &[&str] = &["/api/auth/login"];
+
+/// How long verified HTTP Basic credentials are trusted before the password is checked again
+const BASIC_AUTH_CACHE_SECONDS: i64 = 300;
+
+/// Realm announced to clients asked for HTTP Basic credentials
+const BASIC_AUTH_REALM: &str = "Biblio";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
@@ -18,6 +48,10 @@ pub struct Session {
 pub struct SessionStore {
     sessions: Arc<Mutex<HashMap<String, Session>>>,
     session_timeout_minutes: i64,
+    /// Recently verified Basic credentials: fingerprint of the header, username and expiry
+    credentials: Mutex<HashMap<String, (String, DateTime<Utc>)>>,
+    /// Random per-process key for the credential fingerprints, so they cannot be reversed offline
+    credentials_key: [u8; 32],
 }
 
 impl SessionStore {
@@ -25,6 +59,12 @@ impl SessionStore {
         SessionStore {
             sessions: Arc::new(Mutex::new(HashMap::new())),
             session_timeout_minutes: timeout_minutes,
+            credentials: Mutex::new(HashMap::new()),
+            credentials_key: {
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                key
+            },
         }
     }
 
@@ -48,25 +88,35 @@ impl SessionStore {
         token
     }
 
+    /// Validate a session token and return the username it belongs to.
+    /// A valid session is extended by the configured timeout (sliding expiration).
     pub fn validate_session(&self, token: &str) -> Option<String> {
-        if let Ok(mut sessions) = self.sessions.lock() {
-            if let Some(session) = sessions.get_mut(token) {
-                let now = Utc::now();
-                
-                // Check if session has expired
-                if let Ok(expires_at) = DateTime::parse_from_rfc3339(&session.expires_at) {
-                    if now > expires_at.with_timezone(&Utc) {
-                        sessions.remove(token);
-                        return None;
-                    }
-                }
+        let mut sessions = self.sessions.lock().ok()?;
+        let session = sessions.get_mut(token)?;
+        let now = Utc::now();
 
-                // Update last activity
-                session.last_activity = now.to_rfc3339();
-                return Some(session.username.clone());
-            }
+        // Check if session has expired
+        if let Ok(expires_at) = DateTime::parse_from_rfc3339(&session.expires_at)
+            && now > expires_at.with_timezone(&Utc)
+        {
+            sessions.remove(token);
+            return None;
         }
-        None
+
+        // Update last activity and push back the expiration
+        session.last_activity = now.to_rfc3339();
+        session.expires_at = (now + Duration::minutes(self.session_timeout_minutes)).to_rfc3339();
+        Some(session.username.clone())
+    }
+
+    /// Whether a session is still valid, without counting as activity, for
+    /// long-lived connections that must not keep an idle session alive
+    pub fn is_session_active(&self, token: &str) -> bool {
+        let Ok(sessions) = self.sessions.lock() else { return false };
+        sessions.get(token).is_some_and(|session| {
+            DateTime::parse_from_rfc3339(&session.expires_at)
+                .is_ok_and(|expires_at| Utc::now() <= expires_at.with_timezone(&Utc))
+        })
     }
 
     pub fn invalidate_session(&self, token: &str) {
@@ -86,5 +136,280 @@ impl SessionStore {
                 }
             });
         }
+        if let Ok(mut credentials) = self.credentials.lock() {
+            let now = Utc::now();
+            credentials.retain(|_, (_, expires_at)| now <= *expires_at);
+        }
+    }
+
+    /// Keyed hash of an Authorization header, so the cache never holds the password
+    fn credentials_fingerprint(&self, header: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.credentials_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(header.as_bytes());
+        mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Username of Basic credentials verified within the last few minutes.
+    /// Unlike sessions these are not extended, so a changed password takes effect soon.
+    pub fn verified_credentials(&self, header: &str) -> Option<String> {
+        let fingerprint = self.credentials_fingerprint(header);
+        let credentials = self.credentials.lock().ok()?;
+        let (username, expires_at) = credentials.get(&fingerprint)?;
+        (Utc::now() <= *expires_at).then(|| username.clone())
+    }
+
+    pub fn remember_credentials(&self, header: &str, username: &str) {
+        let fingerprint = self.credentials_fingerprint(header);
+        if let Ok(mut credentials) = self.credentials.lock() {
+            let expires_at = Utc::now() + Duration::seconds(BASIC_AUTH_CACHE_SECONDS);
+            credentials.insert(fingerprint, (username.to_string(), expires_at));
+        }
+    }
+}
+
+/// Build the HttpOnly cookie handed to the browser after a successful login
+pub fn session_cookie(token: &str) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, token.to_string())
+        .path("/")
+        .http_only(true)
+        .secure(config::use_https())
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+/// Build a cookie that makes the browser drop its session cookie
+pub fn removal_cookie() -> Cookie<'static> {
+    let mut cookie = session_cookie("");
+    cookie.make_removal();
+    cookie
+}
+
+/// Identity of the caller, resolved from the session cookie by `require_session`
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub username: String,
+    pub token: String,
+    /// The caller's account, loaded from the user store once per request
+    pub account: Arc<auth::User>,
+}
+
+impl AuthenticatedUser {
+    pub fn role(&self) -> UserRole {
+        UserRole::from_str(&self.account.role)
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("Authentication required")),
+        )
+    }
+}
+
+/// Why a request could not be authenticated
+enum AuthFailure {
+    Missing,
+    Invalid,
+    /// Too many failed attempts; seconds until the lockout ends
+    LockedOut(i64),
+    Error(String),
+}
+
+/// Resolve the caller and load their account, so the handlers and access checks
+/// of the request share one lookup made off the async worker threads
+async fn authenticate(req: &ServiceRequest) -> Result<AuthenticatedUser, AuthFailure> {
+    let (username, token) = identify(req).await?;
+    let account = {
+        let username = username.clone();
+        web::block(move || users::store().get_user(&username))
+            .await
+            .map_err(|e| AuthFailure::Error(e.to_string()))?
+            .map_err(|e| AuthFailure::Error(e.to_string()))?
+    };
+    match account {
+        Some(account) => Ok(AuthenticatedUser { username, token, account: Arc::new(account) }),
+        // Deleted since the session was created or the credentials were cached
+        None => Err(AuthFailure::Invalid),
+    }
+}
+
+/// Username and session token of the caller, from the session cookie or, for
+/// clients that cannot keep a cookie such as e-reader apps and scripts, from an
+/// API token (`Bearer`) or HTTP Basic credentials checked against the user store
+async fn identify(req: &ServiceRequest) -> Result<(String, String), AuthFailure> {
+    let store = req.app_data::<web::Data<SessionStore>>();
+
+    if let Some(token) = req.cookie(SESSION_COOKIE).map(|c| c.value().to_string())
+        && let Some(username) = store.and_then(|store| store.validate_session(&token))
+    {
+        return Ok((username, token));
+    }
+
+    let Some(header) = req.headers().get(header::AUTHORIZATION).and_then(|h| h.to_str().ok()) else {
+        return Err(AuthFailure::Missing);
+    };
+    if let Some(secret) = header.strip_prefix("Bearer ") {
+        let secret = secret.trim().to_string();
+        let username = web::block(move || users::store().token_user(&secret))
+            .await
+            .map_err(|e| AuthFailure::Error(e.to_string()))?
+            .map_err(|e| AuthFailure::Error(e.to_string()))?;
+        return match username {
+            Some(username) => Ok((username, String::new())),
+            None => Err(AuthFailure::Invalid),
+        };
+    }
+    let Some((username, password)) = header
+        .strip_prefix("Basic ")
+        .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|decoded| decoded.split_once(':').map(|(u, p)| (u.to_string(), p.to_string())))
+    else {
+        return Err(AuthFailure::Missing);
+    };
+
+    // Clients send the credentials with every request; only verify the password now and then
+    if let Some(username) = store.and_then(|store| store.verified_credentials(header)) {
+        return Ok((username, String::new()));
+    }
+
+    let client_ip = audit::client_ip(req.request());
+    let audit_logger = req.app_data::<web::Data<AuditLogger>>();
+    let log = |event: AuditEventType, details: &str, success: bool| {
+        if let Some(audit_logger) = audit_logger {
+            audit_logger.log_event(event, &username, &client_ip, details, success);
+        }
+    };
+
+    let throttle = req.app_data::<web::Data<LoginThrottle>>();
+    let throttle_keys = [LockoutKey::User(username.clone()), LockoutKey::Ip(client_ip.clone())];
+    if let Some(throttle) = throttle {
+        let admission = throttle.check(&throttle_keys);
+        for key in &admission.expired {
+            log(AuditEventType::AccountUnlocked, &format!("Lockout expired for {}", key), true);
+        }
+        if let Some(locked_until) = admission.locked_until {
+            log(
+                AuditEventType::LoginFailure,
+                &format!("Basic authentication refused: locked out until {}", locked_until.to_rfc3339()),
+                false,
+            );
+            return Err(AuthFailure::LockedOut((locked_until - Utc::now()).num_seconds().max(1)));
+        }
     }
+
+    let verified = {
+        let username = username.clone();
+        web::block(move || {
+            let user = users::store().get_user(&username).map_err(|e| e.to_string())?;
+            auth::authenticate_user(&username, &password, user.as_slice())
+        })
+        .await
+        .map_err(|e| AuthFailure::Error(e.to_string()))?
+    };
+
+    match verified {
+        Ok(true) => {
+            if let Some(throttle) = throttle {
+                throttle.record_success(&username);
+            }
+            if let Some(store) = store {
+                store.remember_credentials(header, &username);
+            }
+            log(AuditEventType::LoginSuccess, "Authenticated with HTTP Basic credentials", true);
+            Ok((username, String::new()))
+        }
+        Ok(false) => {
+            log(AuditEventType::LoginFailure, "Invalid HTTP Basic credentials", false);
+            if let Some(throttle) = throttle {
+                for (key, locked_until) in throttle.record_failure(&throttle_keys) {
+                    log(
+                        AuditEventType::AccountLocked,
+                        &format!("Locked out {} until {} after repeated failed logins", key, locked_until.to_rfc3339()),
+                        false,
+                    );
+                }
+            }
+            Err(AuthFailure::Invalid)
+        }
+        Err(e) => {
+            log(AuditEventType::LoginFailure, &format!("Authentication error: {}", e), false);
+            Err(AuthFailure::Error(e))
+        }
+    }
+}
+
+/// Middleware rejecting API requests that carry neither a valid session cookie nor
+/// valid HTTP Basic credentials
+pub async fn require_session(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if PUBLIC_PATHS.contains(&req.path()) {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let failure = match authenticate(&req).await {
+        Ok(user) => {
+            req.extensions_mut().insert(user);
+            return next.call(req).await.map(ServiceResponse::map_into_left_body);
+        }
+        Err(failure) => failure,
+    };
+
+    let (mut response, error) = match failure {
+        AuthFailure::LockedOut(retry_after) => {
+            let mut response = HttpResponse::TooManyRequests();
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+            response.cookie(removal_cookie());
+            (response, format!("Too many failed login attempts. Try again in {} seconds.", retry_after))
+        }
+        // The session may be fine; the user store could not be read
+        AuthFailure::Error(e) => (HttpResponse::InternalServerError(), format!("Authentication error: {}", e)),
+        AuthFailure::Missing | AuthFailure::Invalid => {
+            let mut response = HttpResponse::Unauthorized();
+            response.cookie(removal_cookie());
+            (response, "Authentication required".to_string())
+        }
+    };
+    let response = response
+        .json(ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some(error),
+        });
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+/// Middleware for feeds read by e-reader apps: like `require_session`, but asks for
+/// HTTP Basic credentials with a `WWW-Authenticate` challenge instead of answering in JSON
+pub async fn require_basic_auth(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let response = match authenticate(&req).await {
+        Ok(user) => {
+            req.extensions_mut().insert(user);
+            return next.call(req).await.map(ServiceResponse::map_into_left_body);
+        }
+        Err(AuthFailure::LockedOut(retry_after)) => HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+            .body(format!("Too many failed login attempts. Try again in {} seconds.", retry_after)),
+        Err(AuthFailure::Error(e)) => HttpResponse::InternalServerError().body(format!("Authentication error: {}", e)),
+        Err(AuthFailure::Missing | AuthFailure::Invalid) => HttpResponse::Unauthorized()
+            .insert_header((
+                header::WWW_AUTHENTICATE,
+                format!("Basic realm=\"{}\", charset=\"UTF-8\"", BASIC_AUTH_REALM),
+            ))
+            .body("Authentication required"),
+    };
+    Ok(req.into_response(response).map_into_right_body())
 }
@@ -0,0 +1,228 @@
+// Login throttling and temporary account lockout
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::config::LoginThrottleConfig;
+
+/// What a failure counter is attached to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "value", rename_all = "lowercase")]
+pub enum LockoutKey {
+    User(String),
+    Ip(String),
+}
+
+impl std::fmt::Display for LockoutKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockoutKey::User(username) => write!(f, "user {}", username),
+            LockoutKey::Ip(ip) => write!(f, "IP {}", ip),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FailureRecord {
+    failures: u32,
+    window_start: DateTime<Utc>,
+    last_failure: DateTime<Utc>,
+    lockouts: u32,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+/// A key that is currently locked out or accumulating failures
+#[derive(Debug, Clone, Serialize)]
+pub struct LockoutInfo {
+    pub key: LockoutKey,
+    pub failures: u32,
+    pub lockouts: u32,
+    pub locked_until: Option<String>,
+}
+
+/// Result of checking whether a login attempt may proceed
+#[derive(Debug, Default)]
+pub struct Admission {
+    /// Set when the username or IP is locked out
+    pub locked_until: Option<DateTime<Utc>>,
+    /// Lockouts that expired since they were last checked
+    pub expired: Vec<LockoutKey>,
+}
+
+/// Counts failed logins per username and per IP address and locks them out
+/// once a threshold is reached. Every consecutive lockout doubles in length.
+pub struct LoginThrottle {
+    records: Mutex<HashMap<LockoutKey, FailureRecord>>,
+    settings: LoginThrottleConfig,
+}
+
+impl LoginThrottle {
+    pub fn new(settings: LoginThrottleConfig) -> Self {
+        LoginThrottle {
+            records: Mutex::new(HashMap::new()),
+            settings,
+        }
+    }
+
+    fn threshold(&self, key: &LockoutKey) -> u32 {
+        match key {
+            LockoutKey::User(_) => self.settings.max_failures_per_user,
+            LockoutKey::Ip(_) => self.settings.max_failures_per_ip,
+        }
+    }
+
+    pub fn check(&self, keys: &[LockoutKey]) -> Admission {
+        self.check_at(keys, Utc::now())
+    }
+
+    fn check_at(&self, keys: &[LockoutKey], now: DateTime<Utc>) -> Admission {
+        let mut admission = Admission::default();
+        let mut records = self.records.lock().unwrap();
+
+        for key in keys {
+            if let Some(record) = records.get_mut(key)
+                && let Some(locked_until) = record.locked_until
+            {
+                if now < locked_until {
+                    admission.locked_until = admission.locked_until.max(Some(locked_until));
+                } else {
+                    record.locked_until = None;
+                    record.failures = 0;
+                    admission.expired.push(key.clone());
+                }
+            }
+        }
+
+        admission
+    }
+
+    /// Record a failed login, returning the keys that just became locked out
+    pub fn record_failure(&self, keys: &[LockoutKey]) -> Vec<(LockoutKey, DateTime<Utc>)> {
+        self.record_failure_at(keys, Utc::now())
+    }
+
+    fn record_failure_at(&self, keys: &[LockoutKey], now: DateTime<Utc>) -> Vec<(LockoutKey, DateTime<Utc>)> {
+        let window = Duration::seconds(self.settings.window_seconds as i64);
+        let max_lockout = Duration::seconds(self.settings.max_lockout_seconds as i64);
+        let mut locked = Vec::new();
+        let mut records = self.records.lock().unwrap();
+
+        for key in keys {
+            let threshold = self.threshold(key);
+            if threshold == 0 {
+                continue;
+            }
+
+            let record = records.entry(key.clone()).or_insert(FailureRecord {
+                failures: 0,
+                window_start: now,
+                last_failure: now,
+                lockouts: 0,
+                locked_until: None,
+            });
+
+            // Forget the backoff level after a quiet period as long as the longest lockout
+            if now - record.last_failure > max_lockout {
+                record.lockouts = 0;
+            }
+            if now - record.window_start > window {
+                record.failures = 0;
+                record.window_start = now;
+            }
+
+            record.failures += 1;
+            record.last_failure = now;
+
+            if record.failures >= threshold && record.locked_until.is_none() {
+                let factor = 2i64.saturating_pow(record.lockouts.min(30));
+                let seconds = (self.settings.lockout_seconds as i64).saturating_mul(factor);
+                let until = now + Duration::seconds(seconds).min(max_lockout);
+
+                record.lockouts += 1;
+                record.failures = 0;
+                record.window_start = now;
+                record.locked_until = Some(until);
+                locked.push((key.clone(), until));
+            }
+        }
+
+        locked
+    }
+
+    /// Reset the failure counter of a username after a successful login
+    pub fn record_success(&self, username: &str) {
+        self.records.lock().unwrap().remove(&LockoutKey::User(username.to_string()));
+    }
+
+    /// Clear a lockout; returns false if nothing was recorded for the key
+    pub fn unlock(&self, key: &LockoutKey) -> bool {
+        self.records.lock().unwrap().remove(key).is_some()
+    }
+
+    pub fn lockouts(&self) -> Vec<LockoutInfo> {
+        let now = Utc::now();
+        let records = self.records.lock().unwrap();
+        let mut lockouts: Vec<LockoutInfo> = records
+            .iter()
+            .filter(|(_, r)| r.failures > 0 || r.locked_until.is_some_and(|until| until > now))
+            .map(|(key, r)| LockoutInfo {
+                key: key.clone(),
+                failures: r.failures,
+                lockouts: r.lockouts,
+                locked_until: r.locked_until.filter(|until| *until > now).map(|until| until.to_rfc3339()),
+            })
+            .collect();
+        lockouts.sort_by(|a, b| b.locked_until.cmp(&a.locked_until));
+        lockouts
+    }
+
+    /// Drop records that are neither locked nor inside their failure window
+    pub fn cleanup(&self) {
+        let now = Utc::now();
+        let window = Duration::seconds(self.settings.window_seconds as i64);
+        let max_lockout = Duration::seconds(self.settings.max_lockout_seconds as i64);
+        self.records.lock().unwrap().retain(|_, r| {
+            r.locked_until.is_some_and(|until| until > now)
+                || now - r.window_start <= window
+                || now - r.last_failure <= max_lockout
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout_with_backoff() {
+        let throttle = LoginThrottle::new(LoginThrottleConfig {
+            max_failures_per_user: 3,
+            max_failures_per_ip: 0,
+            window_seconds: 600,
+            lockout_seconds: 60,
+            max_lockout_seconds: 3600,
+        });
+        let keys = [LockoutKey::User("alice".to_string()), LockoutKey::Ip("10.0.0.1".to_string())];
+        let start = Utc::now();
+
+        assert!(throttle.record_failure_at(&keys, start).is_empty());
+        assert!(throttle.record_failure_at(&keys, start).is_empty());
+        let locked = throttle.record_failure_at(&keys, start);
+        assert_eq!(locked, vec![(keys[0].clone(), start + Duration::seconds(60))]);
+        assert!(throttle.check_at(&keys, start + Duration::seconds(30)).locked_until.is_some());
+
+        // Once expired the lockout is reported and the next one lasts twice as long
+        let later = start + Duration::seconds(61);
+        let admission = throttle.check_at(&keys, later);
+        assert!(admission.locked_until.is_none());
+        assert_eq!(admission.expired, vec![keys[0].clone()]);
+
+        throttle.record_failure_at(&keys, later);
+        throttle.record_failure_at(&keys, later);
+        let locked = throttle.record_failure_at(&keys, later);
+        assert_eq!(locked[0].1, later + Duration::seconds(120));
+
+        assert!(throttle.unlock(&keys[0]));
+        assert!(throttle.check_at(&keys, later).locked_until.is_none());
+    }
+}
@@ -0,0 +1,178 @@
+// Cover thumbnails, resized on demand and cached on disk
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::{ExtendedColorType, ImageReader};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+/// Widths thumbnails are rendered at. Requested widths are rounded up to one of
+/// these so arbitrary `w` values cannot fill the cache.
+pub const WIDTHS: [u32; 6] = [50, 100, 150, 200, 250, 400];
+
+/// JPEG quality used for thumbnails
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    #[default]
+    Jpeg,
+    Webp,
+}
+
+impl ThumbnailFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "image/jpeg",
+            ThumbnailFormat::Webp => "image/webp",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Round a requested width up to the nearest preset
+pub fn preset_width(requested: u32) -> u32 {
+    WIDTHS.iter().copied().find(|w| *w >= requested).unwrap_or(WIDTHS[WIDTHS.len() - 1])
+}
+
+/// One rendition of a book cover
+#[derive(Debug, Clone)]
+pub struct ThumbnailRequest {
+    pub library_id: String,
+    pub book_id: i32,
+    pub cover: PathBuf,
+    /// The book's `last_modified`, so metadata edits in Calibre also refresh the thumbnail
+    pub last_modified: Option<String>,
+    pub width: u32,
+    pub format: ThumbnailFormat,
+}
+
+impl ThumbnailRequest {
+    /// Fingerprint of the rendition and its sources, used as cache file name and ETag.
+    /// It changes whenever the cover's mtime or the book's `last_modified` does.
+    pub fn fingerprint(&self) -> io::Result<String> {
+        let mtime = fs::metadata(&self.cover)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let key = format!(
+            "{}:{}:{}:{}:{}:{}",
+            self.library_id,
+            self.book_id,
+            self.width,
+            self.format.extension(),
+            mtime,
+            self.last_modified.as_deref().unwrap_or("")
+        );
+        Ok(Uuid::new_v5(&Uuid::NAMESPACE_OID, key.as_bytes()).simple().to_string())
+    }
+
+    /// Return the cached thumbnail, rendering it first when missing.
+    /// Older renditions of the same cover at this size are removed.
+    pub fn cached(&self, cache_dir: &Path, fingerprint: &str) -> io::Result<PathBuf> {
+        let dir = cache_dir.join(&self.library_id).join(self.book_id.to_string());
+        let prefix = format!("{}-", self.width);
+        let suffix = format!(".{}", self.format.extension());
+        let path = dir.join(format!("{}{}{}", prefix, fingerprint, suffix));
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        fs::create_dir_all(&dir)?;
+        let data = self.render()?;
+
+        // Write to a temporary file first so concurrent readers never see a partial image
+        let tmp = dir.join(format!(".{}.tmp", Uuid::new_v4().simple()));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(&suffix) && entry.path() != path {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+
+        Ok(path)
+    }
+
+    fn render(&self) -> io::Result<Vec<u8>> {
+        let image = ImageReader::open(&self.cover)?
+            .with_guessed_format()?
+            .decode()
+            .map_err(io::Error::other)?;
+
+        // Never upscale small covers
+        let width = self.width.min(image.width());
+        let thumbnail = image.resize(width, u32::MAX, FilterType::Triangle);
+
+        let mut data = Vec::new();
+        match self.format {
+            ThumbnailFormat::Jpeg => {
+                JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY)
+                    .encode_image(&thumbnail.to_rgb8())
+                    .map_err(io::Error::other)?;
+            }
+            ThumbnailFormat::Webp => {
+                let rgba = thumbnail.to_rgba8();
+                WebPEncoder::new_lossless(&mut data)
+                    .encode(rgba.as_raw(), rgba.width(), rgba.height(), ExtendedColorType::Rgba8)
+                    .map_err(io::Error::other)?;
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumbnail_cache() {
+        let dir = std::env::temp_dir().join(format!("biblio-thumbs-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = dir.join("cover.jpg");
+        image::RgbImage::from_pixel(600, 900, image::Rgb([200, 80, 40])).save(&cover).unwrap();
+
+        let mut request = ThumbnailRequest {
+            library_id: "lib".to_string(),
+            book_id: 7,
+            cover: cover.clone(),
+            last_modified: Some("2024-01-01T00:00:00Z".to_string()),
+            width: preset_width(180),
+            format: ThumbnailFormat::Jpeg,
+        };
+        assert_eq!(request.width, 200);
+
+        let cache_dir = dir.join("cache");
+        let fingerprint = request.fingerprint().unwrap();
+        let first = request.cached(&cache_dir, &fingerprint).unwrap();
+        let thumbnail = image::open(&first).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (200, 300));
+        assert_eq!(request.cached(&cache_dir, &fingerprint).unwrap(), first);
+
+        // Editing the book in Calibre yields a new rendition and drops the old one
+        request.last_modified = Some("2024-02-01T00:00:00Z".to_string());
+        let fingerprint = request.fingerprint().unwrap();
+        let second = request.cached(&cache_dir, &fingerprint).unwrap();
+        assert_ne!(first, second);
+        assert!(!first.exists());
+        assert!(second.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
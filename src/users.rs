@@ -0,0 +1,610 @@
+// Storage of user accounts, their preferences and API tokens
+use chrono::{SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Result as SqlResult, Row};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+use crate::auth::{self, User};
+use crate::config::{self, UserStoreKind};
+
+/// Prefix of API token secrets, so leaked tokens are easy to recognise
+const TOKEN_PREFIX: &str = "biblio_";
+
+/// How stale `last_used_at` may get before a request using the token updates it, in seconds
+const TOKEN_USE_RESOLUTION: i64 = 60;
+
+#[derive(Debug)]
+pub enum StoreError {
+    NotFound,
+    AlreadyExists,
+    /// The value cannot be stored, e.g. a ':' in a users.ids field
+    Invalid(String),
+    /// The store does not keep this kind of data, e.g. API tokens in users.ids
+    Unsupported(&'static str),
+    Backend(String),
+}
+
+impl std::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::NotFound => write!(f, "User not found"),
+            StoreError::AlreadyExists => write!(f, "User already exists"),
+            StoreError::Invalid(e) => write!(f, "{}", e),
+            StoreError::Unsupported(e) => write!(f, "{}", e),
+            StoreError::Backend(e) => write!(f, "User store error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Backend(e.to_string())
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// An API token as listed to its owner; the secret is only shown once, on creation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NewApiToken {
+    #[serde(flatten)]
+    pub token: ApiToken,
+    pub secret: String,
+}
+
+/// User accounts with their role, restrictions and library access, plus the
+/// preferences and API tokens of each user. Every method applies its change
+/// atomically, so concurrent admin edits cannot overwrite each other.
+pub trait UserStore: Send + Sync {
+    fn list_users(&self) -> StoreResult<Vec<User>>;
+
+    fn get_user(&self, username: &str) -> StoreResult<Option<User>>;
+
+    /// Fails with `AlreadyExists` when the username is taken
+    fn create_user(&self, user: &User) -> StoreResult<()>;
+
+    /// Apply `update` to a user and return the stored result
+    fn update_user(&self, username: &str, update: &mut dyn FnMut(&mut User)) -> StoreResult<User>;
+
+    fn delete_user(&self, username: &str) -> StoreResult<()>;
+
+    /// Client settings of a user as a JSON object, empty until first saved
+    fn preferences(&self, username: &str) -> StoreResult<serde_json::Value>;
+
+    fn set_preferences(&self, username: &str, preferences: &serde_json::Value) -> StoreResult<()>;
+
+    fn create_api_token(&self, username: &str, name: &str) -> StoreResult<NewApiToken>;
+
+    fn api_tokens(&self, username: &str) -> StoreResult<Vec<ApiToken>>;
+
+    fn revoke_api_token(&self, username: &str, id: &str) -> StoreResult<()>;
+
+    /// The owner of the token with this secret, if any
+    fn token_user(&self, secret: &str) -> StoreResult<Option<String>>;
+}
+
+/// Parse a JSON column. Unreadable access data is an error, never "no restrictions".
+fn json_column<T: DeserializeOwned>(column: usize, value: &str) -> SqlResult<T> {
+    serde_json::from_str(value)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Hex SHA-256 of a token secret, so the database never holds usable tokens; secrets
+/// are random, so no salt or stretching is needed
+fn token_hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn new_token_secret() -> String {
+    format!("{}{}{}", TOKEN_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Users, preferences and API tokens in a SQLite database of Biblio's own
+pub struct SqliteUserStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteUserStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> SqlResult<Self> {
+        let conn = Connection::open(path)?;
+        Self::init_schema(&conn)?;
+        Ok(SqliteUserStore { conn: Mutex::new(conn) })
+    }
+
+    fn init_schema(conn: &Connection) -> SqlResult<()> {
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA foreign_keys = ON;
+             CREATE TABLE IF NOT EXISTS users (
+                 username TEXT PRIMARY KEY,
+                 password_hash TEXT NOT NULL,
+                 role TEXT NOT NULL,
+                 email TEXT,
+                 created_at TEXT,
+                 restrictions TEXT NOT NULL DEFAULT '{}',
+                 libraries TEXT,
+                 preferences TEXT NOT NULL DEFAULT '{}'
+             );
+             CREATE TABLE IF NOT EXISTS api_tokens (
+                 id TEXT PRIMARY KEY,
+                 username TEXT NOT NULL REFERENCES users (username) ON DELETE CASCADE,
+                 name TEXT NOT NULL,
+                 token_hash TEXT NOT NULL UNIQUE,
+                 created_at TEXT NOT NULL,
+                 last_used_at TEXT
+             );
+             CREATE INDEX IF NOT EXISTS api_tokens_user ON api_tokens (username);
+             CREATE TABLE IF NOT EXISTS meta (
+                 key TEXT PRIMARY KEY,
+                 value TEXT NOT NULL
+             );"
+        )
+    }
+
+    /// Copy the accounts of a users.ids file into the database, once: later
+    /// calls do nothing, so the file can be kept around or removed afterwards.
+    /// Accounts already in the database are left alone. Returns the number of
+    /// users imported, or `None` when the import had already been done.
+    pub fn import_users_file(&self, users_file_path: &str) -> StoreResult<Option<usize>> {
+        let mut conn = self.conn.lock().unwrap();
+        let imported: Option<String> = conn
+            .query_row("SELECT value FROM meta WHERE key = 'users_file_imported'", [], |row| row.get(0))
+            .optional()?;
+        if imported.is_some() {
+            return Ok(None);
+        }
+
+        let users = auth::load_users(users_file_path).map_err(|e| StoreError::Backend(e.to_string()))?;
+        let tx = conn.transaction()?;
+        let mut count = 0;
+        for user in &users {
+            count += Self::insert_user(&tx, user)?;
+        }
+        tx.execute(
+            "INSERT INTO meta (key, value) VALUES ('users_file_imported', ?1)",
+            params![format!("{} from {}", now(), users_file_path)],
+        )?;
+        tx.commit()?;
+        Ok(Some(count))
+    }
+
+    /// Insert a user unless the username is taken; returns the number of rows inserted
+    fn insert_user(conn: &Connection, user: &User) -> StoreResult<usize> {
+        Ok(conn.execute(
+            "INSERT OR IGNORE INTO users (username, password_hash, role, email, created_at, restrictions, libraries)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                user.username,
+                user.password_hash,
+                user.role,
+                user.email,
+                user.created_at,
+                to_json(&user.restrictions)?,
+                user.libraries.as_ref().map(to_json).transpose()?,
+            ],
+        )?)
+    }
+
+    fn read_user(row: &Row) -> SqlResult<User> {
+        let restrictions: String = row.get(5)?;
+        let libraries: Option<String> = row.get(6)?;
+        Ok(User {
+            username: row.get(0)?,
+            password_hash: row.get(1)?,
+            role: row.get(2)?,
+            email: row.get(3)?,
+            created_at: row.get(4)?,
+            restrictions: json_column(5, &restrictions)?,
+            libraries: libraries.map(|l| json_column(6, &l)).transpose()?,
+        })
+    }
+
+    fn find_user(conn: &Connection, username: &str) -> StoreResult<Option<User>> {
+        Ok(conn
+            .query_row(
+                "SELECT username, password_hash, role, email, created_at, restrictions, libraries
+                 FROM users WHERE username = ?1",
+                params![username],
+                Self::read_user,
+            )
+            .optional()?)
+    }
+
+    fn user_exists(conn: &Connection, username: &str) -> StoreResult<()> {
+        let exists: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM users WHERE username = ?1)",
+            params![username],
+            |row| row.get(0),
+        )?;
+        if exists { Ok(()) } else { Err(StoreError::NotFound) }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> StoreResult<String> {
+    serde_json::to_string(value).map_err(|e| StoreError::Backend(e.to_string()))
+}
+
+impl UserStore for SqliteUserStore {
+    fn list_users(&self) -> StoreResult<Vec<User>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT username, password_hash, role, email, created_at, restrictions, libraries
+             FROM users ORDER BY created_at, username",
+        )?;
+        let users = stmt.query_map([], Self::read_user)?.collect::<SqlResult<Vec<_>>>()?;
+        Ok(users)
+    }
+
+    fn get_user(&self, username: &str) -> StoreResult<Option<User>> {
+        Self::find_user(&self.conn.lock().unwrap(), username)
+    }
+
+    fn create_user(&self, user: &User) -> StoreResult<()> {
+        let conn = self.conn.lock().unwrap();
+        match Self::insert_user(&conn, user)? {
+            0 => Err(StoreError::AlreadyExists),
+            _ => Ok(()),
+        }
+    }
+
+    fn update_user(&self, username: &str, update: &mut dyn FnMut(&mut User)) -> StoreResult<User> {
+        let conn = self.conn.lock().unwrap();
+        let mut user = Self::find_user(&conn, username)?.ok_or(StoreError::NotFound)?;
+        update(&mut user);
+        conn.execute(
+            "UPDATE users SET password_hash = ?2, role = ?3, email = ?4, restrictions = ?5, libraries = ?6
+             WHERE username = ?1",
+            params![
+                username,
+                user.password_hash,
+                user.role,
+                user.email,
+                to_json(&user.restrictions)?,
+                user.libraries.as_ref().map(to_json).transpose()?,
+            ],
+        )?;
+        Ok(user)
+    }
+
+    fn delete_user(&self, username: &str) -> StoreResult<()> {
+        let conn = self.conn.lock().unwrap();
+        match conn.execute("DELETE FROM users WHERE username = ?1", params![username])? {
+            0 => Err(StoreError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn preferences(&self, username: &str) -> StoreResult<serde_json::Value> {
+        let conn = self.conn.lock().unwrap();
+        let preferences: Option<String> = conn
+            .query_row("SELECT preferences FROM users WHERE username = ?1", params![username], |row| row.get(0))
+            .optional()?;
+        let preferences = preferences.ok_or(StoreError::NotFound)?;
+        Ok(serde_json::from_str(&preferences).unwrap_or_else(|_| serde_json::json!({})))
+    }
+
+    fn set_preferences(&self, username: &str, preferences: &serde_json::Value) -> StoreResult<()> {
+        let conn = self.conn.lock().unwrap();
+        match conn.execute(
+            "UPDATE users SET preferences = ?2 WHERE username = ?1",
+            params![username, preferences.to_string()],
+        )? {
+            0 => Err(StoreError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn create_api_token(&self, username: &str, name: &str) -> StoreResult<NewApiToken> {
+        let conn = self.conn.lock().unwrap();
+        Self::user_exists(&conn, username)?;
+        let secret = new_token_secret();
+        let token = ApiToken {
+            id: Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            created_at: now(),
+            last_used_at: None,
+        };
+        conn.execute(
+            "INSERT INTO api_tokens (id, username, name, token_hash, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![token.id, username, token.name, token_hash(&secret), token.created_at],
+        )?;
+        Ok(NewApiToken { token, secret })
+    }
+
+    fn api_tokens(&self, username: &str) -> StoreResult<Vec<ApiToken>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, created_at, last_used_at FROM api_tokens WHERE username = ?1 ORDER BY created_at",
+        )?;
+        let tokens = stmt
+            .query_map(params![username], |row| {
+                Ok(ApiToken {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    created_at: row.get(2)?,
+                    last_used_at: row.get(3)?,
+                })
+            })?
+            .collect::<SqlResult<Vec<_>>>()?;
+        Ok(tokens)
+    }
+
+    fn revoke_api_token(&self, username: &str, id: &str) -> StoreResult<()> {
+        let conn = self.conn.lock().unwrap();
+        match conn.execute("DELETE FROM api_tokens WHERE id = ?1 AND username = ?2", params![id, username])? {
+            0 => Err(StoreError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn token_user(&self, secret: &str) -> StoreResult<Option<String>> {
+        if !secret.starts_with(TOKEN_PREFIX) {
+            return Ok(None);
+        }
+        let conn = self.conn.lock().unwrap();
+        let hash = token_hash(secret);
+        let username: Option<String> = conn
+            .query_row("SELECT username FROM api_tokens WHERE token_hash = ?1", params![hash], |row| row.get(0))
+            .optional()?;
+        if username.is_some() {
+            let stale = (Utc::now() - chrono::Duration::seconds(TOKEN_USE_RESOLUTION))
+                .to_rfc3339_opts(SecondsFormat::Secs, true);
+            conn.execute(
+                "UPDATE api_tokens SET last_used_at = ?2
+                 WHERE token_hash = ?1 AND (last_used_at IS NULL OR last_used_at < ?3)",
+                params![hash, now(), stale],
+            )?;
+        }
+        Ok(username)
+    }
+}
+
+/// Users kept in the users.ids flat file, for small setups that edit it by hand.
+/// The file is rewritten on every change; preferences and API tokens are not supported.
+pub struct FileUserStore {
+    path: String,
+    /// Held from reading the file until it is written back
+    lock: Mutex<()>,
+    /// Users parsed from the file, until it is saved or changes on disk
+    cache: Mutex<Option<CachedUsers>>,
+}
+
+struct CachedUsers {
+    modified: SystemTime,
+    len: u64,
+    users: Vec<User>,
+}
+
+impl FileUserStore {
+    pub fn new(path: &str) -> Self {
+        FileUserStore { path: path.to_string(), lock: Mutex::new(()), cache: Mutex::new(None) }
+    }
+
+    fn load(&self) -> StoreResult<Vec<User>> {
+        // The file may be edited by hand, so it is parsed again when its size or time changes
+        let stamp = fs::metadata(&self.path).and_then(|m| Ok((m.modified()?, m.len()))).ok();
+        let mut cache = self.cache.lock().unwrap();
+        if let (Some(cached), Some((modified, len))) = (cache.as_ref(), stamp)
+            && cached.modified == modified
+            && cached.len == len
+        {
+            return Ok(cached.users.clone());
+        }
+
+        let users = auth::load_users(&self.path).map_err(|e| StoreError::Backend(e.to_string()))?;
+        *cache = stamp.map(|(modified, len)| CachedUsers { modified, len, users: users.clone() });
+        Ok(users)
+    }
+
+    fn save(&self, users: &[User]) -> StoreResult<()> {
+        *self.cache.lock().unwrap() = None;
+        auth::save_users(users, &self.path).map_err(|e| StoreError::Backend(e.to_string()))
+    }
+
+    /// Reject values the colon-separated format cannot hold
+    fn check(user: &User) -> StoreResult<()> {
+        if user.username.contains(':') || user.role.contains(':') {
+            return Err(StoreError::Invalid("Usernames and roles cannot contain ':'".to_string()));
+        }
+        if user.email.as_deref().is_some_and(|email| email.contains(':')) {
+            return Err(StoreError::Invalid("Emails cannot contain ':' in users.ids".to_string()));
+        }
+        if user.libraries.is_some() {
+            return Err(StoreError::Unsupported("Per-user library access needs the SQLite user store"));
+        }
+        Ok(())
+    }
+}
+
+impl UserStore for FileUserStore {
+    fn list_users(&self) -> StoreResult<Vec<User>> {
+        self.load()
+    }
+
+    fn get_user(&self, username: &str) -> StoreResult<Option<User>> {
+        Ok(self.load()?.into_iter().find(|u| u.username == username))
+    }
+
+    fn create_user(&self, user: &User) -> StoreResult<()> {
+        Self::check(user)?;
+        let _lock = self.lock.lock().unwrap();
+        let mut users = self.load()?;
+        if users.iter().any(|u| u.username == user.username) {
+            return Err(StoreError::AlreadyExists);
+        }
+        users.push(user.clone());
+        self.save(&users)
+    }
+
+    fn update_user(&self, username: &str, update: &mut dyn FnMut(&mut User)) -> StoreResult<User> {
+        let _lock = self.lock.lock().unwrap();
+        let mut users = self.load()?;
+        let user = users.iter_mut().find(|u| u.username == username).ok_or(StoreError::NotFound)?;
+        update(user);
+        Self::check(user)?;
+        let user = user.clone();
+        self.save(&users)?;
+        Ok(user)
+    }
+
+    fn delete_user(&self, username: &str) -> StoreResult<()> {
+        let _lock = self.lock.lock().unwrap();
+        let mut users = self.load()?;
+        let count = users.len();
+        users.retain(|u| u.username != username);
+        if users.len() == count {
+            return Err(StoreError::NotFound);
+        }
+        self.save(&users)
+    }
+
+    fn preferences(&self, _username: &str) -> StoreResult<serde_json::Value> {
+        Err(StoreError::Unsupported("Saved preferences need the SQLite user store"))
+    }
+
+    fn set_preferences(&self, _username: &str, _preferences: &serde_json::Value) -> StoreResult<()> {
+        Err(StoreError::Unsupported("Saved preferences need the SQLite user store"))
+    }
+
+    fn create_api_token(&self, _username: &str, _name: &str) -> StoreResult<NewApiToken> {
+        Err(StoreError::Unsupported("API tokens need the SQLite user store"))
+    }
+
+    fn api_tokens(&self, _username: &str) -> StoreResult<Vec<ApiToken>> {
+        Err(StoreError::Unsupported("API tokens need the SQLite user store"))
+    }
+
+    fn revoke_api_token(&self, _username: &str, _id: &str) -> StoreResult<()> {
+        Err(StoreError::Unsupported("API tokens need the SQLite user store"))
+    }
+
+    fn token_user(&self, _secret: &str) -> StoreResult<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Global user store, picked by `user_store` in config.yaml
+static STORE: OnceLock<Arc<dyn UserStore>> = OnceLock::new();
+
+/// Open the configured user store, importing users.ids into a new SQLite store
+pub fn init() -> StoreResult<()> {
+    let users_file_path = config::users_file_path();
+    let store: Arc<dyn UserStore> = match config::user_store() {
+        UserStoreKind::File => {
+            info!("Storing users in {}", users_file_path);
+            Arc::new(FileUserStore::new(&users_file_path))
+        }
+        UserStoreKind::Sqlite => {
+            // No fallback to the users file: it cannot hold tokens, preferences or
+            // per-user library lists, so serving from it would silently drop them
+            let user_db_path = config::user_db_path();
+            let store = SqliteUserStore::open(&user_db_path).map_err(|e| {
+                error!("Failed to open user database at {}: {}", user_db_path, e);
+                e
+            })?;
+            info!("Storing users in {}", user_db_path);
+            if Path::new(&users_file_path).exists() {
+                match store.import_users_file(&users_file_path) {
+                    Ok(Some(count)) => info!("Imported {} user(s) from {}", count, users_file_path),
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to import users from {}: {}", users_file_path, e),
+                }
+            }
+            Arc::new(store)
+        }
+    };
+
+    match store.list_users() {
+        Ok(users) if !users.is_empty() => info!("Loaded {} user(s) for authentication", users.len()),
+        Ok(_) => warn!("No users found; copy users.ids.example to users.ids and restart to create the admin account"),
+        Err(e) => error!("Failed to load users: {}", e),
+    }
+
+    if STORE.set(store).is_err() {
+        warn!("User store already initialized");
+    }
+
+    Ok(())
+}
+
+/// Get the user store
+pub fn store() -> Arc<dyn UserStore> {
+    STORE.get().cloned().unwrap_or_else(|| {
+        error!("User store not initialized. Call users::init() during startup.");
+        panic!("User store not initialized");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_store() {
+        let dir = std::env::temp_dir().join(format!("biblio-users-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let users_file = dir.join("users.ids");
+        let users_file = users_file.to_str().unwrap();
+        std::fs::write(
+            users_file,
+            "admin:hash:admin::2025-01-02T03:04:05+00:00\nkid:hash:reader:kid@example.org::denied_tags=adult\n",
+        )
+        .unwrap();
+
+        let store = SqliteUserStore::open(dir.join("users.db")).unwrap();
+        assert_eq!(store.import_users_file(users_file).unwrap(), Some(2));
+        assert_eq!(store.import_users_file(users_file).unwrap(), None);
+        let kid = store.get_user("kid").unwrap().unwrap();
+        assert_eq!(kid.restrictions.denied_tags, vec!["adult".to_string()]);
+
+        let mut user = kid.clone();
+        user.username = "reader".to_string();
+        user.email = Some("mailto:reader@example.org".to_string());
+        user.libraries = Some(vec!["Kids".to_string()]);
+        store.create_user(&user).unwrap();
+        assert!(matches!(store.create_user(&user), Err(StoreError::AlreadyExists)));
+        let updated = store.update_user("reader", &mut |u| u.role = "user".to_string()).unwrap();
+        assert_eq!(updated.email, user.email);
+        assert_eq!(store.get_user("reader").unwrap().unwrap().libraries, user.libraries);
+        assert_eq!(store.list_users().unwrap().len(), 3);
+
+        store.set_preferences("reader", &serde_json::json!({"view": "table"})).unwrap();
+        assert_eq!(store.preferences("reader").unwrap()["view"], "table");
+
+        let token = store.create_api_token("reader", "e-reader").unwrap();
+        assert_eq!(store.token_user(&token.secret).unwrap().as_deref(), Some("reader"));
+        assert_eq!(store.token_user("biblio_wrong").unwrap(), None);
+        assert!(store.api_tokens("reader").unwrap()[0].last_used_at.is_some());
+
+        // Deleting a user takes their tokens along
+        store.delete_user("reader").unwrap();
+        assert_eq!(store.token_user(&token.secret).unwrap(), None);
+        assert!(matches!(store.delete_user("reader"), Err(StoreError::NotFound)));
+
+        // Corrupt access data fails the lookup instead of lifting the restrictions
+        store.conn.lock().unwrap().execute("UPDATE users SET restrictions = '{' WHERE username = 'kid'", []).unwrap();
+        assert!(matches!(store.get_user("kid"), Err(StoreError::Backend(_))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+// Background detection of library changes on disk
+use actix_web::web;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+use crate::config::{self, LibraryWatchMode};
+use crate::content::ContentIndexJobs;
+use crate::events::LibraryEvents;
+use crate::library::{self, LibraryCache, LibraryChange, LibraryScanner};
+
+/// Quiet period after the last filesystem event before rescanning, as Calibre
+/// writes a change in several steps
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Longest wait for a quiet period, so a busy library is still rescanned
+const MAX_DEBOUNCE: Duration = Duration::from_secs(30);
+
+/// Held for a whole rescan, so the watcher and the Refresh button never rescan at
+/// the same time, opening databases twice and announcing the same change twice
+static SYNC: Mutex<()> = Mutex::new(());
+
+/// Rescan the libraries directory, apply the changes to the cache, bring the
+/// changed libraries' indexes up to date and notify clients
+pub fn sync_libraries(
+    cache: &RwLock<LibraryCache>,
+    events: &LibraryEvents,
+    content_jobs: &ContentIndexJobs,
+) -> Vec<LibraryChange> {
+    // The lock guards no data, so one left poisoned by a failed rescan is still fine to take
+    let _syncing = SYNC.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let library_path = PathBuf::from(config::library_path());
+    // An unmounted share must not look like every library was deleted
+    if !library_path.is_dir() {
+        warn!("Libraries directory {:?} is not available, skipping rescan", library_path);
+        return Vec::new();
+    }
+
+    let changes = library::rescan_libraries(cache, &library_path);
+    {
+        let cache = cache.read().unwrap();
+        for change in &changes {
+            if matches!(change, LibraryChange::LibraryRemoved { .. }) {
+                continue;
+            }
+            let id = change.library_id();
+            cache.refresh_search_index(id);
+            if config::content_indexing()
+                && let (Some(db), Some(index)) = (cache.get_database(id), cache.get_search_index(id))
+            {
+                content_jobs.start(id, db.clone(), index);
+            }
+        }
+    }
+    for change in &changes {
+        events.publish(change.clone());
+    }
+    changes
+}
+
+/// Watch the libraries directory according to `library_watch`, rescanning on
+/// filesystem notifications and every `library_poll_seconds`
+pub fn spawn(
+    cache: web::Data<RwLock<LibraryCache>>,
+    events: web::Data<LibraryEvents>,
+    content_jobs: web::Data<ContentIndexJobs>,
+) {
+    let mode = config::library_watch();
+    if mode == LibraryWatchMode::Off {
+        info!("Library change detection is off; use the Refresh button to pick up changes");
+        return;
+    }
+
+    let poll_interval = Duration::from_secs(config::library_poll_seconds().max(1));
+    let (sender, receiver) = mpsc::channel();
+    let watcher = match mode {
+        LibraryWatchMode::Auto => match notify::recommended_watcher(sender) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("Failed to start filesystem watcher, polling libraries every {:?} instead: {}", poll_interval, e);
+                None
+            }
+        },
+        _ => None,
+    };
+    if watcher.is_none() {
+        info!("Polling libraries for changes every {:?}", poll_interval);
+    }
+
+    std::thread::spawn(move || {
+        let base_path = PathBuf::from(config::library_path());
+        let mut watcher = watcher;
+        let mut watched = HashSet::new();
+        loop {
+            if let Some(watcher) = watcher.as_mut() {
+                update_watches(watcher, &mut watched, &base_path);
+            }
+
+            match watcher {
+                Some(_) => {
+                    if wait_for_change(&receiver, Instant::now() + poll_interval) {
+                        debounce(&receiver);
+                    }
+                }
+                None => std::thread::sleep(poll_interval),
+            }
+
+            sync_libraries(&cache, &events, &content_jobs);
+        }
+    });
+}
+
+/// The directories to watch: the libraries directory, its subfolders (where
+/// new libraries may appear) and every library folder, each without recursion
+/// so the many book folders of a library cost nothing
+fn watch_targets(base_path: &Path) -> HashSet<PathBuf> {
+    let mut targets: HashSet<PathBuf> = WalkDir::new(base_path)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+        .map(|e| e.into_path())
+        .collect();
+    targets.extend(LibraryScanner::new(base_path).library_paths().into_iter().map(|(path, _)| path));
+    targets
+}
+
+fn update_watches(watcher: &mut RecommendedWatcher, watched: &mut HashSet<PathBuf>, base_path: &Path) {
+    let targets = watch_targets(base_path);
+    for path in watched.difference(&targets) {
+        // Deleted folders are unwatched by the OS already
+        let _ = watcher.unwatch(path);
+    }
+    watched.retain(|path| targets.contains(path));
+    for path in targets {
+        if watched.contains(&path) {
+            continue;
+        }
+        match watcher.watch(&path, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watched.insert(path);
+            }
+            Err(e) => debug!("Failed to watch {:?}: {}", path, e),
+        }
+    }
+}
+
+/// Only writes matter; reads of metadata.db by Biblio itself are ignored
+fn is_change(event: &notify::Result<Event>) -> bool {
+    match event {
+        Ok(event) => !matches!(event.kind, EventKind::Access(_)),
+        Err(e) => {
+            warn!("Filesystem watcher error: {}", e);
+            false
+        }
+    }
+}
+
+/// Wait until a filesystem change is reported (`true`) or the deadline passes (`false`)
+fn wait_for_change(receiver: &Receiver<notify::Result<Event>>, deadline: Instant) -> bool {
+    loop {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(event) if is_change(&event) => return true,
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout) => return false,
+            Err(RecvTimeoutError::Disconnected) => {
+                std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                return false;
+            }
+        }
+    }
+}
+
+/// Let a burst of filesystem events settle
+fn debounce(receiver: &Receiver<notify::Result<Event>>) {
+    let deadline = Instant::now() + MAX_DEBOUNCE;
+    while Instant::now() < deadline && receiver.recv_timeout(DEBOUNCE).is_ok() {}
+}